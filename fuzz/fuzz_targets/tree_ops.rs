@@ -8,7 +8,7 @@ use xdb::bplustree::algorithms::delete::delete;
 use xdb::bplustree::algorithms::find;
 use xdb::bplustree::algorithms::insert::insert;
 use xdb::bplustree::debug::{TransactionAction, assert_properties, assert_tree_equal};
-use xdb::bplustree::{Tree, TreeError, TreeKey};
+use xdb::bplustree::{FixedSizeKey, Tree, TreeError};
 use xdb::debug::BigKey;
 use xdb::storage::Storage;
 use xdb::storage::in_memory::InMemoryStorage;
@@ -36,7 +36,7 @@ impl<'a> Arbitrary<'a> for Value {
 }
 
 #[derive(Debug, Arbitrary, Clone)]
-pub enum TreeAction<T: TreeKey, const KEY_SIZE: usize> {
+pub enum TreeAction<T: FixedSizeKey, const KEY_SIZE: usize> {
     Insert {
         key: BigKey<T, KEY_SIZE>,
         value: Value,
@@ -51,7 +51,7 @@ pub enum TreeAction<T: TreeKey, const KEY_SIZE: usize> {
     },
 }
 
-fn execute_actions<TStorage: Storage, TKey: TreeKey, const KEY_SIZE: usize>(
+fn execute_actions<TStorage: Storage, TKey: FixedSizeKey, const KEY_SIZE: usize>(
     tree: &Tree<TStorage, BigKey<TKey, KEY_SIZE>>,
     actions: impl Iterator<Item = TreeAction<TKey, KEY_SIZE>>,
     after_action: impl Fn(),
@@ -99,7 +99,7 @@ fn execute_actions<TStorage: Storage, TKey: TreeKey, const KEY_SIZE: usize>(
 }
 
 #[allow(unused)]
-pub fn run_ops<T: TreeKey, const KEY_SIZE: usize>(actions: &[TreeAction<T, KEY_SIZE>]) {
+pub fn run_ops<T: FixedSizeKey, const KEY_SIZE: usize>(actions: &[TreeAction<T, KEY_SIZE>]) {
     #[cfg(true)]
     {
         let mut result = "vec![\n".to_string();
@@ -59,13 +59,13 @@ mod tests {
 
             if matches!(
                 t1.join().unwrap(),
-                Err(TreeError::StorageError(StorageError::Deadlock(_)))
+                Err(TreeError::StorageError(StorageError::Conflict { .. }))
             ) {
                 return;
             }
             if matches!(
                 t2.join().unwrap(),
-                Err(TreeError::StorageError(StorageError::Deadlock(_)))
+                Err(TreeError::StorageError(StorageError::Conflict { .. }))
             ) {
                 return;
             }
@@ -17,7 +17,7 @@ use xdb::bplustree::algorithms::delete::delete;
 use xdb::bplustree::algorithms::find;
 use xdb::bplustree::algorithms::insert::insert;
 use xdb::bplustree::debug::assert_properties;
-use xdb::bplustree::{Tree, TreeError, TreeKey};
+use xdb::bplustree::{FixedSizeKey, Tree, TreeError};
 use xdb::debug::BigKey;
 use xdb::storage::StorageError;
 use xdb::storage::in_memory::{InMemoryPageId, InMemoryStorage};
@@ -28,7 +28,7 @@ type KeyType = BigKey<u16, 1024>;
 const RUN_LENGTH: Duration = Duration::from_secs(300);
 const THREAD_COUNT: usize = 16;
 
-fn final_checks<T: TreeKey + for<'a> Arbitrary<'a>>(tree: &Tree<InMemoryStorage, T>) {
+fn final_checks<T: FixedSizeKey + for<'a> Arbitrary<'a>>(tree: &Tree<InMemoryStorage, T>) {
     let mut trx = tree.transaction().unwrap();
     assert_properties(&mut trx);
     trx.rollback().unwrap();
@@ -48,15 +48,15 @@ impl<'a> Arbitrary<'a> for Value {
 }
 
 #[derive(Debug, Arbitrary)]
-#[arbitrary(bound = "T: TreeKey + for<'a> Arbitrary<'a>")]
+#[arbitrary(bound = "T: FixedSizeKey + for<'a> Arbitrary<'a>")]
 // TODO add a `Sleep` command, it would allow us to stress-test locking code
-enum Command<T: TreeKey + for<'a> Arbitrary<'a>> {
+enum Command<T: FixedSizeKey + for<'a> Arbitrary<'a>> {
     Insert(T, Value),
     Delete(T),
     Read(T),
 }
 
-impl<T: TreeKey + for<'a> Arbitrary<'a>> Command<T> {
+impl<T: FixedSizeKey + for<'a> Arbitrary<'a>> Command<T> {
     fn run(
         &self,
         transaction: &mut TreeTransaction<InMemoryStorage, T>,
@@ -74,13 +74,13 @@ impl<T: TreeKey + for<'a> Arbitrary<'a>> Command<T> {
 }
 
 #[derive(Debug, Arbitrary)]
-#[arbitrary(bound = "T: TreeKey + for<'a> Arbitrary<'a>")]
-struct TransactionCommands<T: TreeKey + for<'a> Arbitrary<'a>> {
+#[arbitrary(bound = "T: FixedSizeKey + for<'a> Arbitrary<'a>")]
+struct TransactionCommands<T: FixedSizeKey + for<'a> Arbitrary<'a>> {
     commands: Vec<Command<T>>,
     commit: bool,
 }
 
-impl<T: TreeKey + for<'a> Arbitrary<'a>> TransactionCommands<T> {
+impl<T: FixedSizeKey + for<'a> Arbitrary<'a>> TransactionCommands<T> {
     // TODO allow providing probabilities for each type of command (so we can e.g. create a read
     // heavy test)
     fn new_random<TRng: Rng>(rng: &mut TRng) -> Self {
@@ -109,9 +109,7 @@ impl<T: TreeKey + for<'a> Arbitrary<'a>> TransactionCommands<T> {
     }
 }
 
-// TODO we should differentiate between deadlocks and optimistic concurrency failures (and only
-// handle the latter here)
-fn retry_on_deadlock<T: TreeKey + for<'a> Arbitrary<'a>>(
+fn retry_on_conflict<T: FixedSizeKey + for<'a> Arbitrary<'a>>(
     tree: &Tree<InMemoryStorage, T>,
     callable: impl Fn(TreeTransaction<InMemoryStorage, T>) -> Result<(), TreeError<InMemoryPageId>>,
 ) -> Result<(), TreeError<InMemoryPageId>> {
@@ -120,7 +118,7 @@ fn retry_on_deadlock<T: TreeKey + for<'a> Arbitrary<'a>>(
 
         match callable(transaction) {
             Ok(ok) => return Ok(ok),
-            Err(TreeError::StorageError(StorageError::Deadlock(_))) => {}
+            Err(TreeError::StorageError(StorageError::Conflict { .. })) => {}
             error @ Err(_) => return error,
         };
         thread::sleep(Duration::from_millis(2u64.pow(i / 16)));
@@ -6,7 +6,7 @@ use xdb::bplustree::algorithms::find;
 use xdb::storage::in_memory::InMemoryStorage;
 
 use crate::predictable::{KEYS_PER_ITERATION, commands_for_iteration, expected_value_for_key};
-use crate::{RUN_LENGTH, final_checks, retry_on_deadlock};
+use crate::{RUN_LENGTH, final_checks, retry_on_conflict};
 
 pub fn run() {
     let storage = InMemoryStorage::new();
@@ -20,10 +20,10 @@ pub fn run() {
     while start.elapsed() < RUN_LENGTH {
         let transaction_commands = commands_for_iteration(i);
 
-        retry_on_deadlock(&tree, |transaction| transaction_commands.run(transaction)).unwrap();
+        retry_on_conflict(&tree, |transaction| transaction_commands.run(transaction)).unwrap();
 
         if i.is_multiple_of(10000) && i > 0 {
-            retry_on_deadlock(&tree, |mut transaction| {
+            retry_on_conflict(&tree, |mut transaction| {
                 for j in
                     ((i.saturating_sub(5000)) * KEYS_PER_ITERATION)..((i - 1) * KEYS_PER_ITERATION)
                 {
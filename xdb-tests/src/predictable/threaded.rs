@@ -10,7 +10,7 @@ use xdb::bplustree::algorithms::find;
 use xdb::storage::in_memory::InMemoryStorage;
 
 use crate::predictable::{commands_for_iteration, expected_value_for_key};
-use crate::{RUN_LENGTH, THREAD_COUNT, final_checks, retry_on_deadlock};
+use crate::{RUN_LENGTH, THREAD_COUNT, final_checks, retry_on_conflict};
 
 const MILESTONE_EACH: u64 = 500;
 
@@ -25,7 +25,7 @@ fn test_thread(
 
     while !stop.load(Ordering::Relaxed) {
         let commands = commands_for_iteration(i);
-        retry_on_deadlock(&tree, |transaction| commands.run(transaction)).unwrap();
+        retry_on_conflict(&tree, |transaction| commands.run(transaction)).unwrap();
 
         if (i - thread_id).is_multiple_of(MILESTONE_EACH) {
             tx.send(i).unwrap();
@@ -5,7 +5,7 @@ use tracing::info;
 use xdb::bplustree::Tree;
 use xdb::storage::in_memory::InMemoryStorage;
 
-use crate::{KeyType, RUN_LENGTH, TransactionCommands, final_checks, retry_on_deadlock};
+use crate::{KeyType, RUN_LENGTH, TransactionCommands, final_checks, retry_on_conflict};
 
 pub fn run() {
     let storage = InMemoryStorage::new();
@@ -19,7 +19,7 @@ pub fn run() {
     while start.elapsed() < RUN_LENGTH {
         let commands = TransactionCommands::new_random(&mut rng);
 
-        retry_on_deadlock(&tree, |transaction| commands.run(transaction)).unwrap();
+        retry_on_conflict(&tree, |transaction| commands.run(transaction)).unwrap();
     }
 
     info!("time's up, wrapping up");
@@ -10,7 +10,7 @@ use xdb::bplustree::Tree;
 use xdb::storage::in_memory::InMemoryStorage;
 
 use crate::{
-    KeyType, RUN_LENGTH, THREAD_COUNT, TransactionCommands, final_checks, retry_on_deadlock,
+    KeyType, RUN_LENGTH, THREAD_COUNT, TransactionCommands, final_checks, retry_on_conflict,
 };
 
 struct ServerThread {
@@ -106,7 +106,7 @@ fn server_thread(
 ) {
     while let Ok(commands) = rx.recv() {
         info_span!("transaction").in_scope(|| {
-            retry_on_deadlock(&tree, |transaction| {
+            retry_on_conflict(&tree, |transaction| {
                 commands.run(transaction)?;
 
                 Ok(())
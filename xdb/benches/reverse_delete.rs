@@ -9,7 +9,7 @@ use xdb::storage::in_memory::InMemoryStorage;
 
 fn reverse_delete(c: &mut Criterion) {
     let storage = InMemoryStorage::new();
-    let tree = Tree::new(storage).unwrap();
+    let tree = Tree::<_, BigKey<u64, 256>>::new(storage).unwrap();
     let mut transaction = tree.transaction().unwrap();
     for i in 0..50000 {
         insert(
@@ -8,7 +8,7 @@ use xdb::storage::in_memory::InMemoryStorage;
 
 fn sorted_insert(c: &mut Criterion) {
     let storage = InMemoryStorage::new();
-    let tree = Tree::new(storage).unwrap();
+    let tree = Tree::<_, BigKey<u64, 256>>::new(storage).unwrap();
     let mut transaction = tree.transaction().unwrap();
 
     c.bench_function("sorted_insert (8 byte value)", |b| {
@@ -28,7 +28,7 @@ fn sorted_insert(c: &mut Criterion) {
     black_box(tree);
 
     let storage = InMemoryStorage::new();
-    let tree = Tree::new(storage).unwrap();
+    let tree = Tree::<_, BigKey<u64, 256>>::new(storage).unwrap();
     let mut transaction = tree.transaction().unwrap();
 
     c.bench_function("sorted_insert (512 byte value)", |b| {
@@ -0,0 +1,237 @@
+use tracing::{instrument, trace};
+
+use crate::bplustree::node::AnyNodeKind;
+use crate::bplustree::node::leaf::MAX_INLINE_VALUE_SIZE;
+use crate::bplustree::node::leaf::builder::LeafNodeBuilder;
+use crate::bplustree::overflow::LeafValue;
+use crate::bplustree::{
+    AnyNodeId, InteriorNode, InteriorNodeId, LeafNode, LeafNodeId, Node as _, NodeId as _,
+    TreeError, TreeKey, TreeTransaction, overflow,
+};
+use crate::storage::{PageId as _, PageReservation as _, Storage};
+
+/// How much of the capacity of each leaf is used by `bulk_load`, in percent. Leaving some space
+/// free makes the inserts that follow the load less likely to split the leaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FillFactor(u8);
+
+impl FillFactor {
+    pub const FULL: Self = Self(100);
+
+    #[must_use]
+    pub const fn new(percent: u8) -> Option<Self> {
+        if percent == 0 || percent > 100 {
+            return None;
+        }
+
+        Some(Self(percent))
+    }
+
+    pub(in crate::bplustree) const fn of(self, size: usize) -> usize {
+        size * self.0 as usize / 100
+    }
+}
+
+impl Default for FillFactor {
+    fn default() -> Self {
+        Self(90)
+    }
+}
+
+/// The first key of a subtree, together with its root.
+type Child<TKey> = (<TKey as TreeKey>::Owned, AnyNodeId);
+
+/// Fills an empty tree with `entries`, which must be sorted by their keys and not contain any
+/// duplicates.
+///
+/// The leaves are filled one after another, and the interior nodes are built on top of them, level
+/// by level. If an error is returned, the tree is left in an inconsistent state, and the
+/// transaction must be rolled back.
+#[instrument(skip(transaction, entries), fields(transaction_id=?transaction.id()))]
+pub fn bulk_load<'key, TStorage: Storage, TKey: TreeKey, TValue: AsRef<[u8]>>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    entries: impl IntoIterator<Item = (TKey::Borrowed<'key>, TValue)>,
+    fill_factor: FillFactor,
+) -> Result<(), TreeError<TStorage::PageId>> {
+    // the loaded entries are not recorded as separate inserts, they are only captured for the
+    // change feed
+    transaction.stop_replaying();
+
+    let old_root = transaction.get_root()?;
+
+    let is_empty = transaction.read_nodes(old_root, |node| match node.as_any() {
+        AnyNodeKind::Interior(_) => false,
+        AnyNodeKind::Leaf(leaf) => leaf.len() == 0,
+    })?;
+
+    if !is_empty {
+        return Err(TreeError::NotEmpty);
+    }
+
+    let mut children = load_leaves(transaction, entries, fill_factor)?;
+
+    if children.is_empty() {
+        return Ok(());
+    }
+
+    while children.len() > 1 {
+        children = load_interior_level(transaction, children)?;
+    }
+
+    let (_, new_root) = children.pop().unwrap();
+
+    transaction.write_header(|header| header.root = new_root.page())?;
+    transaction.delete_node(old_root)?;
+
+    Ok(())
+}
+
+fn load_leaves<'key, TStorage: Storage, TKey: TreeKey, TValue: AsRef<[u8]>>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    entries: impl IntoIterator<Item = (TKey::Borrowed<'key>, TValue)>,
+    fill_factor: FillFactor,
+) -> Result<Vec<Child<TKey>>, TreeError<TStorage::PageId>> {
+    let mut leaves = vec![];
+    let mut previous_key: Option<TKey::Owned> = None;
+
+    let mut current = LeafNode::<TKey>::new(None);
+    let mut current_reservation = None;
+    let mut previous_leaf = None;
+
+    for (key, value) in entries {
+        let key_size = TKey::encoded_size(key);
+
+        if key_size > TKey::MAX_ENCODED_SIZE {
+            return Err(TreeError::KeyTooLarge {
+                size: key_size,
+                max: TKey::MAX_ENCODED_SIZE,
+            });
+        }
+
+        if let Some(previous_key) = &previous_key
+            && !TKey::compare(key, TKey::borrow(previous_key)).is_gt()
+        {
+            return Err(TreeError::UnsortedKeys);
+        }
+
+        previous_key = Some(TKey::to_owned(key));
+
+        let value = value.as_ref();
+        transaction.capture_change(key, None, Some(value));
+
+        let value = if value.len() > MAX_INLINE_VALUE_SIZE {
+            LeafValue::Overflow(overflow::write(transaction, value)?)
+        } else {
+            LeafValue::Inline(value)
+        };
+
+        if current.len() > 0 && !current.can_fit_filled(key, value.inline_size(), fill_factor) {
+            let next_reservation = transaction.reserve_node()?;
+            let next_leaf = LeafNodeId::new(next_reservation.index().serialize());
+
+            previous_leaf = Some(finish_leaf(
+                transaction,
+                &current,
+                current_reservation.take().unwrap(),
+                previous_leaf,
+                Some(next_leaf),
+                &mut leaves,
+            )?);
+
+            current = LeafNode::new(None);
+            current_reservation = Some(next_reservation);
+        }
+
+        if current_reservation.is_none() {
+            current_reservation = Some(transaction.reserve_node()?);
+        }
+
+        let replaced = current.insert(key, value);
+        debug_assert!(replaced.is_none());
+    }
+
+    if let Some(reservation) = current_reservation {
+        finish_leaf(
+            transaction,
+            &current,
+            reservation,
+            previous_leaf,
+            None,
+            &mut leaves,
+        )?;
+    }
+
+    trace!(leaf_count = leaves.len(), "loaded leaves");
+
+    Ok(leaves)
+}
+
+#[allow(clippy::large_types_passed_by_value)]
+fn finish_leaf<'storage, TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey>,
+    leaf: &LeafNode<TKey>,
+    reservation: TStorage::PageReservation<'storage>,
+    previous: Option<LeafNodeId>,
+    next: Option<LeafNodeId>,
+    leaves: &mut Vec<Child<TKey>>,
+) -> Result<LeafNodeId, TreeError<TStorage::PageId>> {
+    let leaf_id = LeafNodeId::new(reservation.index().serialize());
+
+    let new_leaf = LeafNodeBuilder::new()
+        .with_topology(None, previous, next)
+        .with_data(leaf.materialize())
+        .build();
+
+    leaves.push((
+        TKey::to_owned(new_leaf.first_key().unwrap()),
+        leaf_id.into(),
+    ));
+
+    transaction.insert_reserved(reservation, new_leaf)?;
+
+    Ok(leaf_id)
+}
+
+/// Builds interior nodes over `children`, returning them as the children for the next level.
+fn load_interior_level<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    children: Vec<Child<TKey>>,
+) -> Result<Vec<Child<TKey>>, TreeError<TStorage::PageId>> {
+    let mut parents = vec![];
+    let mut children = children.into_iter().peekable();
+
+    while let Some((first_key, first_child)) = children.next() {
+        // the children are split so that each node gets at least two, see below
+        let (second_key, second_child) = children.next().unwrap();
+
+        let mut node =
+            InteriorNode::<TKey>::new(None, first_child, TKey::borrow(&second_key), second_child);
+        let mut node_children = vec![first_child, second_child];
+
+        while children.peek().is_some() && node.has_spare_capacity() {
+            // there's space for at least two more children, so if there are only two left, both
+            // are taken, so that the next node doesn't end up with just one
+            let take = if children.len() == 2 { 2 } else { 1 };
+
+            for (key, child) in children.by_ref().take(take) {
+                node.push(TKey::borrow(&key), child);
+                node_children.push(child);
+            }
+        }
+
+        let reservation = transaction.reserve_node()?;
+        let node_id = InteriorNodeId::new(reservation.index().serialize());
+
+        transaction.insert_reserved(reservation, node)?;
+
+        for child in node_children {
+            transaction.write_nodes(child, |child| child.set_parent(Some(node_id)))?;
+        }
+
+        parents.push((first_key, node_id.into()));
+    }
+
+    trace!(node_count = parents.len(), "loaded interior level");
+
+    Ok(parents)
+}
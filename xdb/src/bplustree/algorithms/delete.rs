@@ -7,7 +7,7 @@ use crate::bplustree::algorithms::{last_leaf, leaf_search};
 use crate::bplustree::node::Node;
 use crate::bplustree::node::interior::InteriorNode;
 use crate::bplustree::node::leaf::LeafNode;
-use crate::bplustree::{InteriorNodeId, LeafNodeId, TreeError, TreeKey, TreeTransaction};
+use crate::bplustree::{InteriorNodeId, LeafNodeId, TreeError, TreeKey, TreeTransaction, overflow};
 use crate::storage::{PageId, Storage};
 
 #[must_use]
@@ -81,7 +81,7 @@ fn merge_interior_node_with<TStorage: Storage, TKey: TreeKey>(
             .unwrap()
             .key_before()
             .unwrap();
-        let parent_key = parent.key_at(parent_key_index).unwrap();
+        let parent_key = parent.key_at(parent_key_index);
 
         left.merge_from(right, parent_key);
         parent.delete_at(parent_key_index.value_after());
@@ -218,21 +218,34 @@ fn merge_leaf<TStorage: Storage, TKey: TreeKey>(
 #[instrument(skip(transaction), fields(transaction_id=?transaction.id()))]
 pub fn delete<TStorage: Storage, TKey: TreeKey>(
     transaction: &mut TreeTransaction<TStorage, TKey>,
-    key: TKey,
+    key: TKey::Borrowed<'_>,
+) -> Result<Option<Vec<u8>>, TreeError<TStorage::PageId>> {
+    transaction.retry_on_conflict(key, |transaction| delete_once(transaction, key))
+}
+
+fn delete_once<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    key: TKey::Borrowed<'_>,
 ) -> Result<Option<Vec<u8>>, TreeError<TStorage::PageId>> {
     let root = transaction.get_root()?;
     let starting_leaf = leaf_search(transaction, root, key)?;
 
     let result = transaction.write_nodes(starting_leaf, |node| node.delete(key))?;
 
-    match result {
-        Some((deleted, needs_merge)) => {
-            if needs_merge {
-                merge_leaf(transaction, starting_leaf)?;
-            }
+    let Some((deleted, needs_merge)) = result else {
+        transaction.record_delete(key);
 
-            Ok(Some(deleted))
-        }
-        None => Ok(None),
+        return Ok(None);
+    };
+
+    let deleted = overflow::take(transaction, deleted)?;
+
+    if needs_merge {
+        merge_leaf(transaction, starting_leaf)?;
     }
+
+    transaction.capture_change(key, Some(&deleted), None);
+    transaction.record_delete(key);
+
+    Ok(Some(deleted))
 }
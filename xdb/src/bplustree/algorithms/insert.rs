@@ -4,10 +4,12 @@ use std::collections::HashSet;
 use tracing::{error, instrument, trace};
 
 use crate::bplustree::algorithms::leaf_search;
+use crate::bplustree::node::leaf::MAX_INLINE_VALUE_SIZE;
 use crate::bplustree::node::leaf::builder::MaterializedTopology;
+use crate::bplustree::overflow::{LeafValue, StoredValue};
 use crate::bplustree::{
     AnyNodeId, InteriorNode, InteriorNodeId, LeafNodeId, Node, NodeId as _, TreeError, TreeKey,
-    TreeTransaction,
+    TreeTransaction, overflow,
 };
 use crate::storage::{PageId, PageReservation as _, Storage};
 
@@ -15,7 +17,7 @@ fn create_new_root<'storage, TStorage: Storage, TKey: TreeKey>(
     transaction: &mut TreeTransaction<'storage, TStorage, TKey>,
     reservation: <TStorage as Storage>::PageReservation<'storage>,
     left: AnyNodeId,
-    key: TKey,
+    key: TKey::Borrowed<'_>,
     right: AnyNodeId,
 ) -> Result<(), TreeError<TStorage::PageId>> {
     let new_root_id = InteriorNodeId::new(reservation.index().serialize());
@@ -55,12 +57,14 @@ fn split_leaf_root<TStorage: Storage, TKey: TreeKey>(
         .build()
     })?;
 
+    let split_key = TKey::to_owned(new_leaf.first_key().unwrap());
+
     transaction.insert_reserved(new_leaf_reservation, new_leaf)?;
     create_new_root(
         transaction,
         new_root_reservation,
         root_id.into(),
-        new_leaf.first_key().unwrap(),
+        TKey::borrow(&split_key),
         new_leaf_id.into(),
     )?;
 
@@ -110,7 +114,12 @@ fn split_interior_node<TStorage: Storage, TKey: TreeKey>(
     if let Some(parent_id) = parent_id {
         trace!(node_id=?split_id, new_node_id=?new_node_id, parent_id=?parent_id, "split interior node");
 
-        insert_child(transaction, parent_id, split_key, new_node_id.into())?;
+        insert_child(
+            transaction,
+            parent_id,
+            TKey::borrow(&split_key),
+            new_node_id.into(),
+        )?;
     } else {
         let new_root_reservation = transaction.reserve_node()?;
         let new_root_id = InteriorNodeId::new(new_root_reservation.index().serialize());
@@ -122,7 +131,7 @@ fn split_interior_node<TStorage: Storage, TKey: TreeKey>(
             transaction,
             new_root_reservation,
             split_id.into(),
-            split_key,
+            TKey::borrow(&split_key),
             new_node_id.into(),
         )?;
 
@@ -142,7 +151,7 @@ fn split_interior_node<TStorage: Storage, TKey: TreeKey>(
 fn insert_child<TStorage: Storage, TKey: TreeKey>(
     transaction: &mut TreeTransaction<TStorage, TKey>,
     parent_id: InteriorNodeId,
-    key: TKey,
+    key: TKey::Borrowed<'_>,
     child_id: AnyNodeId,
 ) -> Result<(), TreeError<TStorage::PageId>> {
     transaction.write_nodes(parent_id, |node| node.insert_node(key, child_id))?;
@@ -162,7 +171,7 @@ fn split_leaf<TStorage: Storage, TKey: TreeKey>(
 
     if !(has_spare_capacity) {
         error!("the node does not have spare capacity");
-        panic!("the node does not have spare capacity",);
+        panic!("the node does not have spare capacity");
     }
 
     let new_leaf_reservation = transaction.reserve_node()?;
@@ -187,7 +196,7 @@ fn split_leaf<TStorage: Storage, TKey: TreeKey>(
         })?;
     }
 
-    let split_key = new_leaf.first_key().unwrap();
+    let split_key = TKey::to_owned(new_leaf.first_key().unwrap());
     trace!(
         node_id=?leaf_id,
         split_id=?new_leaf_id,
@@ -197,7 +206,12 @@ fn split_leaf<TStorage: Storage, TKey: TreeKey>(
 
     transaction.insert_reserved(new_leaf_reservation, new_leaf)?;
 
-    insert_child(transaction, parent_id, split_key, new_leaf_id.into())?;
+    insert_child(
+        transaction,
+        parent_id,
+        TKey::borrow(&split_key),
+        new_leaf_id.into(),
+    )?;
 
     Ok(())
 }
@@ -205,14 +219,61 @@ fn split_leaf<TStorage: Storage, TKey: TreeKey>(
 #[instrument(skip(value, transaction), fields(transaction_id=?transaction.id()))]
 pub fn insert<TStorage: Storage, TKey: TreeKey>(
     transaction: &mut TreeTransaction<TStorage, TKey>,
-    key: TKey,
+    key: TKey::Borrowed<'_>,
     value: &[u8],
 ) -> Result<(), TreeError<TStorage::PageId>> {
+    transaction.retry_on_conflict(key, |transaction| insert_once(transaction, key, value))
+}
+
+fn insert_once<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    key: TKey::Borrowed<'_>,
+    value: &[u8],
+) -> Result<(), TreeError<TStorage::PageId>> {
+    let key_size = TKey::encoded_size(key);
+
+    if key_size > TKey::MAX_ENCODED_SIZE {
+        return Err(TreeError::KeyTooLarge {
+            size: key_size,
+            max: TKey::MAX_ENCODED_SIZE,
+        });
+    }
+
+    let leaf_value = if value.len() > MAX_INLINE_VALUE_SIZE {
+        LeafValue::Overflow(overflow::write(transaction, value)?)
+    } else {
+        LeafValue::Inline(value)
+    };
+
+    let replaced = insert_value(transaction, key, leaf_value)?;
+
+    let old_value = match &replaced {
+        Some(replaced) if transaction.captures_changes() => {
+            Some(overflow::read(transaction, replaced.clone())?)
+        }
+        _ => None,
+    };
+
+    if let Some(replaced) = replaced {
+        overflow::free(transaction, &replaced)?;
+    }
+
+    transaction.capture_change(key, old_value.as_deref(), Some(value));
+    transaction.record_insert(key, value);
+
+    Ok(())
+}
+
+fn insert_value<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    key: TKey::Borrowed<'_>,
+    value: LeafValue<'_>,
+) -> Result<Option<StoredValue>, TreeError<TStorage::PageId>> {
     let root_index = transaction.get_root()?;
     let target_node_id = leaf_search(transaction, root_index, key)?;
 
     let (can_fit, parent) = transaction.read_nodes(target_node_id, |node| {
-        (node.can_fit(value.len()), node.parent())
+        (node.can_fit(key, value.inline_size()), node.parent())
     })?;
 
     if !can_fit {
@@ -220,11 +281,11 @@ pub fn insert<TStorage: Storage, TKey: TreeKey>(
             if !transaction.read_nodes(parent, InteriorNode::has_spare_capacity)? {
                 let _ = split_interior_node(transaction, parent)?;
 
-                return insert(transaction, key, value);
+                return insert_value(transaction, key, value);
             }
             split_leaf(transaction, target_node_id)?;
 
-            return insert(transaction, key, value);
+            return insert_value(transaction, key, value);
         }
         if !(root_index == target_node_id.into()) {
             error!(?root_index, ?target_node_id, "target node is not the root");
@@ -233,10 +294,8 @@ pub fn insert<TStorage: Storage, TKey: TreeKey>(
 
         split_leaf_root(transaction)?;
 
-        return insert(transaction, key, value);
+        return insert_value(transaction, key, value);
     }
 
-    transaction.write_nodes(target_node_id, |node| node.insert(key, value))?;
-
-    Ok(())
+    transaction.write_nodes(target_node_id, |node| node.insert(key, value))
 }
@@ -1,22 +1,34 @@
+pub mod bulk_load;
 pub mod delete;
 pub mod insert;
 
 use crate::bplustree::node::{AnyNodeId, AnyNodeKind, LeafNodeId};
-use crate::bplustree::{TreeError, TreeKey, TreeTransaction};
-use crate::storage::Storage;
+use crate::bplustree::{TreeError, TreeKey, TreeTransaction, overflow};
+use crate::storage::{ReadTransaction, Storage};
 
-pub fn find<TStorage: Storage, TKey: TreeKey>(
-    transaction: &mut TreeTransaction<TStorage, TKey>,
-    key: TKey,
-) -> Result<Option<Vec<u8>>, TreeError<TStorage::PageId>> {
+pub fn find<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey, TTransaction>,
+    key: TKey::Borrowed<'_>,
+) -> Result<Option<Vec<u8>>, TreeError<TStorage::PageId>>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
     let root_id = transaction.get_root()?;
     let leaf = leaf_search(transaction, root_id, key)?;
 
-    transaction.read_nodes(leaf, |leaf| {
+    let value = transaction.read_nodes(leaf, |leaf| {
         leaf.find(key)
             .and_then(|i| leaf.entry(i))
-            .map(|x| x.value().to_vec())
-    })
+            .map(|x| x.value().to_stored())
+    })?;
+
+    let value = value
+        .map(|value| overflow::read(transaction, value))
+        .transpose()?;
+
+    transaction.record_read(key, value.as_deref());
+
+    Ok(value)
 }
 
 enum LeafSearchResult {
@@ -24,24 +36,19 @@ enum LeafSearchResult {
     Done(LeafNodeId),
 }
 
-pub(super) fn leaf_search<TStorage: Storage, TKey: TreeKey>(
-    transaction: &mut TreeTransaction<TStorage, TKey>,
+pub(super) fn leaf_search<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey, TTransaction>,
     start_id: AnyNodeId,
-    key: TKey,
-) -> Result<LeafNodeId, TreeError<TStorage::PageId>> {
+    key: TKey::Borrowed<'_>,
+) -> Result<LeafNodeId, TreeError<TStorage::PageId>>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
     let result = transaction.read_nodes(start_id, |node| {
         match node.as_any() {
-            AnyNodeKind::Interior(node) => {
-                for (key_index, node_key) in node.keys() {
-                    if key < node_key {
-                        return LeafSearchResult::Recurse(
-                            node.value_at(key_index.value_before()).unwrap(),
-                        );
-                    }
-                }
-
-                LeafSearchResult::Recurse(node.last_value().unwrap())
-            }
+            AnyNodeKind::Interior(node) => LeafSearchResult::Recurse(
+                node.value_at(node.upper_bound(key).value_before()).unwrap(),
+            ),
             AnyNodeKind::Leaf(_) => {
                 // TODO can we avoid from_any here and instead make the conversion happen higher in
                 // the transaction API?
@@ -56,10 +63,13 @@ pub(super) fn leaf_search<TStorage: Storage, TKey: TreeKey>(
     }
 }
 
-pub(super) fn first_leaf<TStorage: Storage, TKey: TreeKey>(
-    transaction: &mut TreeTransaction<TStorage, TKey>,
+pub(super) fn first_leaf<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey, TTransaction>,
     root: AnyNodeId,
-) -> Result<LeafNodeId, TreeError<TStorage::PageId>> {
+) -> Result<LeafNodeId, TreeError<TStorage::PageId>>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
     let result = transaction.read_nodes(root, |node| match node.as_any() {
         AnyNodeKind::Interior(interior_node_reader) => {
             LeafSearchResult::Recurse(interior_node_reader.first_value().unwrap())
@@ -73,10 +83,13 @@ pub(super) fn first_leaf<TStorage: Storage, TKey: TreeKey>(
     }
 }
 
-pub(super) fn last_leaf<TStorage: Storage, TKey: TreeKey>(
-    transaction: &mut TreeTransaction<TStorage, TKey>,
+pub(super) fn last_leaf<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey, TTransaction>,
     root: AnyNodeId,
-) -> Result<LeafNodeId, TreeError<TStorage::PageId>> {
+) -> Result<LeafNodeId, TreeError<TStorage::PageId>>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
     let result = transaction.read_nodes(root, |node| match node.as_any() {
         AnyNodeKind::Interior(interior_node_reader) => {
             LeafSearchResult::Recurse(interior_node_reader.last_value().unwrap())
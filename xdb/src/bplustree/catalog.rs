@@ -0,0 +1,328 @@
+use bytemuck::{bytes_of, pod_read_unaligned};
+
+use crate::bplustree::algorithms::delete::delete;
+use crate::bplustree::algorithms::find;
+use crate::bplustree::algorithms::insert::insert;
+use crate::bplustree::node::AnyNodeKind;
+use crate::bplustree::overflow::{LeafValue, StoredValue};
+use crate::bplustree::{
+    AnyNodeId, ByteKey, Tree, TreeError, TreeHeader, TreeKey, TreeTransaction, overflow,
+};
+use crate::storage::{SerializedPageId, Storage};
+
+/// Holds any number of named trees in a single storage.
+///
+/// The catalog is itself a tree, with its header in the first page of the storage, mapping the
+/// names of the trees to the pages holding their headers.
+#[derive(Debug)]
+pub struct Catalog<T: Storage> {
+    catalog: Tree<T, ByteKey>,
+}
+
+impl<T: Storage> Catalog<T> {
+    pub fn new(storage: T) -> Result<Self, TreeError<T::PageId>> {
+        Ok(Self {
+            catalog: Tree::new(storage)?,
+        })
+    }
+
+    /// Opens a catalog that was previously created in the storage with `new`.
+    pub fn open(storage: T) -> Result<Self, TreeError<T::PageId>> {
+        Ok(Self {
+            catalog: Tree::open(storage)?,
+        })
+    }
+
+    pub fn transaction(&self) -> Result<CatalogTransaction<'_, T>, TreeError<T::PageId>> {
+        Ok(CatalogTransaction {
+            transaction: Some(self.catalog.transaction()?),
+        })
+    }
+
+    /// Creates an empty tree in a transaction of its own.
+    pub fn create_tree<TKey: TreeKey>(
+        &self,
+        name: &[u8],
+    ) -> Result<Tree<T, TKey>, TreeError<T::PageId>> {
+        let mut transaction = self.transaction()?;
+        let header = transaction.create::<TKey>(name)?;
+        transaction.commit()?;
+
+        Ok(Tree::from_header(self.catalog.storage.clone(), header))
+    }
+
+    /// Opens a tree with keys of type `TKey`. The returned tree must not be used after it is
+    /// dropped from the catalog.
+    pub fn open_tree<TKey: TreeKey>(
+        &self,
+        name: &[u8],
+    ) -> Result<Tree<T, TKey>, TreeError<T::PageId>> {
+        let mut transaction = self.transaction()?;
+        let header = transaction.header_of::<TKey>(name)?;
+        transaction.rollback()?;
+
+        Ok(Tree::from_header(self.catalog.storage.clone(), header))
+    }
+
+    /// Drops a tree in a transaction of its own.
+    pub fn drop_tree<TKey: TreeKey>(&self, name: &[u8]) -> Result<(), TreeError<T::PageId>> {
+        let mut transaction = self.transaction()?;
+        transaction.drop_tree::<TKey>(name)?;
+        transaction.commit()
+    }
+
+    pub fn list_trees(&self) -> Result<Vec<Vec<u8>>, TreeError<T::PageId>> {
+        let mut transaction = self.transaction()?;
+        let trees = transaction.list_trees()?;
+        transaction.rollback()?;
+
+        Ok(trees)
+    }
+}
+
+/// Creates and drops trees within a single transaction. The changes are only visible to others
+/// once it is committed, and if any of the operations returns an error, the transaction must be
+/// rolled back.
+#[derive(Debug)]
+pub struct CatalogTransaction<'storage, T: Storage + 'storage> {
+    // this is only `None` while one of the trees is being modified, see `with_tree`
+    transaction: Option<TreeTransaction<'storage, T, ByteKey>>,
+}
+
+impl<'storage, T: Storage + 'storage> CatalogTransaction<'storage, T> {
+    const fn catalog(&mut self) -> &mut TreeTransaction<'storage, T, ByteKey> {
+        self.transaction.as_mut().unwrap()
+    }
+
+    fn with_tree<TKey: TreeKey, TReturn>(
+        &mut self,
+        header: SerializedPageId,
+        action: impl FnOnce(&mut TreeTransaction<'storage, T, TKey>) -> TReturn,
+    ) -> TReturn {
+        let catalog = self.transaction.take().unwrap();
+        let catalog_header = catalog.header();
+
+        let mut transaction = catalog.into_tree(header);
+        let result = action(&mut transaction);
+
+        self.transaction = Some(transaction.into_tree(catalog_header));
+
+        result
+    }
+
+    pub fn create_tree<TKey: TreeKey>(&mut self, name: &[u8]) -> Result<(), TreeError<T::PageId>> {
+        self.create::<TKey>(name).map(drop)
+    }
+
+    fn create<TKey: TreeKey>(
+        &mut self,
+        name: &[u8],
+    ) -> Result<SerializedPageId, TreeError<T::PageId>> {
+        if find(self.catalog(), name)?.is_some() {
+            return Err(TreeError::TreeAlreadyExists);
+        }
+
+        let header = TreeHeader::create_in::<T, TKey>(self.catalog().storage_transaction())?;
+
+        insert(self.catalog(), name, bytes_of(&header))?;
+
+        Ok(header)
+    }
+
+    /// Drops the tree and frees all of its pages.
+    pub fn drop_tree<TKey: TreeKey>(&mut self, name: &[u8]) -> Result<(), TreeError<T::PageId>> {
+        let header = self.header_of::<TKey>(name)?;
+
+        delete(self.catalog(), name)?;
+
+        self.with_tree::<TKey, _>(header, |transaction| {
+            let root = transaction.get_root()?;
+
+            free_node(transaction, root)?;
+            transaction.delete_page(header)
+        })
+    }
+
+    pub fn list_trees(&mut self) -> Result<Vec<Vec<u8>>, TreeError<T::PageId>> {
+        self.catalog()
+            .iter()?
+            .map(|entry| entry.map(|(name, _)| name))
+            .collect()
+    }
+
+    fn header_of<TKey: TreeKey>(
+        &mut self,
+        name: &[u8],
+    ) -> Result<SerializedPageId, TreeError<T::PageId>> {
+        let Some(header) = find(self.catalog(), name)? else {
+            return Err(TreeError::TreeNotFound);
+        };
+        let header = pod_read_unaligned(&header);
+
+        TreeHeader::check::<T, TKey>(self.catalog().storage_transaction(), header)?;
+
+        Ok(header)
+    }
+
+    pub fn commit(self) -> Result<(), TreeError<T::PageId>> {
+        self.transaction.unwrap().commit()
+    }
+
+    pub fn rollback(self) -> Result<(), TreeError<T::PageId>> {
+        self.transaction.unwrap().rollback()
+    }
+}
+
+fn free_node<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    node_id: AnyNodeId,
+) -> Result<(), TreeError<TStorage::PageId>> {
+    let (children, overflow_values) =
+        transaction.read_nodes(node_id, |node| match node.as_any() {
+            AnyNodeKind::Interior(node) => (node.values().map(|(_, x)| x).collect(), vec![]),
+            AnyNodeKind::Leaf(node) => (
+                vec![],
+                node.entries()
+                    .filter_map(|entry| match entry.value() {
+                        LeafValue::Inline(_) => None,
+                        LeafValue::Overflow(pointer) => Some(StoredValue::Overflow(pointer)),
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+        })?;
+
+    for value in overflow_values {
+        overflow::free(transaction, &value)?;
+    }
+
+    for child in children {
+        free_node(transaction, child)?;
+    }
+
+    transaction.delete_node(node_id)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    #[cfg(not(miri))]
+    use test_log::test;
+
+    use super::*;
+    use crate::bplustree::algorithms::insert::insert;
+    use crate::storage::in_memory::{InMemoryPageId, InMemoryStorage};
+    use crate::storage::{PageId as _, ReadTransaction as _};
+
+    #[test]
+    fn create_and_drop_trees() {
+        let catalog = Catalog::new(InMemoryStorage::new()).unwrap();
+
+        let numbers = catalog.create_tree::<u64>(b"numbers").unwrap();
+        let names = catalog.create_tree::<ByteKey>(b"names").unwrap();
+
+        let mut transaction = numbers.transaction().unwrap();
+        for i in 0..1000u64 {
+            insert(&mut transaction, i, &i.to_le_bytes()).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let mut transaction = names.transaction().unwrap();
+        insert(&mut transaction, b"a", b"1").unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(
+            catalog.list_trees().unwrap(),
+            vec![b"names".to_vec(), b"numbers".to_vec()]
+        );
+
+        let numbers = catalog.open_tree::<u64>(b"numbers").unwrap();
+        assert_eq!(numbers.iter().unwrap().count(), 1000);
+
+        let names = catalog.open_tree::<ByteKey>(b"names").unwrap();
+        assert_eq!(
+            names
+                .iter()
+                .unwrap()
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>(),
+            vec![(b"a".to_vec(), b"1".to_vec())]
+        );
+
+        catalog.drop_tree::<u64>(b"numbers").unwrap();
+
+        assert_eq!(catalog.list_trees().unwrap(), vec![b"names".to_vec()]);
+        assert!(matches!(
+            catalog.open_tree::<u64>(b"numbers"),
+            Err(TreeError::TreeNotFound)
+        ));
+    }
+
+    #[test]
+    fn create_existing_tree() {
+        let catalog = Catalog::new(InMemoryStorage::new()).unwrap();
+
+        catalog.create_tree::<u64>(b"numbers").unwrap();
+
+        assert!(matches!(
+            catalog.create_tree::<u64>(b"numbers"),
+            Err(TreeError::TreeAlreadyExists)
+        ));
+    }
+
+    #[test]
+    fn open_with_wrong_key_type() {
+        let catalog = Catalog::new(InMemoryStorage::new()).unwrap();
+
+        catalog.create_tree::<u64>(b"numbers").unwrap();
+
+        assert!(matches!(
+            catalog.open_tree::<u32>(b"numbers"),
+            Err(TreeError::KeySizeMismatch { .. })
+        ));
+        assert!(matches!(
+            catalog.drop_tree::<u32>(b"numbers"),
+            Err(TreeError::KeySizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rollback_create_and_drop() {
+        let catalog = Catalog::new(InMemoryStorage::new()).unwrap();
+
+        catalog.create_tree::<u64>(b"numbers").unwrap();
+
+        let mut transaction = catalog.transaction().unwrap();
+        transaction.create_tree::<u64>(b"other").unwrap();
+        transaction.drop_tree::<u64>(b"numbers").unwrap();
+        assert_eq!(transaction.list_trees().unwrap(), vec![b"other".to_vec()]);
+        transaction.rollback().unwrap();
+
+        assert_eq!(catalog.list_trees().unwrap(), vec![b"numbers".to_vec()]);
+        catalog.open_tree::<u64>(b"numbers").unwrap();
+    }
+
+    #[test]
+    fn drop_tree_frees_pages() {
+        let catalog = Catalog::new(InMemoryStorage::new()).unwrap();
+        let tree = catalog.create_tree::<u64>(b"numbers").unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..1000u64 {
+            insert(&mut transaction, i, &vec![1; 10_000]).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let mut transaction = catalog.transaction().unwrap();
+        let header = transaction.header_of::<u64>(b"numbers").unwrap();
+        transaction.drop_tree::<u64>(b"numbers").unwrap();
+
+        assert!(
+            transaction
+                .catalog()
+                .storage_transaction()
+                .read([InMemoryPageId::deserialize(header)], |_| ())
+                .is_err()
+        );
+        transaction.commit().unwrap();
+    }
+}
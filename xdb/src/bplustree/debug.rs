@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use std::fmt::Debug;
 
 use pretty_assertions::assert_eq;
 use tracing::debug;
@@ -8,10 +9,10 @@ use crate::bplustree::algorithms::{first_leaf, last_leaf};
 use crate::bplustree::{AnyNodeId, InteriorNodeId, Node as _, Tree, TreeKey, TreeTransaction};
 use crate::storage::Storage;
 
-pub fn assert_tree_equal<TStorage: Storage, TKey: TreeKey, TRightKey: TreeKey>(
+pub fn assert_tree_equal<TStorage: Storage, TKey: TreeKey, TRightKey: Debug + Clone + Eq>(
     left: &Tree<TStorage, TKey>,
     right: &BTreeMap<TRightKey, Vec<u8>>,
-    key_convert: impl Fn(TKey) -> TRightKey,
+    key_convert: impl Fn(TKey::Owned) -> TRightKey,
 ) {
     assert_eq!(
         left.iter()
@@ -21,7 +22,7 @@ pub fn assert_tree_equal<TStorage: Storage, TKey: TreeKey, TRightKey: TreeKey>(
             .collect::<Vec<_>>(),
         right
             .iter()
-            .map(|(x, y)| (*x, y.clone()))
+            .map(|(x, y)| (x.clone(), y.clone()))
             .collect::<Vec<_>>(),
     );
     assert_eq!(
@@ -34,7 +35,7 @@ pub fn assert_tree_equal<TStorage: Storage, TKey: TreeKey, TRightKey: TreeKey>(
         right
             .iter()
             .rev()
-            .map(|(x, y)| (*x, y.clone()))
+            .map(|(x, y)| (x.clone(), y.clone()))
             .collect::<Vec<_>>(),
     );
 }
@@ -54,13 +55,13 @@ pub fn assert_properties<TStorage: Storage, TKey: TreeKey>(
 fn assert_keys_lower_than_parent<TStorage: Storage, TKey: TreeKey>(
     transaction: &mut TreeTransaction<TStorage, TKey>,
     start_id: Option<AnyNodeId>,
-    start_min_key: Option<TKey>,
-    start_max_key: Option<TKey>,
+    start_min_key: Option<&TKey::Owned>,
+    start_max_key: Option<&TKey::Owned>,
 ) {
     let start_id = start_id.unwrap_or_else(|| transaction.get_root().unwrap());
 
     #[allow(clippy::type_complexity)]
-    let limits: Vec<(Option<TKey>, Option<TKey>, AnyNodeId)> = transaction
+    let limits: Vec<(Option<TKey::Owned>, Option<TKey::Owned>, AnyNodeId)> = transaction
         .read_nodes(start_id, |node| {
             let mut result = vec![];
 
@@ -68,10 +69,11 @@ fn assert_keys_lower_than_parent<TStorage: Storage, TKey: TreeKey>(
                 AnyNodeKind::Interior(interior_node) => {
                     for (index, key) in interior_node.keys() {
                         result.push((
-                            index.key_before().map_or(start_min_key, |key_before| {
-                                interior_node.key_at(key_before)
-                            }),
-                            Some(key),
+                            index.key_before().map_or_else(
+                                || start_min_key.cloned(),
+                                |key_before| Some(TKey::to_owned(interior_node.key_at(key_before))),
+                            ),
+                            Some(TKey::to_owned(key)),
                             interior_node.value_at(index.value_before()).unwrap(),
                         ));
                     }
@@ -81,11 +83,11 @@ fn assert_keys_lower_than_parent<TStorage: Storage, TKey: TreeKey>(
 
                         result.push((
                             if keys.is_empty() {
-                                start_min_key
+                                start_min_key.cloned()
                             } else {
-                                keys.last().copied().map(|x| x.1)
+                                keys.last().map(|x| TKey::to_owned(x.1))
                             },
-                            start_max_key,
+                            start_max_key.cloned(),
                             last_value,
                         ));
                     }
@@ -93,11 +95,11 @@ fn assert_keys_lower_than_parent<TStorage: Storage, TKey: TreeKey>(
                 AnyNodeKind::Leaf(leaf_node) => {
                     for entry in leaf_node.entries() {
                         if let Some(max_key) = start_max_key {
-                            assert!(entry.key() < max_key);
+                            assert!(TKey::compare(entry.key(), TKey::borrow(max_key)).is_lt());
                         }
 
                         if let Some(min_key) = start_min_key {
-                            assert!(entry.key() >= min_key);
+                            assert!(TKey::compare(entry.key(), TKey::borrow(min_key)).is_ge());
                         }
                     }
                 }
@@ -111,8 +113,8 @@ fn assert_keys_lower_than_parent<TStorage: Storage, TKey: TreeKey>(
         assert_keys_lower_than_parent(
             transaction,
             Some(node_id),
-            min_key.or(start_min_key),
-            max_key,
+            min_key.as_ref().or(start_min_key),
+            max_key.as_ref(),
         );
     }
 }
@@ -1,6 +1,7 @@
 use std::fmt::Write;
 
 use crate::bplustree::node::AnyNodeKind;
+use crate::bplustree::overflow::LeafValue;
 use crate::bplustree::{AnyNodeId, Node, NodeId, Tree, TreeError, TreeKey, TreeTransaction};
 use crate::storage::Storage;
 
@@ -74,11 +75,12 @@ impl<T: Storage, TKey: TreeKey> Tree<T, TKey> {
                     }
 
                     for entry in node.entries() {
-                        label.push(format!(
-                            "{:?}/{}",
-                            entry.key(),
-                            (stringify_value)(entry.value())
-                        ));
+                        let value = match entry.value() {
+                            LeafValue::Inline(value) => (stringify_value)(value),
+                            LeafValue::Overflow(pointer) => format!("{pointer:?}"),
+                        };
+
+                        label.push(format!("{:?}/{value}", entry.key()));
                     }
 
                     let label = label.join("\\n");
@@ -1,127 +1,533 @@
-use crate::bplustree::algorithms::{first_leaf, last_leaf};
-use crate::bplustree::{LeafNodeId, TreeError, TreeKey, TreeTransaction};
-use crate::storage::{PageId, Storage};
+use std::borrow::BorrowMut;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
-pub(super) type TreeIteratorItem<TKey, TPageId> = Result<(TKey, Vec<u8>), TreeError<TPageId>>;
+use crate::bplustree::algorithms::{first_leaf, last_leaf, leaf_search};
+use crate::bplustree::node::leaf::LeafNode;
+use crate::bplustree::overflow::StoredValue;
+use crate::bplustree::{LeafNodeId, TreeError, TreeKey, TreeTransaction, overflow};
+use crate::storage::{ReadTransaction, Storage};
 
-pub(super) struct TreeIterator<'tree, T: Storage, TKey> {
-    transaction: TreeTransaction<'tree, T, TKey>,
+pub(super) type TreeIteratorItem<TKey, TPageId> =
+    Result<(<TKey as TreeKey>::Owned, Vec<u8>), TreeError<TPageId>>;
+type TryNextResult<TKey, TPageId> =
+    Result<Option<(<TKey as TreeKey>::Owned, Vec<u8>)>, TreeError<TPageId>>;
+
+/// Iterates over the entries with keys within `bounds`.
+///
+/// The transaction can either be owned by the iterator, or borrowed from the caller.
+pub(super) struct TreeIterator<
+    'storage,
+    T: Storage + 'storage,
+    TKey: TreeKey,
+    TTransaction,
+    TTreeTransaction,
+> {
+    transaction: TTreeTransaction,
+    bounds: (Bound<TKey::Owned>, Bound<TKey::Owned>),
     current_forward_leaf: LeafNodeId,
     forward_index: usize,
     current_backward_leaf: LeafNodeId,
     backward_index: usize,
+    _storage: PhantomData<&'storage (T, TTransaction)>,
 }
 
-impl<'tree, T: Storage, TKey: TreeKey> TreeIterator<'tree, T, TKey> {
-    pub fn new(
-        mut transaction: TreeTransaction<'tree, T, TKey>,
+impl<'storage, T: Storage + 'storage, TKey: TreeKey, TTransaction, TTreeTransaction>
+    TreeIterator<'storage, T, TKey, TTransaction, TTreeTransaction>
+where
+    TTransaction: ReadTransaction<'storage, Storage = T>,
+    TTreeTransaction: BorrowMut<TreeTransaction<'storage, T, TKey, TTransaction>>,
+{
+    pub fn new<'key>(
+        mut transaction: TTreeTransaction,
+        bounds: impl RangeBounds<TKey::Borrowed<'key>>,
     ) -> Result<Self, TreeError<T::PageId>> {
-        let root = transaction.get_root()?;
-        let starting_leaf_forwards = first_leaf(&mut transaction, root)?;
-        let starting_leaf_backwards = last_leaf(&mut transaction, root)?;
+        let bounds = (
+            bounds.start_bound().map(|x| TKey::to_owned(*x)),
+            bounds.end_bound().map(|x| TKey::to_owned(*x)),
+        );
+        let tree_transaction = transaction.borrow_mut();
+        let root = tree_transaction.get_root()?;
+
+        let (starting_leaf_forwards, forward_index) = match bounds.0.as_ref().map(TKey::borrow) {
+            Bound::Included(key) => {
+                let leaf = leaf_search(tree_transaction, root, key)?;
 
-        let backward_index =
-            transaction.read_nodes(starting_leaf_backwards, super::node::leaf::LeafNode::len)?;
+                (
+                    leaf,
+                    tree_transaction.read_nodes(leaf, |node| node.lower_bound(key))?,
+                )
+            }
+            Bound::Excluded(key) => {
+                let leaf = leaf_search(tree_transaction, root, key)?;
+
+                (
+                    leaf,
+                    tree_transaction.read_nodes(leaf, |node| node.upper_bound(key))?,
+                )
+            }
+            Bound::Unbounded => (first_leaf(tree_transaction, root)?, 0),
+        };
+
+        let (starting_leaf_backwards, backward_index) = match bounds.1.as_ref().map(TKey::borrow) {
+            Bound::Included(key) => {
+                let leaf = leaf_search(tree_transaction, root, key)?;
+
+                (
+                    leaf,
+                    tree_transaction.read_nodes(leaf, |node| node.upper_bound(key))?,
+                )
+            }
+            Bound::Excluded(key) => {
+                let leaf = leaf_search(tree_transaction, root, key)?;
+
+                (
+                    leaf,
+                    tree_transaction.read_nodes(leaf, |node| node.lower_bound(key))?,
+                )
+            }
+            Bound::Unbounded => {
+                let leaf = last_leaf(tree_transaction, root)?;
+
+                (leaf, tree_transaction.read_nodes(leaf, LeafNode::len)?)
+            }
+        };
 
         Ok(Self {
             transaction,
+            bounds,
             current_forward_leaf: starting_leaf_forwards,
             current_backward_leaf: starting_leaf_backwards,
-            forward_index: 0,
+            forward_index,
             backward_index,
+            _storage: PhantomData,
         })
     }
+
+    fn is_exhausted(&self) -> bool {
+        self.current_forward_leaf == self.current_backward_leaf
+            && self.forward_index == self.backward_index
+    }
+
+    /// Moves the forward cursor onto the backward one, so that both directions return `None` from
+    /// now on.
+    const fn exhaust(&mut self) {
+        self.current_forward_leaf = self.current_backward_leaf;
+        self.forward_index = self.backward_index;
+    }
 }
 
-enum IteratorResult<TKey, TPageId: PageId> {
-    Value(TreeIteratorItem<TKey, TPageId>),
+enum IteratorResult<TKey> {
+    Value((TKey, StoredValue)),
     Next(LeafNodeId),
     None,
 }
 
-impl<T: Storage, TKey: TreeKey> Iterator for TreeIterator<'_, T, TKey> {
-    type Item = Result<(TKey, Vec<u8>), TreeError<T::PageId>>;
-
-    // TODO get rid of all the unwraps!
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current_forward_leaf == self.current_backward_leaf
-            && self.forward_index == self.backward_index
-        {
-            return None;
+impl<'storage, T: Storage + 'storage, TKey: TreeKey, TTransaction, TTreeTransaction>
+    TreeIterator<'storage, T, TKey, TTransaction, TTreeTransaction>
+where
+    TTransaction: ReadTransaction<'storage, Storage = T>,
+    TTreeTransaction: BorrowMut<TreeTransaction<'storage, T, TKey, TTransaction>>,
+{
+    fn try_next(&mut self) -> TryNextResult<TKey, T::PageId> {
+        if self.is_exhausted() {
+            return Ok(None);
         }
 
-        let read_result = self
-            .transaction
-            .read_nodes(self.current_forward_leaf, |node| {
-                let entry = node.entry(self.forward_index);
-                match entry {
-                    Some(entry) => {
-                        self.forward_index += 1;
+        let read_result = self.transaction.borrow_mut().read_nodes(
+            self.current_forward_leaf,
+            |node| match node.entry(self.forward_index) {
+                Some(entry) => {
+                    self.forward_index += 1;
 
-                        IteratorResult::Value(Ok((entry.key(), entry.value().to_vec())))
-                    }
-                    None => node
-                        .next()
-                        .map_or(IteratorResult::None, IteratorResult::Next),
+                    IteratorResult::Value((TKey::to_owned(entry.key()), entry.value().to_stored()))
                 }
-            })
-            .unwrap();
+                None => node
+                    .next()
+                    .map_or(IteratorResult::None, IteratorResult::Next),
+            },
+        )?;
 
         match read_result {
-            IteratorResult::Value(x) => Some(x),
+            IteratorResult::Value((key, _)) if !self.bounds.contains(&key) => {
+                self.exhaust();
+
+                Ok(None)
+            }
+            IteratorResult::Value((key, value)) => Ok(Some((
+                key,
+                overflow::read(self.transaction.borrow_mut(), value)?,
+            ))),
             IteratorResult::Next(next_leaf) => {
                 self.current_forward_leaf = next_leaf;
                 self.forward_index = 0;
 
-                self.next()
+                self.try_next()
+            }
+            IteratorResult::None => {
+                self.exhaust();
+
+                Ok(None)
             }
-            IteratorResult::None => None,
         }
     }
-}
 
-impl<T: Storage, TKey: TreeKey> DoubleEndedIterator for TreeIterator<'_, T, TKey> {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        if self.current_forward_leaf == self.current_backward_leaf
-            && self.forward_index == self.backward_index
-        {
-            return None;
+    fn try_next_back(&mut self) -> TryNextResult<TKey, T::PageId> {
+        if self.is_exhausted() {
+            return Ok(None);
         }
 
-        let read_result = self
-            .transaction
-            .read_nodes(self.current_backward_leaf, |node| {
-                let entry = if self.backward_index == 0 {
-                    None
-                } else {
-                    node.entry(self.backward_index - 1)
-                };
+        let read_result =
+            self.transaction
+                .borrow_mut()
+                .read_nodes(self.current_backward_leaf, |node| {
+                    let entry = if self.backward_index == 0 {
+                        None
+                    } else {
+                        node.entry(self.backward_index - 1)
+                    };
 
-                match entry {
-                    Some(entry) => {
-                        self.backward_index -= 1;
+                    match entry {
+                        Some(entry) => {
+                            self.backward_index -= 1;
 
-                        IteratorResult::Value(Ok((entry.key(), entry.value().to_vec())))
+                            IteratorResult::Value((
+                                TKey::to_owned(entry.key()),
+                                entry.value().to_stored(),
+                            ))
+                        }
+                        None => node
+                            .previous()
+                            .map_or(IteratorResult::None, IteratorResult::Next),
                     }
-                    None => node
-                        .previous()
-                        .map_or(IteratorResult::None, IteratorResult::Next),
-                }
-            })
-            .unwrap();
+                })?;
 
         match read_result {
-            IteratorResult::Value(x) => Some(x),
+            IteratorResult::Value((key, _)) if !self.bounds.contains(&key) => {
+                self.exhaust();
+
+                Ok(None)
+            }
+            IteratorResult::Value((key, value)) => Ok(Some((
+                key,
+                overflow::read(self.transaction.borrow_mut(), value)?,
+            ))),
             IteratorResult::Next(next_leaf) => {
-                self.current_backward_leaf = next_leaf;
                 self.backward_index = self
                     .transaction
-                    .read_nodes(next_leaf, super::node::leaf::LeafNode::len)
-                    .unwrap();
+                    .borrow_mut()
+                    .read_nodes(next_leaf, LeafNode::len)?;
+                self.current_backward_leaf = next_leaf;
+
+                self.try_next_back()
+            }
+            IteratorResult::None => {
+                self.exhaust();
 
-                self.next_back()
+                Ok(None)
             }
-            IteratorResult::None => None,
         }
     }
+
+    /// The iterator is fused after the first error, as there is no position it could reliably
+    /// continue from.
+    const fn fuse_on_error(
+        &mut self,
+        result: TryNextResult<TKey, T::PageId>,
+    ) -> Option<TreeIteratorItem<TKey, T::PageId>> {
+        if result.is_err() {
+            self.exhaust();
+        }
+
+        result.transpose()
+    }
+}
+
+impl<'storage, T: Storage + 'storage, TKey: TreeKey, TTransaction, TTreeTransaction> Iterator
+    for TreeIterator<'storage, T, TKey, TTransaction, TTreeTransaction>
+where
+    TTransaction: ReadTransaction<'storage, Storage = T>,
+    TTreeTransaction: BorrowMut<TreeTransaction<'storage, T, TKey, TTransaction>>,
+{
+    type Item = TreeIteratorItem<TKey, T::PageId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.try_next();
+
+        self.fuse_on_error(result)
+    }
+}
+
+impl<'storage, T: Storage + 'storage, TKey: TreeKey, TTransaction, TTreeTransaction>
+    DoubleEndedIterator for TreeIterator<'storage, T, TKey, TTransaction, TTreeTransaction>
+where
+    TTransaction: ReadTransaction<'storage, Storage = T>,
+    TTreeTransaction: BorrowMut<TreeTransaction<'storage, T, TKey, TTransaction>>,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let result = self.try_next_back();
+
+        self.fuse_on_error(result)
+    }
+}
+
+impl<'storage, T: Storage + 'storage, TKey: TreeKey, TTransaction, TTreeTransaction> FusedIterator
+    for TreeIterator<'storage, T, TKey, TTransaction, TTreeTransaction>
+where
+    TTransaction: ReadTransaction<'storage, Storage = T>,
+    TTreeTransaction: BorrowMut<TreeTransaction<'storage, T, TKey, TTransaction>>,
+{
+}
+
+#[cfg(test)]
+mod test {
+    use std::marker::PhantomData;
+    use std::time::Instant;
+
+    use pretty_assertions::assert_eq;
+
+    use crate::bplustree::algorithms::insert::insert;
+    use crate::bplustree::{Tree, TreeError};
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::{
+        Change, IsolationLevel, PageReservation, ReadTransaction, Savepoint, Storage, StorageError,
+        Transaction, TransactionId,
+    };
+    use crate::sync::Arc;
+    use crate::sync::atomic::{AtomicUsize, Ordering};
+
+    struct FailingPageReservation<'a, TStorage: Storage + 'a>(TStorage::PageReservation<'a>);
+
+    impl<'a, TStorage: Storage + 'a> PageReservation<'a> for FailingPageReservation<'a, TStorage> {
+        type Storage = FailingStorage<TStorage>;
+
+        fn index(&self) -> TStorage::PageId {
+            self.0.index()
+        }
+    }
+
+    #[derive(Debug)]
+    struct FailingTransaction<
+        'a,
+        TStorage: Storage + 'a,
+        TTransaction = <TStorage as Storage>::Transaction<'a>,
+    >(TTransaction, Arc<AtomicUsize>, PhantomData<&'a TStorage>);
+
+    impl<'a, TStorage: Storage, TTransaction> ReadTransaction<'a>
+        for FailingTransaction<'a, TStorage, TTransaction>
+    where
+        TTransaction: ReadTransaction<'a, Storage = TStorage>,
+    {
+        type Storage = FailingStorage<TStorage>;
+
+        fn id(&self) -> TransactionId {
+            self.0.id()
+        }
+
+        fn set_deadline(&mut self, deadline: Instant) {
+            self.0.set_deadline(deadline);
+        }
+
+        fn read<TReturn, const N: usize>(
+            &mut self,
+            indices: impl Into<[TStorage::PageId; N]>,
+            read: impl FnOnce([&TStorage::Page; N]) -> TReturn,
+        ) -> Result<TReturn, StorageError<TStorage::PageId>> {
+            let indices = indices.into();
+
+            if self
+                .1
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+                .is_err()
+            {
+                return Err(StorageError::PageNotFound(
+                    indices.into_iter().next().unwrap(),
+                ));
+            }
+
+            self.0.read(indices, read)
+        }
+
+        fn commit(self) -> Result<(), StorageError<TStorage::PageId>> {
+            self.0.commit()
+        }
+
+        fn rollback(self) -> Result<(), StorageError<TStorage::PageId>> {
+            self.0.rollback()
+        }
+    }
+
+    impl<'a, TStorage: Storage> Transaction<'a> for FailingTransaction<'a, TStorage> {
+        fn write<TReturn, const N: usize>(
+            &mut self,
+            indices: impl Into<[TStorage::PageId; N]>,
+            write: impl FnOnce([&mut TStorage::Page; N]) -> TReturn,
+        ) -> Result<TReturn, StorageError<TStorage::PageId>> {
+            self.0.write(indices, write)
+        }
+
+        fn reserve(
+            &mut self,
+        ) -> Result<FailingPageReservation<'a, TStorage>, StorageError<TStorage::PageId>> {
+            Ok(FailingPageReservation(self.0.reserve()?))
+        }
+
+        fn insert_reserved(
+            &mut self,
+            reservation: FailingPageReservation<'a, TStorage>,
+            page: TStorage::Page,
+        ) -> Result<(), StorageError<TStorage::PageId>> {
+            self.0.insert_reserved(reservation.0, page)
+        }
+
+        fn insert(
+            &mut self,
+            page: TStorage::Page,
+        ) -> Result<TStorage::PageId, StorageError<TStorage::PageId>> {
+            self.0.insert(page)
+        }
+
+        fn delete(&mut self, page: TStorage::PageId) -> Result<(), StorageError<TStorage::PageId>> {
+            self.0.delete(page)
+        }
+
+        fn savepoint(&mut self) -> Savepoint {
+            self.0.savepoint()
+        }
+
+        fn rollback_to(
+            &mut self,
+            savepoint: Savepoint,
+        ) -> Result<(), StorageError<TStorage::PageId>> {
+            self.0.rollback_to(savepoint)
+        }
+
+        fn captures_changes(&self) -> bool {
+            self.0.captures_changes()
+        }
+
+        fn record_change(&mut self, change: Change) {
+            self.0.record_change(change);
+        }
+    }
+
+    /// Fails all the reads once `remaining_reads` runs out.
+    #[derive(Debug)]
+    struct FailingStorage<T: Storage> {
+        remaining_reads: Arc<AtomicUsize>,
+        inner: T,
+    }
+
+    impl<T: Storage> Storage for FailingStorage<T> {
+        type Page = T::Page;
+        type PageId = T::PageId;
+        type PageReservation<'a>
+            = FailingPageReservation<'a, T>
+        where
+            T: 'a;
+        type ReadTransaction<'a>
+            = FailingTransaction<'a, T, T::ReadTransaction<'a>>
+        where
+            T: 'a;
+        type Transaction<'a>
+            = FailingTransaction<'a, T>
+        where
+            T: 'a;
+
+        fn transaction_with_isolation(
+            &self,
+            isolation: IsolationLevel,
+        ) -> Result<Self::Transaction<'_>, StorageError<T::PageId>> {
+            Ok(FailingTransaction(
+                self.inner.transaction_with_isolation(isolation)?,
+                self.remaining_reads.clone(),
+                PhantomData,
+            ))
+        }
+
+        fn read_transaction(&self) -> Result<Self::ReadTransaction<'_>, StorageError<T::PageId>> {
+            Ok(FailingTransaction(
+                self.inner.read_transaction()?,
+                self.remaining_reads.clone(),
+                PhantomData,
+            ))
+        }
+    }
+
+    fn failing_tree() -> (Tree<FailingStorage<InMemoryStorage>, u64>, Arc<AtomicUsize>) {
+        let remaining_reads = Arc::new(AtomicUsize::new(usize::MAX));
+        let tree = Tree::new(FailingStorage {
+            remaining_reads: remaining_reads.clone(),
+            inner: InMemoryStorage::new(),
+        })
+        .unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..500u64 {
+            insert(&mut transaction, i, &i.to_le_bytes().repeat(8)).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        (tree, remaining_reads)
+    }
+
+    #[test]
+    fn errors_are_returned_and_fuse_the_iterator() {
+        let (tree, remaining_reads) = failing_tree();
+
+        for reversed in [false, true] {
+            for budget in [7, 8, 20, 100] {
+                remaining_reads.store(budget, Ordering::Relaxed);
+
+                let mut iterator = tree.iter().unwrap();
+                let mut keys = vec![];
+
+                let error = loop {
+                    let item = if reversed {
+                        iterator.next_back()
+                    } else {
+                        iterator.next()
+                    };
+
+                    match item.unwrap() {
+                        Ok((key, _)) => keys.push(key),
+                        Err(error) => break error,
+                    }
+                };
+
+                assert!(matches!(
+                    error,
+                    TreeError::StorageError(StorageError::PageNotFound(_))
+                ));
+
+                let expected = if reversed {
+                    (500 - keys.len() as u64..500).rev().collect::<Vec<_>>()
+                } else {
+                    (0..keys.len() as u64).collect::<Vec<_>>()
+                };
+                assert_eq!(keys, expected);
+                assert!(!keys.is_empty());
+
+                remaining_reads.store(usize::MAX, Ordering::Relaxed);
+
+                assert!(iterator.next().is_none());
+                assert!(iterator.next_back().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn error_when_creating_the_iterator() {
+        let (tree, remaining_reads) = failing_tree();
+
+        remaining_reads.store(1, Ordering::Relaxed);
+
+        let mut transaction = tree.transaction().unwrap();
+
+        assert!(matches!(
+            transaction.range(10..20),
+            Err(TreeError::StorageError(StorageError::PageNotFound(_)))
+        ));
+    }
 }
@@ -1,19 +1,22 @@
-use crate::bplustree::transaction::TreeTransaction;
+use crate::bplustree::transaction::{ReadOnlyTreeTransaction, TreeTransaction};
 // TODO this file is huge, split into smaller chunks
 use crate::storage::PageId;
-use crate::storage::{FIRST_PAGE_ID, Page as _};
+use crate::storage::{FIRST_PAGE_ID, Page as _, SENTINEL_PAGE_ID};
 pub mod algorithms;
+pub mod catalog;
 pub mod debug;
 pub mod dot;
 mod iterator;
 mod node;
+mod overflow;
 pub mod transaction;
 mod tuples;
 
+use std::cmp::Ordering;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
-use bytemuck::{Pod, Zeroable};
+use bytemuck::{Pod, Zeroable, bytes_of, pod_read_unaligned};
 use thiserror::Error;
 
 use crate::Size;
@@ -25,34 +28,142 @@ use crate::bplustree::tuples::NodeIds;
 use crate::storage::in_memory::version_manager::versioned_page::VERSIONED_PAGE_DATA_SIZE;
 use crate::storage::page::PAGE_DATA_SIZE;
 use crate::storage::{
-    PageIndex, PageReservation, SerializedPageId, Storage, StorageError, Transaction,
+    IsolationLevel, PageIndex, PageReservation, ReadTransaction, SerializedPageId, Storage,
+    StorageError, Transaction, TransactionalTimestamp, VersionedStorage,
 };
+use crate::sync::Arc;
+
+/// The key type of a tree, describing how the keys are stored inside the nodes.
+///
+/// Keys are passed into and read out of the tree as `Borrowed`, so that variable-length keys can be
+/// compared in place, without copying them out of the nodes.
+pub trait TreeKey: Debug + Pod {
+    type Borrowed<'a>: Debug + Copy + Ord;
+    type Owned: Debug + Clone + Ord;
+
+    /// The size of every encoded key, or `None` if the keys are of variable length.
+    const FIXED_SIZE: Option<usize>;
+    /// The maximum size of an encoded key, including any length prefix.
+    const MAX_ENCODED_SIZE: usize;
+
+    fn encoded_size(key: Self::Borrowed<'_>) -> usize;
+    fn encode(key: Self::Borrowed<'_>, target: &mut [u8]);
+    /// Decodes the key at the start of `source`, which may be followed by any other data.
+    fn decode(source: &[u8]) -> Self::Borrowed<'_>;
+    /// Compares two keys that were borrowed for different lifetimes.
+    fn compare(left: Self::Borrowed<'_>, right: Self::Borrowed<'_>) -> Ordering;
+    fn to_owned(key: Self::Borrowed<'_>) -> Self::Owned;
+    fn borrow(key: &Self::Owned) -> Self::Borrowed<'_>;
+}
+
+/// Keys that are stored as their in-memory representation, all of the same size.
+pub trait FixedSizeKey: Debug + Ord + Pod {}
+impl FixedSizeKey for u8 {}
+impl FixedSizeKey for u16 {}
+impl FixedSizeKey for u32 {}
+impl FixedSizeKey for u64 {}
+impl FixedSizeKey for usize {}
+impl FixedSizeKey for i8 {}
+impl FixedSizeKey for i16 {}
+impl FixedSizeKey for i32 {}
+impl FixedSizeKey for i64 {}
+impl FixedSizeKey for isize {}
+
+impl<T: FixedSizeKey> TreeKey for T {
+    type Borrowed<'a> = Self;
+    type Owned = Self;
+
+    const FIXED_SIZE: Option<usize> = Some(size_of::<Self>());
+    const MAX_ENCODED_SIZE: usize = size_of::<Self>();
+
+    fn encoded_size(_key: Self) -> usize {
+        size_of::<Self>()
+    }
+
+    fn encode(key: Self, target: &mut [u8]) {
+        target[..size_of::<Self>()].copy_from_slice(bytes_of(&key));
+    }
+
+    fn decode(source: &[u8]) -> Self {
+        pod_read_unaligned(&source[..size_of::<Self>()])
+    }
+
+    fn compare(left: Self, right: Self) -> Ordering {
+        left.cmp(&right)
+    }
+
+    fn to_owned(key: Self) -> Self {
+        key
+    }
+
+    fn borrow(key: &Self) -> Self {
+        *key
+    }
+}
+
+/// Variable-length byte-string keys, ordered lexicographically (as with `memcmp`).
+///
+/// The keys are passed in as `&[u8]` and returned from iterators as `Vec<u8>`. They are stored
+/// with a `u16` length prefix and can be at most `ByteKey::MAX_LENGTH` bytes long.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct ByteKey;
+
+impl ByteKey {
+    pub const MAX_LENGTH: usize = 512;
+}
+
+impl TreeKey for ByteKey {
+    type Borrowed<'a> = &'a [u8];
+    type Owned = Vec<u8>;
+
+    const FIXED_SIZE: Option<usize> = None;
+    const MAX_ENCODED_SIZE: usize = size_of::<u16>() + Self::MAX_LENGTH;
+
+    fn encoded_size(key: &[u8]) -> usize {
+        size_of::<u16>() + key.len()
+    }
+
+    fn encode(key: &[u8], target: &mut [u8]) {
+        let length = u16::try_from(key.len()).unwrap();
+
+        target[..size_of::<u16>()].copy_from_slice(&length.to_le_bytes());
+        target[size_of::<u16>()..Self::encoded_size(key)].copy_from_slice(key);
+    }
+
+    fn decode(source: &[u8]) -> &[u8] {
+        let length = usize::from(u16::from_le_bytes([source[0], source[1]]));
+
+        &source[size_of::<u16>()..size_of::<u16>() + length]
+    }
+
+    fn compare(left: &[u8], right: &[u8]) -> Ordering {
+        left.cmp(right)
+    }
+
+    fn to_owned(key: &[u8]) -> Vec<u8> {
+        key.to_vec()
+    }
 
-pub trait TreeKey: Debug + Ord + Pod {}
-impl TreeKey for u8 {}
-impl TreeKey for u16 {}
-impl TreeKey for u32 {}
-impl TreeKey for u64 {}
-impl TreeKey for usize {}
-impl TreeKey for i8 {}
-impl TreeKey for i16 {}
-impl TreeKey for i32 {}
-impl TreeKey for i64 {}
-impl TreeKey for isize {}
+    fn borrow(key: &Vec<u8>) -> &[u8] {
+        key
+    }
+}
 
 const ROOT_NODE_TAIL_SIZE: Size = VERSIONED_PAGE_DATA_SIZE
     .subtract(Size::of::<u64>())
     .subtract(Size::of::<PageIndex>());
 
+/// A tree whose header is stored in the `header` page of the storage. The storage can be shared
+/// with other trees, see `Catalog`.
 #[derive(Debug)]
 pub struct Tree<T: Storage, TKey: TreeKey> {
-    storage: T,
+    storage: Arc<T>,
+    header: SerializedPageId,
     _key: PhantomData<TKey>,
 }
 
 impl<T: Storage, TKey: TreeKey> Tree<T, TKey> {
-    // TODO also create a "new_read" method, or something like that (that reads a tree that already
-    // exists from storage)
     pub fn new(storage: T) -> Result<Self, TreeError<T::PageId>> {
         // TODO assert that the storage is empty, and that the header get's the 0th page, as we
         // depend on that invariant (i.e. PageIndex=0 must always refer to the TreeData and not to
@@ -60,12 +171,31 @@ impl<T: Storage, TKey: TreeKey> Tree<T, TKey> {
 
         TreeHeader::new_in::<_, TKey>(&storage)?;
 
-        Ok(Self {
+        Ok(Self::from_header(Arc::new(storage), FIRST_PAGE_ID))
+    }
+
+    /// Opens a tree that was previously created in the storage with `new`.
+    pub fn open(storage: T) -> Result<Self, TreeError<T::PageId>> {
+        TreeHeader::check_in::<_, TKey>(&storage)?;
+
+        Ok(Self::from_header(Arc::new(storage), FIRST_PAGE_ID))
+    }
+
+    /// Identifies the tree in the changes published by the storage, see `Change::tree`.
+    pub const fn id(&self) -> SerializedPageId {
+        self.header
+    }
+
+    const fn from_header(storage: Arc<T>, header: SerializedPageId) -> Self {
+        Self {
             storage,
+            header,
             _key: PhantomData,
-        })
+        }
     }
 
+    /// Iterates over all the entries in a transaction of its own. Use `TreeTransaction::iter` to
+    /// iterate in the same snapshot as other operations.
     #[allow(clippy::iter_not_returning_iterator)]
     pub fn iter(
         &self,
@@ -73,11 +203,69 @@ impl<T: Storage, TKey: TreeKey> Tree<T, TKey> {
         impl DoubleEndedIterator<Item = TreeIteratorItem<TKey, T::PageId>>,
         TreeError<T::PageId>,
     > {
-        TreeIterator::<_, _>::new(self.transaction()?)
+        TreeIterator::new(self.read_transaction()?, ..)
     }
 
     pub fn transaction(&self) -> Result<TreeTransaction<'_, T, TKey>, TreeError<T::PageId>> {
-        Ok(TreeTransaction::new(self.storage.transaction()?))
+        self.transaction_with_isolation(IsolationLevel::default())
+    }
+
+    pub fn transaction_with_isolation(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<TreeTransaction<'_, T, TKey>, TreeError<T::PageId>> {
+        Ok(TreeTransaction::new(
+            &*self.storage,
+            self.storage.transaction_with_isolation(isolation)?,
+            self.header,
+        ))
+    }
+
+    /// Starts a transaction that keeps track of the keys it touches, so that a conflict with
+    /// another transaction on a page, but not on any of those keys, makes it start over in a newer
+    /// snapshot instead of failing. This costs an extra lookup for every write, and a copy of every
+    /// key and value written, kept until the transaction finishes.
+    pub fn replayable_transaction(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<TreeTransaction<'_, T, TKey>, TreeError<T::PageId>> {
+        Ok(self
+            .transaction_with_isolation(isolation)?
+            .replayable(isolation))
+    }
+
+    /// Starts a transaction that can only read the tree, which makes finishing it cheaper than
+    /// finishing a `transaction`.
+    pub fn read_transaction(
+        &self,
+    ) -> Result<ReadOnlyTreeTransaction<'_, T, TKey>, TreeError<T::PageId>> {
+        Ok(TreeTransaction::new(
+            &*self.storage,
+            self.storage.read_transaction()?,
+            self.header,
+        ))
+    }
+}
+
+impl<T: VersionedStorage, TKey: TreeKey> Tree<T, TKey> {
+    /// Returns the timestamp of the newest commit to the storage, which can be used to read the
+    /// tree as of that commit later on.
+    #[must_use]
+    pub fn latest_commit(&self) -> Option<TransactionalTimestamp> {
+        self.storage.latest_commit()
+    }
+
+    /// Starts a read-only transaction that sees the tree as it was right after the commit at
+    /// `timestamp`.
+    pub fn transaction_as_of(
+        &self,
+        timestamp: TransactionalTimestamp,
+    ) -> Result<ReadOnlyTreeTransaction<'_, T, TKey>, TreeError<T::PageId>> {
+        Ok(TreeTransaction::new(
+            &*self.storage,
+            self.storage.transaction_as_of(timestamp)?,
+            self.header,
+        ))
     }
 }
 
@@ -96,32 +284,118 @@ pub enum TreeError<T: PageId> {
     #[error("Storage error: {0}")]
     // TODO rename -> Storage
     StorageError(#[from] StorageError<T>),
+    #[error("The storage does not contain a tree")]
+    HeaderMissing,
+    #[error("The tree header is corrupted")]
+    HeaderCorrupted,
+    #[error("The tree has keys of size {expected}, but keys of size {actual} were requested")]
+    KeySizeMismatch { expected: u64, actual: u64 },
+    #[error("The key takes {size} bytes, but at most {max} bytes are allowed")]
+    KeyTooLarge { size: usize, max: usize },
+    #[error("The keys are not sorted, or contain duplicates")]
+    UnsortedKeys,
+    #[error("The tree is not empty")]
+    NotEmpty,
+    #[error("A tree with this name already exists")]
+    TreeAlreadyExists,
+    #[error("There is no tree with this name")]
+    TreeNotFound,
+    #[error("The transaction could not be replayed after a conflict, and must be rolled back")]
+    ReplayFailed,
 }
 
 impl TreeHeader {
+    /// The key size stored in the header of trees with variable-length keys.
+    const VARIABLE_KEY_SIZE: u64 = u64::MAX;
     const _SIZE_MATCHES: () = assert!(
         Size::of::<Self>().is_equal(PAGE_DATA_SIZE),
         "The Tree descriptor must have size of exactly one page"
     );
 
+    fn key_size<TKey: TreeKey>() -> u64 {
+        TKey::FIXED_SIZE.map_or(Self::VARIABLE_KEY_SIZE, |x| x as u64)
+    }
+
     pub fn new_in<T: Storage, TKey: TreeKey>(storage: &T) -> Result<(), TreeError<T::PageId>> {
         let mut transaction = storage.transaction()?;
 
         let header_page = transaction.reserve()?;
         assert!(header_page.index().serialize() == FIRST_PAGE_ID);
 
-        // TODO replace usize with actual TKey!
+        let page = T::Page::from_data(Self::new_tree::<T, TKey>(&mut transaction)?);
+
+        transaction.insert_reserved(header_page, page)?;
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Creates a tree with the header in a newly inserted page, and returns the page.
+    fn create_in<'storage, T: Storage + 'storage, TKey: TreeKey>(
+        transaction: &mut T::Transaction<'storage>,
+    ) -> Result<SerializedPageId, TreeError<T::PageId>> {
+        let header = Self::new_tree::<T, TKey>(transaction)?;
+
+        Ok(transaction.insert(T::Page::from_data(header))?.serialize())
+    }
+
+    fn new_tree<'storage, T: Storage + 'storage, TKey: TreeKey>(
+        transaction: &mut T::Transaction<'storage>,
+    ) -> Result<Self, TreeError<T::PageId>> {
         let root_index = transaction.insert(T::Page::from_data(LeafNode::<TKey>::new(None)))?;
 
-        let page = T::Page::from_data(Self {
-            key_size: size_of::<TKey>() as u64,
+        Ok(Self {
+            key_size: Self::key_size::<TKey>(),
             root: root_index.serialize(),
             _unused: [0; _],
-        });
+        })
+    }
 
-        transaction.insert_reserved(header_page, page)?;
+    pub fn check_in<T: Storage, TKey: TreeKey>(storage: &T) -> Result<(), TreeError<T::PageId>> {
+        let mut transaction = storage.read_transaction()?;
 
-        transaction.commit()?;
+        Self::check::<T, TKey>(&mut transaction, FIRST_PAGE_ID)?;
+
+        transaction.rollback()?;
+
+        Ok(())
+    }
+
+    /// Checks that the `header` page contains the header of a tree with keys of type `TKey`.
+    fn check<'storage, T: Storage + 'storage, TKey: TreeKey>(
+        transaction: &mut impl ReadTransaction<'storage, Storage = T>,
+        header: SerializedPageId,
+    ) -> Result<(), TreeError<T::PageId>> {
+        let header = transaction
+            .read(T::PageId::deserialize(header), |[page]| {
+                *page.data::<Self>()
+            })
+            .map_err(|e| match e {
+                StorageError::PageNotFound(_) => TreeError::HeaderMissing,
+                StorageError::Corrupted(_) => TreeError::HeaderCorrupted,
+                e => TreeError::StorageError(e),
+            })?;
+
+        if header.key_size == 0 || header.root == FIRST_PAGE_ID || header.root == SENTINEL_PAGE_ID {
+            return Err(TreeError::HeaderCorrupted);
+        }
+
+        if header.key_size != Self::key_size::<TKey>() {
+            return Err(TreeError::KeySizeMismatch {
+                expected: header.key_size,
+                actual: Self::key_size::<TKey>(),
+            });
+        }
+
+        transaction
+            .read(T::PageId::deserialize(header.root), |_| ())
+            .map_err(|e| match e {
+                StorageError::PageNotFound(_) | StorageError::Corrupted(_) => {
+                    TreeError::HeaderCorrupted
+                }
+                e => TreeError::StorageError(e),
+            })?;
 
         Ok(())
     }
@@ -132,52 +406,60 @@ mod test {
     use std::collections::BTreeMap;
     use std::io::Write;
     use std::mem;
+    use std::ops::{Bound, RangeBounds};
     use std::panic::{RefUnwindSafe, UnwindSafe, catch_unwind};
 
     use pretty_assertions::assert_eq;
-    use tempfile::NamedTempFile;
+    use tempfile::{NamedTempFile, TempDir};
     #[cfg(not(miri))]
     use test_log::test;
     use tracing::info;
 
     use super::*;
+    use crate::bplustree::algorithms::bulk_load::{FillFactor, bulk_load};
     use crate::bplustree::algorithms::delete::delete;
     use crate::bplustree::algorithms::insert::insert;
+    use crate::bplustree::algorithms::{find, leaf_search};
     use crate::bplustree::debug::{TransactionAction, assert_properties, assert_tree_equal};
+    use crate::bplustree::overflow::{LeafValue, OverflowPage, OverflowPointer};
     use crate::debug::BigKey;
-    use crate::storage::in_memory::{InMemoryPageId, InMemoryStorage};
+    use crate::storage::file::FileStorage;
+    use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
+    use crate::storage::in_memory::{
+        ChangeCapture, ChangeEvent, ChangeSubscription, InMemoryPageId, InMemoryStorage,
+    };
     use crate::storage::instrumented::InstrumentedStorage;
     use crate::sync::atomic::{AtomicUsize, Ordering};
     use crate::sync::{Arc, Mutex};
 
     #[test]
     fn node_accessor_entries() {
-        let mut node = LeafNode::zeroed();
+        let mut node = LeafNode::<usize>::zeroed();
 
         assert!(matches!(node.entries().next(), None));
 
-        node.insert(1usize, &[2; 16]);
+        node.insert(1usize, LeafValue::Inline(&[2; 16]));
 
         let mut iter = node.entries();
         let first = iter.next().unwrap();
         assert!(first.key() == 1);
-        assert!(first.value() == &[2; 16]);
+        assert!(first.value() == LeafValue::Inline(&[2; 16]));
 
         assert!(matches!(iter.next(), None));
 
         drop(iter);
 
-        node.insert(2usize, &[1; 16]);
+        node.insert(2usize, LeafValue::Inline(&[1; 16]));
 
         let mut iter = node.entries();
 
         let first = iter.next().unwrap();
         assert!(first.key() == 1);
-        assert!(first.value() == &[2; 16]);
+        assert!(first.value() == LeafValue::Inline(&[2; 16]));
 
         let second = iter.next().unwrap();
         assert!(second.key() == 2);
-        assert!(second.value() == &[1; 16]);
+        assert!(second.value() == LeafValue::Inline(&[1; 16]));
 
         assert!(matches!(iter.next(), None));
     }
@@ -260,7 +542,7 @@ mod test {
     #[test]
     fn insert_reverse() {
         let storage = InMemoryStorage::new();
-        let tree = Tree::new(storage).unwrap();
+        let tree = Tree::<_, i32>::new(storage).unwrap();
         let mut transaction = tree.transaction().unwrap();
 
         insert(&mut transaction, 1, &[0]).unwrap();
@@ -276,18 +558,67 @@ mod test {
     #[test]
     fn same_key_overrides() {
         let storage = InMemoryStorage::new();
-        let tree = Tree::new(storage).unwrap();
+        let tree = Tree::<_, i32>::new(storage).unwrap();
         let mut transaction = tree.transaction().unwrap();
 
         insert(&mut transaction, 1, &0u8.to_ne_bytes()).unwrap();
         insert(&mut transaction, 1, &1u8.to_ne_bytes()).unwrap();
 
+        let result = transaction
+            .iter()
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(result, vec![(1, 1u8.to_ne_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn iteration_in_transaction() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+        let mut transaction = tree.transaction().unwrap();
+
+        for i in 0..100u64 {
+            insert(&mut transaction, i, &[1]).unwrap();
+        }
+
         transaction.commit().unwrap();
 
-        // TODO iter() should be on transaction, so it's created explicitly
-        let result = tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>();
+        let mut transaction = tree.transaction().unwrap();
 
-        assert_eq!(result, vec![(1, 1u8.to_ne_bytes().to_vec())]);
+        // read-modify-write, all in the same snapshot
+        let keys = transaction
+            .range(10..20)
+            .unwrap()
+            .map(|x| x.unwrap().0)
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            delete(&mut transaction, key).unwrap();
+            insert(&mut transaction, key + 1000, &[2]).unwrap();
+        }
+
+        let uncommitted = transaction
+            .iter()
+            .unwrap()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+
+        let expected = (0..10u64)
+            .chain(20..100)
+            .map(|i| (i, vec![1]))
+            .chain((1010..1020u64).map(|i| (i, vec![2])))
+            .collect::<Vec<_>>();
+
+        assert_eq!(uncommitted, expected);
+        assert_eq!(tree.iter().unwrap().count(), 100);
+
+        transaction.commit().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            expected
+        );
     }
 
     enum TestAction<TKey> {
@@ -297,7 +628,7 @@ mod test {
         Rollback,
     }
 
-    fn execute_test_actions<TKey: TreeKey, const SIZE: usize>(
+    fn execute_test_actions<TKey: FixedSizeKey, const SIZE: usize>(
         tree: &Tree<InMemoryStorage, BigKey<TKey, SIZE>>,
         actions: impl Iterator<Item = TestAction<BigKey<TKey, SIZE>>>,
         commit: impl Fn(Vec<TransactionAction<TKey>>),
@@ -342,7 +673,7 @@ mod test {
         transaction.commit().unwrap();
     }
 
-    fn test_from_data<TKey: TreeKey + UnwindSafe + RefUnwindSafe, const SIZE: usize>(
+    fn test_from_data<TKey: FixedSizeKey + UnwindSafe + RefUnwindSafe, const SIZE: usize>(
         data: Vec<TestAction<BigKey<TKey, SIZE>>>,
     ) {
         let storage = InMemoryStorage::new();
@@ -813,7 +1144,7 @@ mod test {
     #[test]
     fn simple_delete() {
         let storage = InMemoryStorage::new();
-        let tree = Tree::new(storage).unwrap();
+        let tree = Tree::<_, BigKey<i32, 256>>::new(storage).unwrap();
         let mut transaction = tree.transaction().unwrap();
 
         insert(&mut transaction, BigKey::<_, 256>::new(1), &[1, 2, 3]).unwrap();
@@ -837,7 +1168,7 @@ mod test {
         let page_count = Arc::new(AtomicUsize::new(0));
         let storage = InMemoryStorage::new();
         let storage = InstrumentedStorage::new(storage, page_count.clone());
-        let tree = Tree::new(storage).unwrap();
+        let tree = Tree::<_, BigKey<u64, 256>>::new(storage).unwrap();
         let mut transaction = tree.transaction().unwrap();
 
         let mut i: u64 = 0;
@@ -895,6 +1226,1049 @@ mod test {
         )
     }
 
+    #[test]
+    fn range() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+        let mut transaction = tree.transaction().unwrap();
+        let mut expected = BTreeMap::new();
+
+        // only even keys, so that the bounds fall both on and between the existing keys
+        for i in (0..2000u64).step_by(2) {
+            insert(&mut transaction, i, &i.to_le_bytes().repeat(8)).unwrap();
+            expected.insert(i, i.to_le_bytes().repeat(8));
+        }
+
+        let bounds: Vec<(Bound<u64>, Bound<u64>)> = vec![
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(100), Bound::Excluded(900)),
+            (Bound::Excluded(100), Bound::Included(900)),
+            (Bound::Included(101), Bound::Included(899)),
+            (Bound::Unbounded, Bound::Included(1)),
+            (Bound::Included(1998), Bound::Unbounded),
+            (Bound::Excluded(1998), Bound::Unbounded),
+            (Bound::Included(5000), Bound::Unbounded),
+            (Bound::Included(500), Bound::Excluded(500)),
+            (Bound::Included(500), Bound::Included(500)),
+            (Bound::Included(900), Bound::Included(100)),
+        ];
+
+        for bounds in bounds {
+            let forward = transaction
+                .range(bounds)
+                .unwrap()
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>();
+            let backward = transaction
+                .range(bounds)
+                .unwrap()
+                .rev()
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>();
+
+            let expected = expected
+                .iter()
+                .filter(|(key, _)| bounds.contains(*key))
+                .map(|(key, value)| (*key, value.clone()))
+                .collect::<Vec<_>>();
+
+            assert_eq!(forward, expected, "{bounds:?}");
+            assert_eq!(
+                backward,
+                expected.iter().rev().cloned().collect::<Vec<_>>(),
+                "{bounds:?}"
+            );
+
+            let mut iterator = transaction.range(bounds).unwrap();
+            let mut interleaved = vec![];
+            let mut interleaved_back = vec![];
+
+            loop {
+                match (iterator.next(), iterator.next_back()) {
+                    (None, None) => break,
+                    (front, back) => {
+                        interleaved.extend(front.map(|x| x.unwrap()));
+                        interleaved_back.extend(back.map(|x| x.unwrap()));
+                    }
+                }
+            }
+
+            interleaved.extend(interleaved_back.into_iter().rev());
+
+            assert_eq!(interleaved, expected, "{bounds:?}");
+        }
+    }
+
+    fn byte_key(i: u64) -> Vec<u8> {
+        // the lengths vary, so that the nodes hold different numbers of keys
+        let mut key = i.to_be_bytes().to_vec();
+        key.resize(
+            8 + (usize::try_from(i).unwrap() * 37) % (ByteKey::MAX_LENGTH - 8),
+            b'x',
+        );
+
+        key
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow in miri")]
+    fn byte_keys() {
+        let tree = Tree::<_, ByteKey>::new(InMemoryStorage::new()).unwrap();
+        let mut transaction = tree.transaction().unwrap();
+        let mut expected = BTreeMap::new();
+
+        for i in (0..1000u64).map(|x| (x * 7919) % 1000) {
+            let key = byte_key(i);
+
+            insert(&mut transaction, &key, &i.to_le_bytes()).unwrap();
+            expected.insert(key, i.to_le_bytes().to_vec());
+        }
+
+        assert_properties(&mut transaction);
+
+        for i in (0..1000u64).step_by(3) {
+            let key = byte_key(i);
+
+            assert_eq!(
+                delete(&mut transaction, &key).unwrap(),
+                Some(i.to_le_bytes().to_vec())
+            );
+            expected.remove(&key);
+        }
+
+        for i in 0..1000u64 {
+            let key = byte_key(i);
+
+            assert_eq!(
+                find(&mut transaction, &key).unwrap(),
+                expected.get(&key).cloned()
+            );
+        }
+
+        transaction.commit().unwrap();
+
+        assert_tree_equal(&tree, &expected, |x| x);
+        assert_properties(&mut tree.transaction().unwrap());
+    }
+
+    #[test]
+    fn byte_keys_are_ordered_by_memcmp() {
+        let tree = Tree::<_, ByteKey>::new(InMemoryStorage::new()).unwrap();
+        let mut transaction = tree.transaction().unwrap();
+
+        for key in [&b"b"[..], b"", b"ab", b"\xff", b"a\0", b"a"] {
+            insert(&mut transaction, key, key).unwrap();
+        }
+
+        assert_eq!(
+            transaction
+                .iter()
+                .unwrap()
+                .map(|x| x.unwrap().0)
+                .collect::<Vec<_>>(),
+            vec![
+                b"".to_vec(),
+                b"a".to_vec(),
+                b"a\0".to_vec(),
+                b"ab".to_vec(),
+                b"b".to_vec(),
+                b"\xff".to_vec()
+            ]
+        );
+        assert_eq!(
+            transaction
+                .range(&b"a"[..]..&b"b"[..])
+                .unwrap()
+                .map(|x| x.unwrap().0)
+                .collect::<Vec<_>>(),
+            vec![b"a".to_vec(), b"a\0".to_vec(), b"ab".to_vec()]
+        );
+        assert_eq!(
+            find(&mut transaction, b"a\0").unwrap(),
+            Some(b"a\0".to_vec())
+        );
+        assert_eq!(find(&mut transaction, b"a\0\0").unwrap(), None);
+    }
+
+    #[test]
+    fn too_large_byte_key() {
+        let tree = Tree::<_, ByteKey>::new(InMemoryStorage::new()).unwrap();
+        let mut transaction = tree.transaction().unwrap();
+
+        insert(&mut transaction, &[1; ByteKey::MAX_LENGTH], &[1]).unwrap();
+
+        assert_eq!(
+            insert(&mut transaction, &[2; ByteKey::MAX_LENGTH + 1], &[2]),
+            Err(TreeError::KeyTooLarge {
+                size: ByteKey::MAX_LENGTH + 3,
+                max: ByteKey::MAX_LENGTH + 2
+            })
+        );
+    }
+
+    fn large_value(i: u64) -> Vec<u8> {
+        (0..10_000 + i * 97)
+            .map(|x| u8::try_from(x % 251).unwrap() ^ u8::try_from(i % 251).unwrap())
+            .collect()
+    }
+
+    fn overflow_pointer<TStorage: Storage>(
+        transaction: &mut TreeTransaction<TStorage, u64>,
+        key: u64,
+    ) -> OverflowPointer {
+        let root = transaction.get_root().unwrap();
+        let leaf = leaf_search(transaction, root, key).unwrap();
+
+        transaction
+            .read_nodes(leaf, |leaf| {
+                match leaf.entry(leaf.find(key).unwrap()).unwrap().value() {
+                    LeafValue::Overflow(pointer) => pointer,
+                    LeafValue::Inline(_) => panic!("the value is stored inline"),
+                }
+            })
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow in miri")]
+    fn overflow_values() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+        let mut transaction = tree.transaction().unwrap();
+        let mut expected = BTreeMap::new();
+
+        for i in 0..100u64 {
+            let value = if i % 3 == 0 {
+                large_value(i)
+            } else {
+                i.to_le_bytes().to_vec()
+            };
+
+            insert(&mut transaction, i, &value).unwrap();
+            expected.insert(i, value);
+        }
+
+        for i in (0..100u64).step_by(2) {
+            let value = if i % 3 == 0 {
+                i.to_le_bytes().to_vec()
+            } else {
+                large_value(i)
+            };
+
+            insert(&mut transaction, i, &value).unwrap();
+            expected.insert(i, value);
+        }
+
+        for i in (0..100u64).step_by(5) {
+            assert_eq!(delete(&mut transaction, i).unwrap(), expected.remove(&i));
+        }
+
+        for (key, value) in &expected {
+            assert_eq!(find(&mut transaction, *key).unwrap().as_ref(), Some(value));
+        }
+
+        assert_properties(&mut transaction);
+        transaction.commit().unwrap();
+
+        assert_tree_equal(&tree, &expected, |x| x);
+    }
+
+    #[test]
+    fn overflow_chain_is_deleted_with_value() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &large_value(1)).unwrap();
+        insert(&mut transaction, 2, &large_value(2)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        let overwritten = overflow_pointer(&mut transaction, 1);
+        let deleted = overflow_pointer(&mut transaction, 2);
+
+        insert(&mut transaction, 1, &[1]).unwrap();
+        assert_eq!(delete(&mut transaction, 2).unwrap(), Some(large_value(2)));
+
+        for pointer in [overwritten, deleted] {
+            assert!(matches!(
+                transaction.read_page(pointer.first_page(), |_: &OverflowPage| ()),
+                Err(TreeError::StorageError(StorageError::PageNotFound(_)))
+            ));
+        }
+
+        transaction.commit().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            &[(1, vec![1])]
+        );
+    }
+
+    #[test]
+    fn overflow_value_rollback() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &large_value(1)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &large_value(3)).unwrap();
+        insert(&mut transaction, 2, &large_value(2)).unwrap();
+        transaction.rollback().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        assert_eq!(delete(&mut transaction, 1).unwrap(), Some(large_value(1)));
+        transaction.rollback().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            &[(1, large_value(1))]
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "too slow in miri")]
+    fn bulk_load_tree() {
+        for fill_factor in [FillFactor::FULL, FillFactor::new(50).unwrap()] {
+            let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+            let expected = (0..10_000u64)
+                .map(|i| {
+                    let value = if i % 1000 == 0 {
+                        large_value(i)
+                    } else {
+                        i.to_le_bytes().repeat(usize::try_from(i % 8).unwrap())
+                    };
+
+                    (i * 2, value)
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            let mut transaction = tree.transaction().unwrap();
+            bulk_load(&mut transaction, expected.clone(), fill_factor).unwrap();
+            assert_properties(&mut transaction);
+            transaction.commit().unwrap();
+
+            assert_tree_equal(&tree, &expected, |x| x);
+
+            let mut expected = expected;
+            let mut transaction = tree.transaction().unwrap();
+
+            for i in (0..20_000u64).step_by(3) {
+                insert(&mut transaction, i, &i.to_le_bytes()).unwrap();
+                expected.insert(i, i.to_le_bytes().to_vec());
+            }
+
+            for i in (0..20_000u64).step_by(7) {
+                assert_eq!(delete(&mut transaction, i).unwrap(), expected.remove(&i));
+            }
+
+            assert_properties(&mut transaction);
+            transaction.commit().unwrap();
+
+            assert_tree_equal(&tree, &expected, |x| x);
+        }
+    }
+
+    #[test]
+    fn bulk_load_byte_keys() {
+        let tree = Tree::<_, ByteKey>::new(InMemoryStorage::new()).unwrap();
+        let expected = (0..1000u64)
+            .map(|i| (byte_key(i), i.to_le_bytes().to_vec()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut transaction = tree.transaction().unwrap();
+        bulk_load(
+            &mut transaction,
+            expected.iter().map(|(k, v)| (&k[..], v)),
+            FillFactor::default(),
+        )
+        .unwrap();
+        assert_properties(&mut transaction);
+        transaction.commit().unwrap();
+
+        assert_tree_equal(&tree, &expected, |x| x);
+    }
+
+    #[test]
+    fn bulk_load_rejects_unsorted_keys() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        for keys in [vec![1, 2, 4, 3], vec![1, 2, 2, 3]] {
+            let mut transaction = tree.transaction().unwrap();
+
+            assert_eq!(
+                bulk_load(
+                    &mut transaction,
+                    keys.into_iter().map(|k| (k, [1])),
+                    FillFactor::FULL
+                ),
+                Err(TreeError::UnsortedKeys)
+            );
+
+            transaction.rollback().unwrap();
+        }
+
+        assert!(tree.iter().unwrap().next().is_none());
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &[1]).unwrap();
+
+        assert_eq!(
+            bulk_load(&mut transaction, [(2, [2])], FillFactor::FULL),
+            Err(TreeError::NotEmpty)
+        );
+    }
+
+    #[test]
+    fn read_as_of_past_commits() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, b"first").unwrap();
+        transaction.commit().unwrap();
+        let first_commit = tree.latest_commit().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, b"second").unwrap();
+        insert(&mut transaction, 2, &large_value(2)).unwrap();
+        transaction.commit().unwrap();
+        let second_commit = tree.latest_commit().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        delete(&mut transaction, 1).unwrap();
+        delete(&mut transaction, 2).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = tree.transaction_as_of(first_commit).unwrap();
+        assert_eq!(find(&mut transaction, 1).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(find(&mut transaction, 2).unwrap(), None);
+        transaction.commit().unwrap();
+
+        let mut transaction = tree.transaction_as_of(second_commit).unwrap();
+        assert_eq!(
+            transaction
+                .iter()
+                .unwrap()
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>(),
+            vec![(1, b"second".to_vec()), (2, large_value(2))]
+        );
+        transaction.rollback().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        assert_eq!(find(&mut transaction, 1).unwrap(), None);
+        transaction.rollback().unwrap();
+    }
+
+    #[test]
+    fn read_only_transaction_sees_snapshot() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, b"first").unwrap();
+        transaction.commit().unwrap();
+
+        let mut read_transaction = tree.read_transaction().unwrap();
+        assert_eq!(
+            find(&mut read_transaction, 1).unwrap(),
+            Some(b"first".to_vec())
+        );
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, b"second").unwrap();
+        insert(&mut transaction, 2, b"second").unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(
+            read_transaction
+                .iter()
+                .unwrap()
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>(),
+            vec![(1, b"first".to_vec())]
+        );
+        read_transaction.commit().unwrap();
+
+        let mut read_transaction = tree.read_transaction().unwrap();
+        assert_eq!(
+            find(&mut read_transaction, 1).unwrap(),
+            Some(b"second".to_vec())
+        );
+        read_transaction.rollback().unwrap();
+    }
+
+    #[test]
+    fn read_as_of_future_commit() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+        let latest_commit = tree.latest_commit().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, b"first").unwrap();
+
+        // the commit can't get the timestamp right after the latest one, as that one was used by
+        // the start of the transaction
+        assert!(matches!(
+            tree.transaction_as_of(latest_commit.next().next()),
+            Err(TreeError::StorageError(StorageError::SnapshotUnavailable(
+                _
+            )))
+        ));
+
+        transaction.commit().unwrap();
+
+        tree.transaction_as_of(latest_commit.next().next())
+            .unwrap()
+            .commit()
+            .unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes_to_the_same_key_conflict() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        insert(&mut second, 1, &[2]).unwrap();
+
+        first.commit().unwrap();
+
+        assert!(matches!(
+            second.commit(),
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+
+        let mut third = tree.transaction().unwrap();
+        let mut fourth = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut third, 3, &[3]).unwrap();
+        third.commit().unwrap();
+
+        assert!(matches!(
+            insert(&mut fourth, 3, &[4]),
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+        fourth.rollback().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            vec![(1, vec![1]), (3, vec![3])]
+        );
+    }
+
+    #[test]
+    fn concurrent_writes_to_different_keys_are_replayed() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        insert(&mut second, 2, &[2]).unwrap();
+
+        // conflicts on the commit
+        first.commit().unwrap();
+        second.commit().unwrap();
+
+        let mut third = tree.transaction().unwrap();
+        let mut fourth = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut third, 3, &[3]).unwrap();
+        delete(&mut third, 1).unwrap();
+        assert_eq!(find(&mut fourth, 4).unwrap(), None);
+        third.commit().unwrap();
+
+        // conflicts on the write
+        insert(&mut fourth, 4, &vec![4; 10_000]).unwrap();
+        fourth.commit().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            vec![(2, vec![2]), (3, vec![3]), (4, vec![4; 10_000])]
+        );
+    }
+
+    #[test]
+    fn transactions_are_not_replayed_by_default() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree.transaction().unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        insert(&mut second, 2, &[2]).unwrap();
+
+        first.commit().unwrap();
+
+        assert!(matches!(
+            second.commit(),
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+    }
+
+    #[test]
+    fn modified_read_key_prevents_replay_before_the_commit() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..1000 {
+            insert(&mut transaction, i, &[0]).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        assert_eq!(find(&mut second, 1).unwrap(), Some(vec![0]));
+        insert(&mut second, 999, &[2]).unwrap();
+        insert(&mut first, 1, &[1]).unwrap();
+        first.commit().unwrap();
+
+        // replaying would make the later reads see the newer value of the key
+        assert!(matches!(
+            insert(&mut second, 2, &[2]),
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+
+        // the earlier insert is gone, so the transaction can't be used anymore
+        assert!(matches!(
+            find(&mut second, 999),
+            Err(TreeError::ReplayFailed)
+        ));
+        assert!(matches!(second.commit(), Err(TreeError::ReplayFailed)));
+
+        let mut transaction = tree.read_transaction().unwrap();
+        assert_eq!(find(&mut transaction, 1).unwrap(), Some(vec![1]));
+        assert_eq!(find(&mut transaction, 999).unwrap(), Some(vec![0]));
+    }
+
+    #[test]
+    fn modified_read_key_prevents_replay() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &[1]).unwrap();
+        transaction.commit().unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Serializable)
+            .unwrap();
+
+        insert(&mut first, 1, &[2]).unwrap();
+        let value = find(&mut second, 1).unwrap().unwrap();
+        insert(&mut second, 2, &value).unwrap();
+
+        first.commit().unwrap();
+
+        assert!(matches!(
+            second.commit(),
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+    }
+
+    fn take_off_call<T: Storage>(
+        tree: &Tree<T, u64>,
+        isolation: IsolationLevel,
+    ) -> [Result<(), TreeError<T::PageId>>; 2] {
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &[1]).unwrap();
+        insert(&mut transaction, 2, &[1]).unwrap();
+        transaction.commit().unwrap();
+
+        // each of the transactions checks that someone else is still on call, before taking
+        // itself off
+        let transactions = [1, 2].map(|key| {
+            let mut transaction = tree.replayable_transaction(isolation).unwrap();
+            let other = 3 - key;
+
+            assert_eq!(find(&mut transaction, other).unwrap(), Some(vec![1]));
+            insert(&mut transaction, key, &[0]).unwrap();
+
+            transaction
+        });
+
+        transactions.map(TreeTransaction::commit)
+    }
+
+    #[test]
+    fn write_skew_with_snapshot_isolation() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let [first, second] = take_off_call(&tree, IsolationLevel::Snapshot);
+        first.unwrap();
+        second.unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            vec![(1, vec![0]), (2, vec![0])]
+        );
+    }
+
+    #[test]
+    fn no_write_skew_with_serializable_isolation() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let [first, second] = take_off_call(&tree, IsolationLevel::Serializable);
+        first.unwrap();
+        assert!(matches!(
+            second,
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            vec![(1, vec![0]), (2, vec![1])]
+        );
+    }
+
+    #[test]
+    fn iterating_prevents_replay() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        assert_eq!(second.iter().unwrap().count(), 0);
+        insert(&mut second, 2, &[2]).unwrap();
+
+        first.commit().unwrap();
+
+        assert!(matches!(
+            second.commit(),
+            Err(TreeError::StorageError(StorageError::Conflict { .. }))
+        ));
+    }
+
+    #[test]
+    fn rollback_to_savepoint_undoes_splits() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..10 {
+            insert(&mut transaction, i, &i.to_le_bytes()).unwrap();
+        }
+
+        let savepoint = transaction.savepoint();
+
+        for i in 10..2000 {
+            insert(&mut transaction, i, &[1; 100]).unwrap();
+        }
+        delete(&mut transaction, 0).unwrap();
+
+        transaction.rollback_to(savepoint).unwrap();
+
+        insert(&mut transaction, 10, &[10]).unwrap();
+        assert_properties(&mut transaction);
+
+        // the savepoint is still valid after rolling back to it
+        transaction.rollback_to(savepoint).unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            (0..10u64)
+                .map(|i| (i, i.to_le_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        );
+        assert_properties(&mut tree.transaction().unwrap());
+    }
+
+    #[test]
+    fn nested_savepoints() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &[1]).unwrap();
+        let first = transaction.savepoint();
+        insert(&mut transaction, 1, &[2]).unwrap();
+        insert(&mut transaction, 2, &[2]).unwrap();
+        let second = transaction.savepoint();
+        insert(&mut transaction, 1, &[3]).unwrap();
+        insert(&mut transaction, 3, &[3]).unwrap();
+
+        transaction.rollback_to(second).unwrap();
+        assert_eq!(find(&mut transaction, 1).unwrap(), Some(vec![2]));
+        assert_eq!(find(&mut transaction, 3).unwrap(), None);
+
+        transaction.rollback_to(first).unwrap();
+        assert_eq!(find(&mut transaction, 1).unwrap(), Some(vec![1]));
+        assert_eq!(find(&mut transaction, 2).unwrap(), None);
+
+        // the later savepoint was released by rolling back past it
+        assert!(matches!(
+            transaction.rollback_to(second),
+            Err(TreeError::StorageError(StorageError::InvalidSavepoint(_)))
+        ));
+        transaction.commit().unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            vec![(1, vec![1])]
+        );
+    }
+
+    #[test]
+    fn released_savepoints_stay_invalid_after_a_replay() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..1000 {
+            insert(&mut transaction, i, &[0]).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut second, 999, &[2]).unwrap();
+        let kept = second.savepoint();
+        insert(&mut second, 998, &[2]).unwrap();
+        let released = second.savepoint();
+        second.rollback_to(kept).unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        first.commit().unwrap();
+
+        // conflicts on the write, so the transaction is replayed in a new storage transaction
+        insert(&mut second, 2, &[2]).unwrap();
+        let latest = second.savepoint();
+
+        assert!(matches!(
+            second.rollback_to(released),
+            Err(TreeError::StorageError(StorageError::InvalidSavepoint(_)))
+        ));
+        second.rollback_to(latest).unwrap();
+        second.commit().unwrap();
+
+        let mut transaction = tree.read_transaction().unwrap();
+        assert_eq!(find(&mut transaction, 2).unwrap(), Some(vec![2]));
+        assert_eq!(find(&mut transaction, 998).unwrap(), Some(vec![0]));
+        assert_eq!(find(&mut transaction, 999).unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn savepoints_survive_replays() {
+        let tree = Tree::<_, u64>::new(InMemoryStorage::new()).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..1000 {
+            insert(&mut transaction, i, &[0]).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        insert(&mut second, 999, &[2]).unwrap();
+        let savepoint = second.savepoint();
+
+        first.commit().unwrap();
+
+        // conflicts on the write, so the transaction is replayed before the rollback
+        insert(&mut second, 2, &[2]).unwrap();
+        second.rollback_to(savepoint).unwrap();
+        second.commit().unwrap();
+
+        let mut transaction = tree.read_transaction().unwrap();
+        assert_eq!(find(&mut transaction, 1).unwrap(), Some(vec![1]));
+        assert_eq!(find(&mut transaction, 2).unwrap(), Some(vec![0]));
+        assert_eq!(find(&mut transaction, 999).unwrap(), Some(vec![2]));
+    }
+
+    type KeyChange = (u64, Option<Vec<u8>>, Option<Vec<u8>>);
+
+    fn received_changes(subscription: &mut ChangeSubscription, count: usize) -> Vec<ChangeEvent> {
+        subscription.by_ref().take(count).collect()
+    }
+
+    fn key_changes(events: &[ChangeEvent]) -> Vec<KeyChange> {
+        events
+            .iter()
+            .map(|x| {
+                let change = x.change();
+
+                (
+                    u64::decode(change.key()),
+                    change.old_value().map(<[u8]>::to_vec),
+                    change.new_value().map(<[u8]>::to_vec),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn committed_changes_are_published() {
+        let storage = InMemoryStorage::new();
+        storage.set_change_capture(ChangeCapture::default().with_buffer_size(1024));
+        let mut subscription = storage.subscribe(None).unwrap();
+
+        let tree = Tree::<_, u64>::new(storage).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &[1]).unwrap();
+        insert(&mut transaction, 2, &[1]).unwrap();
+        let savepoint = transaction.savepoint();
+        insert(&mut transaction, 3, &[1]).unwrap();
+        transaction.rollback_to(savepoint).unwrap();
+        transaction.commit().unwrap();
+        let first_commit = tree.latest_commit().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 4, &[1]).unwrap();
+        transaction.rollback().unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &vec![2; 10_000]).unwrap();
+        insert(&mut transaction, 1, &[3]).unwrap();
+        delete(&mut transaction, 2).unwrap();
+        delete(&mut transaction, 5).unwrap();
+        transaction.commit().unwrap();
+        let second_commit = tree.latest_commit().unwrap();
+
+        let events = received_changes(&mut subscription, 5);
+
+        assert_eq!(
+            key_changes(&events),
+            vec![
+                (1, None, Some(vec![1])),
+                (2, None, Some(vec![1])),
+                (1, Some(vec![1]), Some(vec![2; 10_000])),
+                (1, Some(vec![2; 10_000]), Some(vec![3])),
+                (2, Some(vec![1]), None),
+            ]
+        );
+        assert_eq!(
+            events
+                .iter()
+                .map(ChangeEvent::timestamp)
+                .collect::<Vec<_>>(),
+            vec![
+                first_commit,
+                first_commit,
+                second_commit,
+                second_commit,
+                second_commit
+            ]
+        );
+        assert!(events.iter().all(|x| x.change().tree() == tree.id()));
+        assert_ne!(events[0].transaction(), events[2].transaction());
+    }
+
+    #[test]
+    fn replayed_transactions_publish_their_changes_once() {
+        let storage = InMemoryStorage::new();
+        storage.set_change_capture(ChangeCapture::default().with_buffer_size(1024));
+        let mut subscription = storage.subscribe(None).unwrap();
+
+        let tree = Tree::<_, u64>::new(storage).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        insert(&mut transaction, 1, &[0]).unwrap();
+        insert(&mut transaction, 2, &[0]).unwrap();
+        transaction.commit().unwrap();
+        received_changes(&mut subscription, 2);
+
+        let mut first = tree.transaction().unwrap();
+        let mut second = tree
+            .replayable_transaction(IsolationLevel::Snapshot)
+            .unwrap();
+
+        insert(&mut first, 1, &[1]).unwrap();
+        insert(&mut second, 2, &[2]).unwrap();
+
+        first.commit().unwrap();
+        // both keys are in the same leaf, so this one gets replayed
+        second.commit().unwrap();
+
+        assert_eq!(
+            key_changes(&received_changes(&mut subscription, 2)),
+            vec![
+                (1, Some(vec![0]), Some(vec![1])),
+                (2, Some(vec![0]), Some(vec![2]))
+            ]
+        );
+    }
+
+    #[test]
+    fn open_existing_tree() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("data");
+
+        {
+            let tree = Tree::<_, u64>::new(FileStorage::open(&path).unwrap()).unwrap();
+            let mut transaction = tree.transaction().unwrap();
+
+            for i in 0..100u64 {
+                insert(&mut transaction, i, &i.to_le_bytes()).unwrap();
+            }
+
+            transaction.commit().unwrap();
+        }
+
+        let tree = Tree::<_, u64>::open(FileStorage::open(&path).unwrap()).unwrap();
+
+        assert_eq!(
+            tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>(),
+            (0..100u64)
+                .map(|i| (i, i.to_le_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        );
+
+        drop(tree);
+
+        assert_eq!(
+            Tree::<_, u32>::open(FileStorage::open(&path).unwrap()).unwrap_err(),
+            TreeError::KeySizeMismatch {
+                expected: 8,
+                actual: 4
+            }
+        );
+    }
+
+    #[test]
+    fn open_without_header() {
+        assert_eq!(
+            Tree::<_, u64>::open(InMemoryStorage::new()).unwrap_err(),
+            TreeError::HeaderMissing
+        );
+    }
+
+    #[test]
+    fn open_with_corrupted_header() {
+        let storage = InMemoryStorage::new();
+        let mut transaction = storage.transaction().unwrap();
+        let index = transaction
+            .insert(VersionedPage::from_data(TreeHeader::zeroed()))
+            .unwrap();
+        transaction.commit().unwrap();
+
+        assert!(index.serialize() == FIRST_PAGE_ID);
+
+        assert_eq!(
+            Tree::<_, u64>::open(storage).unwrap_err(),
+            TreeError::HeaderCorrupted
+        );
+    }
+
     #[test]
     fn fuzzer_c() {
         let data = vec![
@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use bytemuck::{Pod, Zeroable, bytes_of, cast_slice, cast_slice_mut};
+use bytemuck::{Pod, Zeroable, bytes_of, pod_read_unaligned};
 
 use crate::Size;
 use crate::bplustree::TreeKey;
@@ -11,95 +11,25 @@ use crate::storage::{SENTINEL_PAGE_ID, SerializedPageId};
 const INTERIOR_NODE_DATA_SIZE: Size =
     VERSIONED_PAGE_DATA_SIZE.subtract(Size::of::<u64>().multiply(3));
 
+/// The keys are encoded one after another from the start of `data`, while the values are stored
+/// from the end of `data` backwards, so that both can grow towards the middle.
+///
+/// If the keys are not of a fixed size, every value but the first one is followed by the offset of
+/// the key before it, so that any key can be found without decoding the ones before it.
 #[derive(Debug, Zeroable, Clone, Copy)]
 #[repr(C, align(8))]
 pub struct InteriorNodeEntries<TKey> {
     key_count: u16,
-    _unused1: u16,
-    _unused2: u32,
+    keys_size: u16,
+    _unused: u32,
 
-    data: InteriorNodeData<TKey>,
-}
-
-impl<TKey: TreeKey> InteriorNodeEntries<TKey> {
-    pub(crate) fn debug(&self) -> String {
-        self.data.debug(self.key_count())
-    }
-}
-
-// SAFETY: this is sound, because the struct has no padding and would be able to derive Pod
-// automatically if not for the PhantomData
-unsafe impl<TKey: TreeKey> Pod for InteriorNodeEntries<TKey> {}
-
-#[derive(Debug, Zeroable, Clone, Copy)]
-#[repr(C, align(8))]
-struct InteriorNodeData<TKey> {
     data: [u8; INTERIOR_NODE_DATA_SIZE.as_bytes()],
     _key: PhantomData<TKey>,
-    _page_id: PhantomData<SerializedPageId>,
 }
 
 // SAFETY: this is sound, because the struct has no padding and would be able to derive Pod
 // automatically if not for the PhantomData
-unsafe impl<TKey: TreeKey> Pod for InteriorNodeData<TKey> {}
-
-impl<TKey: TreeKey> InteriorNodeData<TKey> {
-    // n - max number of keys
-    //
-    // size = key_size*n + value_size*(n+1)
-    // size = key_size*n + value_size*n + value_size
-    // size - value_size = key_size*n + value_size*n
-    // (size - value_size)/(key_size + value_size) = n
-    const KEY_CAPACITY: usize = (INTERIOR_NODE_DATA_SIZE.subtract(Size::of::<SerializedPageId>()))
-        .divide(Size::of::<TKey>().add(Size::of::<SerializedPageId>()));
-    const VALUES_OFFSET: Size = Size::of::<TKey>().multiply(Self::KEY_CAPACITY);
-
-    fn from_raw_data(keys: &[TKey], values: &[SerializedPageId]) -> Self {
-        assert!(Size::of_val(keys) < Self::VALUES_OFFSET);
-
-        let mut data = [0; _];
-
-        data[..size_of_val(keys)].copy_from_slice(cast_slice(keys));
-        data[Self::VALUES_OFFSET.as_bytes()
-            ..Self::VALUES_OFFSET.add(Size::of_val(values)).as_bytes()]
-            .copy_from_slice(cast_slice(values));
-
-        Self {
-            data,
-            _key: PhantomData,
-            _page_id: PhantomData,
-        }
-    }
-
-    fn keys(&self) -> &[TKey] {
-        cast_slice(&self.data[..Self::VALUES_OFFSET.as_bytes()])
-    }
-
-    fn values(&self) -> &[SerializedPageId] {
-        cast_slice(&self.data[Self::VALUES_OFFSET.as_bytes()..])
-    }
-
-    fn keys_mut(&mut self) -> &mut [TKey] {
-        cast_slice_mut(&mut self.data[..Self::VALUES_OFFSET.as_bytes()])
-    }
-
-    fn values_mut(&mut self) -> &mut [SerializedPageId] {
-        cast_slice_mut(&mut self.data[Self::VALUES_OFFSET.as_bytes()..])
-    }
-
-    fn debug(&self, key_count: usize) -> String {
-        let mut debug = "keys: ".to_string();
-        debug += &(0..key_count)
-            .map(|x| format!("{:?}", self.keys()[x]))
-            .fold(String::new(), |acc, x| acc + " " + &x);
-        debug += "\nvalues: ";
-        debug += &(0..=key_count)
-            .map(|x| format!("{:?}", self.values()[x]))
-            .fold(String::new(), |acc, x| acc + " " + &x);
-
-        debug
-    }
-}
+unsafe impl<TKey: TreeKey> Pod for InteriorNodeEntries<TKey> {}
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub(in crate::bplustree) struct KeyIndex(usize);
@@ -120,14 +50,6 @@ impl KeyIndex {
     pub(crate) fn key_before(self) -> Option<Self> {
         self.0.checked_sub(1).map(Self)
     }
-
-    const fn key_after(self) -> Self {
-        Self(self.0.strict_add(1))
-    }
-
-    const fn offset(self, offset: isize) -> Self {
-        Self(self.0.strict_add_signed(offset))
-    }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -150,112 +72,252 @@ impl ValueIndex {
         self.0.checked_sub(1).map(Self)
     }
 
-    const fn key_after(self) -> KeyIndex {
-        KeyIndex(self.0)
-    }
-
     const fn offset(self, offset: isize) -> Self {
         Self(self.0.strict_add_signed(offset))
     }
 }
 
 impl<TKey: TreeKey> InteriorNodeEntries<TKey> {
-    pub fn new(left: SerializedPageId, key: TKey, right: SerializedPageId) -> Self {
-        Self {
-            key_count: 1,
-            _unused1: 0,
-            _unused2: 0,
-            data: InteriorNodeData::from_raw_data(&[key], &[left, right]),
+    // n - max number of keys of the maximum size
+    //
+    // size = key_size*n + value_size*(n+1)
+    // (size - value_size)/(key_size + value_size) = n
+    //
+    // the capacity is rounded down to a multiple of the pair size, so that for fixed-size keys the
+    // node can hold exactly n keys
+    const CAPACITY: usize = (INTERIOR_NODE_DATA_SIZE.as_bytes() - Self::ELEMENT_SIZE)
+        / Self::MAX_PAIR_SIZE
+        * Self::MAX_PAIR_SIZE;
+    /// The size of a value together with the offset of the key before it.
+    const ELEMENT_SIZE: usize = Self::VALUE_SIZE + Self::KEY_OFFSET_SIZE;
+    const KEY_OFFSET_SIZE: usize = if TKey::FIXED_SIZE.is_some() {
+        0
+    } else {
+        size_of::<u16>()
+    };
+    const MAX_PAIR_SIZE: usize = TKey::MAX_ENCODED_SIZE + Self::ELEMENT_SIZE;
+    const VALUE_SIZE: usize = size_of::<SerializedPageId>();
+
+    pub fn new(left: SerializedPageId, key: TKey::Borrowed<'_>, right: SerializedPageId) -> Self {
+        let mut key_data = vec![0; TKey::encoded_size(key)];
+        TKey::encode(key, &mut key_data);
+
+        Self::from_raw_data(1, &key_data, &[left, right])
+    }
+
+    fn from_raw_data(key_count: usize, keys: &[u8], values: &[SerializedPageId]) -> Self {
+        assert!(values.len() == key_count + 1);
+
+        let mut entries = Self {
+            key_count: u16::try_from(key_count).unwrap(),
+            keys_size: u16::try_from(keys.len()).unwrap(),
+            _unused: 0,
+            data: [0; _],
+            _key: PhantomData,
+        };
+
+        entries.data[..keys.len()].copy_from_slice(keys);
+
+        for (index, value) in values.iter().enumerate() {
+            entries.set_value(ValueIndex(index), *value);
         }
+
+        entries.update_key_offsets();
+
+        entries
+    }
+
+    pub(crate) fn debug(&self) -> String {
+        let mut debug = "keys: ".to_string();
+        debug += &(0..self.key_count())
+            .map(|x| format!("{:?}", self.key_at(KeyIndex(x))))
+            .fold(String::new(), |acc, x| acc + " " + &x);
+        debug += "\nvalues: ";
+        debug += &(0..=self.key_count())
+            .map(|x| format!("{:?}", self.value_at(ValueIndex(x)).unwrap()))
+            .fold(String::new(), |acc, x| acc + " " + &x);
+
+        debug
     }
 
     pub fn key_count(&self) -> usize {
         usize::from(self.key_count)
     }
 
+    fn keys_size(&self) -> usize {
+        usize::from(self.keys_size)
+    }
+
+    /// The space taken by the keys and all the values but the first one.
+    fn used_size(&self) -> usize {
+        self.keys_size() + self.key_count() * Self::ELEMENT_SIZE
+    }
+
     pub fn has_spare_capacity(&self) -> bool {
-        self.key_count() + 1 < InteriorNodeData::<TKey>::KEY_CAPACITY
+        self.used_size() + 2 * Self::MAX_PAIR_SIZE <= Self::CAPACITY
+    }
+
+    fn key_offset(&self, index: KeyIndex) -> usize {
+        assert!(index.0 <= self.key_count());
+
+        if let Some(key_size) = TKey::FIXED_SIZE {
+            return index.0 * key_size;
+        }
+
+        if index.0 == self.key_count() {
+            return self.keys_size();
+        }
+
+        let offset = Self::value_offset(index.value_after()) + Self::VALUE_SIZE;
+
+        usize::from(pod_read_unaligned::<u16>(
+            &self.data[offset..offset + Self::KEY_OFFSET_SIZE],
+        ))
+    }
+
+    /// Stores the offsets of all the keys, this has to be done after any of the keys is moved.
+    fn update_key_offsets(&mut self) {
+        if TKey::FIXED_SIZE.is_some() {
+            return;
+        }
+
+        let mut key_offset = 0;
+
+        for index in 0..self.key_count() {
+            let offset = Self::value_offset(KeyIndex(index).value_after()) + Self::VALUE_SIZE;
+
+            self.data[offset..offset + Self::KEY_OFFSET_SIZE]
+                .copy_from_slice(bytes_of(&u16::try_from(key_offset).unwrap()));
+
+            key_offset += TKey::encoded_size(TKey::decode(&self.data[key_offset..]));
+        }
+    }
+
+    const fn value_offset(index: ValueIndex) -> usize {
+        INTERIOR_NODE_DATA_SIZE.as_bytes() - (index.0 + 1) * Self::ELEMENT_SIZE
     }
 
-    pub fn split(&mut self) -> (TKey, Self) {
+    fn set_value(&mut self, index: ValueIndex, value: SerializedPageId) {
+        let offset = Self::value_offset(index);
+
+        self.data[offset..offset + Self::VALUE_SIZE].copy_from_slice(bytes_of(&value));
+    }
+
+    pub fn split(&mut self) -> (TKey::Owned, Self) {
         let key_count = self.key_count();
-        let keys_to_leave = key_count.div_ceil(2);
+        let keys_to_leave = self.keys_to_leave_on_split();
         let keys_to_move = key_count - keys_to_leave - 1;
 
         let values_to_leave = keys_to_leave + 1;
-        let values_to_move = (key_count + 1) - values_to_leave;
 
-        let split_key = self.data.keys()[keys_to_leave];
+        let split_key_offset = self.key_offset(KeyIndex(keys_to_leave));
+        let split_key = TKey::decode(&self.data[split_key_offset..]);
+        let moved_keys_offset = split_key_offset + TKey::encoded_size(split_key);
+
+        let values_to_move = (values_to_leave..=key_count)
+            .map(|x| self.value_at(ValueIndex(x)).unwrap())
+            .collect::<Vec<_>>();
 
-        let key_data_to_move =
-            self.data.keys()[(keys_to_leave + 1)..(keys_to_leave + 1) + keys_to_move].to_vec();
-        let value_data_to_move =
-            self.data.values()[values_to_leave..values_to_leave + values_to_move].to_vec();
+        let new_node = Self::from_raw_data(
+            keys_to_move,
+            &self.data[moved_keys_offset..self.keys_size()],
+            &values_to_move,
+        );
+        let split_key = TKey::to_owned(split_key);
 
         self.key_count = u16::try_from(keys_to_leave).unwrap();
+        self.keys_size = u16::try_from(split_key_offset).unwrap();
 
-        let new_node_data = InteriorNodeData::from_raw_data(&key_data_to_move, &value_data_to_move);
+        (split_key, new_node)
+    }
+
+    /// Splits the keys in halves by size, which for fixed-size keys is the same as splitting them
+    /// by count.
+    fn keys_to_leave_on_split(&self) -> usize {
+        let mut offset = 0;
+        let mut size = 0;
+
+        for index in 0..self.key_count() {
+            let key_size = TKey::encoded_size(TKey::decode(&self.data[offset..]));
+
+            offset += key_size;
+            size += key_size + Self::ELEMENT_SIZE;
 
-        (
-            split_key,
-            Self {
-                key_count: u16::try_from(keys_to_move).unwrap(),
-                _unused1: 0,
-                _unused2: 0,
-                data: new_node_data,
-            },
-        )
+            if 2 * size >= self.used_size() {
+                return (index + 1).min(self.key_count() - 1);
+            }
+        }
+
+        unreachable!("the node must have at least two keys to be split");
     }
 
-    pub(crate) fn merge_from(&mut self, entries: &Self, merge_key: TKey) {
-        let merge_key_offset = self.key_after_last();
+    pub(crate) fn merge_from(&mut self, entries: &Self, merge_key: TKey::Borrowed<'_>) {
+        let merge_key_offset = self.keys_size();
+        let merge_key_size = TKey::encoded_size(merge_key);
 
-        self.data.keys_mut()[merge_key_offset.0] = merge_key;
+        TKey::encode(
+            merge_key,
+            &mut self.data[merge_key_offset..merge_key_offset + merge_key_size],
+        );
 
-        let new_keys_offset = merge_key_offset.key_after();
+        let new_keys_offset = merge_key_offset + merge_key_size;
 
-        self.data.keys_mut()[new_keys_offset.0..new_keys_offset.0 + entries.key_count()]
-            .copy_from_slice(&entries.data.keys()[..entries.key_count()]);
+        self.data[new_keys_offset..new_keys_offset + entries.keys_size()]
+            .copy_from_slice(&entries.data[..entries.keys_size()]);
 
         let new_values_offset = self.value_after_last();
-        let values_size = entries.key_count() + 1;
 
-        self.data.values_mut()[new_values_offset.0..new_values_offset.0 + values_size]
-            .copy_from_slice(&entries.data.values()[..values_size]);
+        for index in 0..=entries.key_count() {
+            self.set_value(
+                new_values_offset.offset(isize::try_from(index).unwrap()),
+                entries.value_at(ValueIndex(index)).unwrap(),
+            );
+        }
 
         self.key_count += entries.key_count + 1;
+        self.keys_size += u16::try_from(merge_key_size).unwrap() + entries.keys_size;
+
+        self.update_key_offsets();
     }
 
-    pub fn insert_at(&mut self, index: KeyIndex, key: TKey, value: SerializedPageId) {
-        assert!(self.key_count() < InteriorNodeData::<TKey>::KEY_CAPACITY);
+    pub fn insert_at(&mut self, index: KeyIndex, key: TKey::Borrowed<'_>, value: SerializedPageId) {
+        let key_size = TKey::encoded_size(key);
 
-        debug_assert!(bytes_of(&key) != vec![0; size_of::<TKey>()]);
+        assert!(self.used_size() + key_size + Self::ELEMENT_SIZE <= Self::CAPACITY);
 
-        self.move_keys(index, 1);
-        self.move_values(index.value_after(), 1);
+        let key_offset = self.key_offset(index);
 
-        self.data.keys_mut()[index.0] = key;
-        self.data.values_mut()[index.value_after().0] = value;
+        let keys_end = self.keys_size();
 
-        self.key_count += 1;
-    }
+        self.data
+            .copy_within(key_offset..keys_end, key_offset + key_size);
+        TKey::encode(key, &mut self.data[key_offset..key_offset + key_size]);
 
-    fn move_keys(&mut self, start_index: KeyIndex, offset: isize) {
-        let keys_to_move = self.data.keys()[start_index.0..self.key_after_last().0].to_vec();
+        debug_assert!(self.data[key_offset..key_offset + key_size] != vec![0; key_size]);
 
-        let target_end_index = self.key_after_last().offset(offset).0;
+        self.move_values(index.value_after(), 1);
+        self.set_value(index.value_after(), value);
+
+        self.key_count += 1;
+        self.keys_size += u16::try_from(key_size).unwrap();
 
-        self.data.keys_mut()[start_index.0.strict_add_signed(offset)..target_end_index]
-            .copy_from_slice(&keys_to_move);
+        self.update_key_offsets();
     }
 
     fn move_values(&mut self, start_index: ValueIndex, offset: isize) {
         let end_index = self.value_after_last();
 
-        let values_to_move = self.data.values()[start_index.0..end_index.0].to_vec();
+        if start_index >= end_index {
+            return;
+        }
+
+        // the values are stored backwards, so the last one has the lowest offset
+        let source_start = Self::value_offset(end_index.offset(-1));
+        let source_end = Self::value_offset(start_index) + Self::ELEMENT_SIZE;
+        let target_start = Self::value_offset(end_index.offset(-1).offset(offset));
 
-        self.data.values_mut()[start_index.offset(offset).0..end_index.0.strict_add_signed(offset)]
-            .copy_from_slice(&values_to_move);
+        self.data
+            .copy_within(source_start..source_end, target_start);
     }
 
     pub fn value_at(&self, index: ValueIndex) -> Option<SerializedPageId> {
@@ -263,7 +325,8 @@ impl<TKey: TreeKey> InteriorNodeEntries<TKey> {
             return None;
         }
 
-        let value = self.data.values()[index.0];
+        let offset = Self::value_offset(index);
+        let value = pod_read_unaligned(&self.data[offset..offset + Self::VALUE_SIZE]);
 
         assert!(value != SENTINEL_PAGE_ID);
 
@@ -273,28 +336,34 @@ impl<TKey: TreeKey> InteriorNodeEntries<TKey> {
     pub fn delete_at(&mut self, index: ValueIndex) {
         assert!(index.0 <= self.key_count());
 
-        self.move_keys(index.key_after(), -1);
+        let deleted_key = index.key_before().unwrap();
+        let key_offset = self.key_offset(deleted_key);
+        let key_size = TKey::encoded_size(TKey::decode(&self.data[key_offset..]));
+
+        let keys_end = self.keys_size();
+
+        self.data
+            .copy_within(key_offset + key_size..keys_end, key_offset);
         self.move_values(index.value_after(), -1);
 
         self.key_count -= 1;
+        self.keys_size -= u16::try_from(key_size).unwrap();
+
+        self.update_key_offsets();
     }
 
     pub fn needs_merge(&self) -> bool {
-        2 * self.key_count() <= InteriorNodeData::<TKey>::KEY_CAPACITY
+        2 * self.used_size() <= Self::CAPACITY
     }
 
     pub fn can_fit_merge(&self, right: &Self) -> bool {
-        self.key_count() + right.key_count() < InteriorNodeData::<TKey>::KEY_CAPACITY
+        self.used_size() + right.used_size() + Self::MAX_PAIR_SIZE <= Self::CAPACITY
     }
 
-    pub fn key_at(&self, index: KeyIndex) -> Option<TKey> {
+    pub fn key_at(&self, index: KeyIndex) -> TKey::Borrowed<'_> {
         assert!(index.0 < self.key_count());
 
-        self.data.keys().get(index.0).copied()
-    }
-
-    pub(crate) fn key_after_last(&self) -> KeyIndex {
-        KeyIndex(self.key_count())
+        TKey::decode(&self.data[self.key_offset(index)..])
     }
 
     pub(crate) fn last_value(&self) -> ValueIndex {
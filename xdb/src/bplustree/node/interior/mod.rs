@@ -25,7 +25,7 @@ impl<TKey: TreeKey> InteriorNode<TKey> {
     pub fn new(
         parent: Option<InteriorNodeId>,
         left: AnyNodeId,
-        key: TKey,
+        key: TKey::Borrowed<'_>,
         right: AnyNodeId,
     ) -> Self {
         Self {
@@ -48,7 +48,9 @@ impl<TKey: TreeKey> InteriorNode<TKey> {
         self.header.parent = parent.map_or(SENTINEL_PAGE_ID, |x| x.page());
     }
 
-    pub(in crate::bplustree) fn keys(&self) -> impl Iterator<Item = (KeyIndex, TKey)> {
+    pub(in crate::bplustree) fn keys(
+        &self,
+    ) -> impl Iterator<Item = (KeyIndex, TKey::Borrowed<'_>)> {
         InteriorNodeKeysIterator {
             node: self,
             index: 0,
@@ -59,25 +61,45 @@ impl<TKey: TreeKey> InteriorNode<TKey> {
         self.entries.has_spare_capacity()
     }
 
-    pub(crate) fn insert_node(&mut self, key: TKey, value: AnyNodeId) {
-        let mut insert_at = self.entries.key_after_last();
+    /// Returns the index of the first key greater than `key`, the child that could contain `key` is
+    /// right before it.
+    pub(in crate::bplustree) fn upper_bound(&self, key: TKey::Borrowed<'_>) -> KeyIndex {
+        let mut low = 0;
+        let mut high = self.entries.key_count();
 
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            if TKey::compare(self.key_at(KeyIndex::new(middle)), key).is_le() {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        KeyIndex::new(low)
+    }
+
+    pub(crate) fn insert_node(&mut self, key: TKey::Borrowed<'_>, value: AnyNodeId) {
         if !self.has_spare_capacity() {
             error!("no capacity for insert, split node first!");
-            panic!("no capacity for insert, split the node first",);
+            panic!("no capacity for insert, split the node first");
         }
 
-        for (index, current_key) in self.keys() {
-            if key < current_key {
-                insert_at = index;
-                break;
-            }
-        }
+        self.entries
+            .insert_at(self.upper_bound(key), key, value.page());
+    }
+
+    /// Appends a key and a child to the end of the node. The key must be greater than all the keys
+    /// already in the node.
+    pub(crate) fn push(&mut self, key: TKey::Borrowed<'_>, value: AnyNodeId) {
+        debug_assert!(self.upper_bound(key) == KeyIndex::new(self.entries.key_count()));
 
-        self.entries.insert_at(insert_at, key, value.page());
+        self.entries
+            .insert_at(KeyIndex::new(self.entries.key_count()), key, value.page());
     }
 
-    pub fn split(&mut self) -> (TKey, Self) {
+    pub fn split(&mut self) -> (TKey::Owned, Self) {
         let (split_key, new_node_entries) = self.entries.split();
 
         (
@@ -136,7 +158,7 @@ impl<TKey: TreeKey> InteriorNode<TKey> {
         self.entries.can_fit_merge(&right.entries)
     }
 
-    pub(crate) fn merge_from(&mut self, right: &Self, at_key: TKey) {
+    pub(crate) fn merge_from(&mut self, right: &Self, at_key: TKey::Borrowed<'_>) {
         assert!(self.can_fit_merge(right));
 
         self.entries.merge_from(&right.entries, at_key);
@@ -146,7 +168,7 @@ impl<TKey: TreeKey> InteriorNode<TKey> {
         self.entries.delete_at(index);
     }
 
-    pub(crate) fn key_at(&self, index: KeyIndex) -> Option<TKey> {
+    pub(crate) fn key_at(&self, index: KeyIndex) -> TKey::Borrowed<'_> {
         self.entries.key_at(index)
     }
 
@@ -175,8 +197,8 @@ struct InteriorNodeKeysIterator<'node, TKey: TreeKey> {
     index: usize,
 }
 
-impl<TKey: TreeKey> Iterator for InteriorNodeKeysIterator<'_, TKey> {
-    type Item = (KeyIndex, TKey);
+impl<'node, TKey: TreeKey> Iterator for InteriorNodeKeysIterator<'node, TKey> {
+    type Item = (KeyIndex, TKey::Borrowed<'node>);
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.index >= self.node.entries.key_count() {
@@ -187,24 +209,25 @@ impl<TKey: TreeKey> Iterator for InteriorNodeKeysIterator<'_, TKey> {
 
         self.index += 1;
 
-        self.node.entries.key_at(index).map(|x| (index, x))
+        Some((index, self.node.entries.key_at(index)))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::bplustree::{AnyNodeId, InteriorNode};
+    use super::KeyIndex;
+    use crate::bplustree::{AnyNodeId, ByteKey, InteriorNode};
     use crate::storage::SerializedPageId;
 
     #[test]
     fn merge_with() {
-        let mut node_a = InteriorNode::new(
+        let mut node_a = InteriorNode::<usize>::new(
             None,
             AnyNodeId::new(SerializedPageId::new(1u64.to_le_bytes())),
             1usize,
             AnyNodeId::new(SerializedPageId::new(2u64.to_le_bytes())),
         );
-        let node_b = InteriorNode::new(
+        let node_b = InteriorNode::<usize>::new(
             None,
             AnyNodeId::new(SerializedPageId::new(3u64.to_le_bytes())),
             3usize,
@@ -227,4 +250,33 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn variable_sized_keys() {
+        let page = |x: u64| AnyNodeId::new(SerializedPageId::new(x.to_le_bytes()));
+
+        let mut node = InteriorNode::<ByteKey>::new(None, page(0), b"m", page(1));
+
+        node.insert_node(b"ddd", page(2));
+        node.insert_node(b"zz", page(3));
+        node.insert_node(b"a", page(4));
+        node.insert_node(b"pppp", page(5));
+
+        assert_eq!(
+            node.keys().map(|x| x.1).collect::<Vec<_>>(),
+            vec![&b"a"[..], b"ddd", b"m", b"pppp", b"zz"]
+        );
+        assert_eq!(node.upper_bound(b"ddd"), KeyIndex::new(2));
+        assert_eq!(node.upper_bound(b"e"), KeyIndex::new(2));
+        assert_eq!(node.upper_bound(b""), KeyIndex::new(0));
+        assert_eq!(node.upper_bound(b"zzz"), KeyIndex::new(5));
+
+        node.delete(page(2));
+
+        assert_eq!(
+            node.keys().map(|x| x.1).collect::<Vec<_>>(),
+            vec![&b"a"[..], b"m", b"pppp", b"zz"]
+        );
+        assert_eq!(node.upper_bound(b"pppp"), KeyIndex::new(3));
+    }
 }
@@ -4,26 +4,34 @@ use bytemuck::{Zeroable, bytes_of, pod_read_unaligned};
 
 use crate::Size;
 use crate::bplustree::TreeKey;
+use crate::bplustree::algorithms::bulk_load::FillFactor;
 use crate::bplustree::node::leaf::LEAF_NODE_DATA_SIZE;
 use crate::bplustree::node::leaf::builder::MaterializedData;
+use crate::bplustree::overflow::{LeafValue, OverflowPointer};
+use crate::storage::SerializedPageId;
 
-pub(in crate::bplustree) struct LeafNodeEntry<'node, TKey> {
-    key: TKey,
-    value: &'node [u8],
+/// Set in the stored value size if the entry holds a pointer to an overflow chain instead of the
+/// value itself. The rest of the bits are then the length of the whole value.
+const OVERFLOW_FLAG: u64 = 1 << 63;
+
+pub(in crate::bplustree) struct LeafNodeEntry<'node, TKey: TreeKey> {
+    key: TKey::Borrowed<'node>,
+    value: LeafValue<'node>,
     size: usize,
+    key_size: usize,
 }
 
 impl<'node, TKey: TreeKey> LeafNodeEntry<'node, TKey> {
-    pub const fn key(&self) -> TKey {
+    pub const fn key(&self) -> TKey::Borrowed<'node> {
         self.key
     }
 
-    pub const fn value(&self) -> &'node [u8] {
+    pub const fn value(&self) -> LeafValue<'node> {
         self.value
     }
 
     pub const fn total_size(&self) -> usize {
-        self.size + size_of::<u64>() + size_of::<TKey>()
+        self.size + size_of::<u64>() + self.key_size
     }
 
     pub(crate) const fn value_size(&self) -> usize {
@@ -33,17 +41,12 @@ impl<'node, TKey: TreeKey> LeafNodeEntry<'node, TKey> {
 
 pub(super) struct LeafNodeEntryIterator<'node, TKey: TreeKey> {
     data: &'node LeafNodeEntries<TKey>,
-    offset: usize,
     index: usize,
 }
 
 impl<'node, TKey: TreeKey> LeafNodeEntryIterator<'node, TKey> {
     pub(crate) const fn new(data: &'node LeafNodeEntries<TKey>) -> Self {
-        Self {
-            data,
-            offset: 0,
-            index: 0,
-        }
+        Self { data, index: 0 }
     }
 }
 
@@ -51,33 +54,38 @@ impl<'node, TKey: TreeKey + 'node> Iterator for LeafNodeEntryIterator<'node, TKe
     type Item = LeafNodeEntry<'node, TKey>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.data.len() {
-            return None;
-        }
-
-        let entry = self.data.entry_at(self.offset);
+        let entry = self.data.entry(self.index)?;
 
-        self.offset += entry.total_size();
         self.index += 1;
 
         Some(entry)
     }
 }
 
+const LEAF_NODE_ENTRIES_DATA_SIZE: usize = LEAF_NODE_DATA_SIZE
+    .subtract(Size::of::<u16>().multiply(2))
+    .as_bytes();
+
+/// The entries are packed one after another from the start of `data`, ordered by their keys, while
+/// the end of `data` holds their offsets (stored backwards), so that any entry can be found without
+/// reading the ones before it.
 #[derive(Debug, Zeroable, Clone, Copy)]
 #[repr(C, align(8))]
 pub struct LeafNodeEntries<TKey> {
-    data: [u8; LEAF_NODE_DATA_SIZE.subtract(Size::of::<u16>()).as_bytes()],
+    data: [u8; LEAF_NODE_ENTRIES_DATA_SIZE],
     len: u16,
+    entries_size: u16,
     _key: PhantomData<TKey>,
 }
 
 impl<TKey: TreeKey> LeafNodeEntries<TKey> {
+    const SLOT_SIZE: usize = size_of::<u16>();
     const _ASSERT_SIZE: () = assert!(Size::of::<Self>().is_equal(LEAF_NODE_DATA_SIZE));
 
     pub const fn new() -> Self {
         Self {
             len: 0,
+            entries_size: 0,
             data: [0; _],
             _key: PhantomData,
         }
@@ -91,116 +99,201 @@ impl<TKey: TreeKey> LeafNodeEntries<TKey> {
         self.len as usize
     }
 
-    pub fn entry(&self, index: usize) -> Option<LeafNodeEntry<'_, TKey>> {
-        if index >= self.len() {
-            return None;
-        }
+    const fn entries_size(&self) -> usize {
+        self.entries_size as usize
+    }
 
+    pub fn entry(&self, index: usize) -> Option<LeafNodeEntry<'_, TKey>> {
         let entry_offset = self.entry_offset(index)?;
 
         Some(self.entry_at(entry_offset))
     }
 
+    /// Returns the index of the first entry for which `predicate` is false, assuming that it is
+    /// true for all the entries before it and false for all the entries after it.
+    pub fn partition_point(&self, predicate: impl Fn(LeafNodeEntry<'_, TKey>) -> bool) -> usize {
+        let mut low = 0;
+        let mut high = self.len();
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            if predicate(self.entry(middle).unwrap()) {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        low
+    }
+
+    const fn slot_offset(index: usize) -> usize {
+        LEAF_NODE_ENTRIES_DATA_SIZE - (index + 1) * Self::SLOT_SIZE
+    }
+
     fn entry_offset(&self, index: usize) -> Option<usize> {
         if index >= self.len() {
             return None;
         }
 
-        let mut offset = 0;
+        let slot_offset = Self::slot_offset(index);
 
-        for _ in 0..index {
-            let entry = self.entry_at(offset);
+        Some(usize::from(pod_read_unaligned::<u16>(
+            &self.data[slot_offset..slot_offset + Self::SLOT_SIZE],
+        )))
+    }
 
-            offset += entry.total_size();
-            debug_assert!(offset < self.data.len());
-        }
+    fn set_entry_offset(&mut self, index: usize, entry_offset: usize) {
+        let slot_offset = Self::slot_offset(index);
 
-        Some(offset)
+        self.data[slot_offset..slot_offset + Self::SLOT_SIZE]
+            .copy_from_slice(bytes_of(&u16::try_from(entry_offset).unwrap()));
     }
 
     fn entry_at(&self, offset: usize) -> LeafNodeEntry<'_, TKey> {
-        let value_size_offset = offset + size_of::<TKey>();
+        let key = TKey::decode(&self.data[offset..]);
+        let key_size = TKey::encoded_size(key);
+        let value_size_offset = offset + key_size;
         let value_offset = value_size_offset + size_of::<u64>();
-        let value_size = usize::try_from(pod_read_unaligned::<u64>(
+        let stored_size = pod_read_unaligned::<u64>(
             &self.data[value_size_offset..value_size_offset + size_of::<u64>()],
-        ))
-        .unwrap();
+        );
+
+        let value = if stored_size & OVERFLOW_FLAG == 0 {
+            let value_size = usize::try_from(stored_size).unwrap();
+
+            LeafValue::Inline(&self.data[value_offset..value_offset + value_size])
+        } else {
+            let first_page = pod_read_unaligned::<SerializedPageId>(
+                &self.data[value_offset..value_offset + size_of::<SerializedPageId>()],
+            );
+
+            LeafValue::Overflow(OverflowPointer::new(
+                first_page,
+                stored_size & !OVERFLOW_FLAG,
+            ))
+        };
 
         LeafNodeEntry {
-            key: pod_read_unaligned(&self.data[offset..offset + size_of::<TKey>()]),
-            value: &self.data[value_offset..value_offset + value_size],
-            size: value_size,
+            key,
+            value,
+            size: value.inline_size(),
+            key_size,
         }
     }
 
-    fn used_size(&self) -> usize {
-        let mut size = 0;
-
-        for entry in self.entries() {
-            size += entry.total_size();
-        }
+    const fn used_size(&self) -> usize {
+        self.entries_size() + self.len() * Self::SLOT_SIZE
+    }
 
-        size
+    const fn entry_size_for(key_size: usize, value_size: usize) -> usize {
+        key_size + size_of::<u64>() + value_size
     }
 
-    const fn entry_size_for_value_size(value_size: usize) -> usize {
-        size_of::<TKey>() + size_of::<u64>() + value_size
+    pub fn can_fit(&self, key: TKey::Borrowed<'_>, value_size: usize) -> bool {
+        self.can_fit_filled(key, value_size, FillFactor::FULL)
     }
 
-    pub fn can_fit(&self, value_size: usize) -> bool {
-        self.used_size() + Self::entry_size_for_value_size(value_size) <= self.data.len()
+    /// Like `can_fit`, but only lets the entries take up `fill_factor` of the capacity.
+    pub fn can_fit_filled(
+        &self,
+        key: TKey::Borrowed<'_>,
+        value_size: usize,
+        fill_factor: FillFactor,
+    ) -> bool {
+        self.used_size()
+            + Self::entry_size_for(TKey::encoded_size(key), value_size)
+            + Self::SLOT_SIZE
+            <= fill_factor.of(LEAF_NODE_ENTRIES_DATA_SIZE)
     }
 
+    /// Moves the data of the entries starting at `start_index` by `offset` bytes.
     fn move_entries(&mut self, start_index: usize, offset: isize) {
-        let move_start_offset = self.entry_offset(start_index).unwrap();
-        let move_end_offset = self.used_size();
-        let data_to_move = self.data[move_start_offset..move_end_offset].to_vec();
+        let Some(move_start_offset) = self.entry_offset(start_index) else {
+            return;
+        };
+        let move_end_offset = self.entries_size();
+
+        self.data.copy_within(
+            move_start_offset..move_end_offset,
+            move_start_offset.strict_add_signed(offset),
+        );
 
-        self.data[move_start_offset.strict_add_signed(offset)
-            ..move_end_offset.strict_add_signed(offset)]
-            .copy_from_slice(&data_to_move);
+        for index in start_index..self.len() {
+            let entry_offset = self.entry_offset(index).unwrap();
+
+            self.set_entry_offset(index, entry_offset.strict_add_signed(offset));
+        }
     }
 
-    // TODO extract functions/struct/whatever for managing a value of any size
-    pub fn insert_at(&mut self, index: usize, key: TKey, value: &[u8]) {
-        assert!(self.can_fit(value.len()));
+    /// Moves the slots starting at `start_index` by `offset` indices.
+    fn move_slots(&mut self, start_index: usize, offset: isize) {
+        if start_index >= self.len() {
+            return;
+        }
 
-        let entry_offset = if index < self.len() {
-            let entry_size = Self::entry_size_for_value_size(value.len());
+        // the slots are stored backwards, so the last one has the lowest offset
+        let source_start = Self::slot_offset(self.len() - 1);
+        let source_end = Self::slot_offset(start_index) + Self::SLOT_SIZE;
+        let target_start = Self::slot_offset((self.len() - 1).strict_add_signed(offset));
 
-            self.move_entries(index, isize::try_from(entry_size).unwrap());
-            self.entry_offset(index).unwrap()
-        } else {
-            self.used_size()
-        };
+        self.data
+            .copy_within(source_start..source_end, target_start);
+    }
 
-        let key_hole: &mut [u8] = &mut self.data[entry_offset..entry_offset + size_of::<TKey>()];
+    pub fn insert_at(&mut self, index: usize, key: TKey::Borrowed<'_>, value: LeafValue<'_>) {
+        let value_size = value.inline_size();
 
-        key_hole.copy_from_slice(bytes_of(&key));
+        assert!(index <= self.len());
+        assert!(self.can_fit(key, value_size));
 
-        let value_size_hole = &mut self.data
-            [entry_offset + size_of::<TKey>()..entry_offset + size_of::<TKey>() + size_of::<u64>()];
+        let key_size = TKey::encoded_size(key);
+        let entry_size = Self::entry_size_for(key_size, value_size);
+        let entry_offset = self
+            .entry_offset(index)
+            .unwrap_or_else(|| self.entries_size());
 
-        value_size_hole.copy_from_slice(bytes_of(&(value.len() as u64)));
+        self.move_entries(index, isize::try_from(entry_size).unwrap());
+        self.move_slots(index, 1);
+        self.set_entry_offset(index, entry_offset);
 
-        let entry_data_offset = Size::of::<TKey>() + Size::of::<u64>();
+        self.len += 1;
+        self.entries_size += u16::try_from(entry_size).unwrap();
 
-        let value_hole = &mut self.data[entry_offset + entry_data_offset.as_bytes()
-            ..entry_offset + (entry_data_offset + Size::B(value.len())).as_bytes()];
+        TKey::encode(key, &mut self.data[entry_offset..entry_offset + key_size]);
 
-        value_hole.copy_from_slice(value);
+        let value_size_hole =
+            &mut self.data[entry_offset + key_size..entry_offset + key_size + size_of::<u64>()];
 
-        self.len += 1;
+        let first_page;
+        let (stored_size, value_bytes) = match value {
+            LeafValue::Inline(value) => (value.len() as u64, value),
+            LeafValue::Overflow(pointer) => {
+                first_page = pointer.first_page();
+
+                (pointer.length() | OVERFLOW_FLAG, bytes_of(&first_page))
+            }
+        };
+
+        value_size_hole.copy_from_slice(bytes_of(&stored_size));
+
+        let entry_data_offset = Size::B(key_size) + Size::of::<u64>();
+
+        let value_hole = &mut self.data[entry_offset + entry_data_offset.as_bytes()
+            ..entry_offset + (entry_data_offset + Size::B(value_size)).as_bytes()];
+
+        value_hole.copy_from_slice(value_bytes);
     }
 
     pub fn delete_at(&mut self, index: usize) {
         let size = self.entry(index).unwrap().total_size();
 
-        if index + 1 < self.len() {
-            self.move_entries(index + 1, -isize::try_from(size).unwrap());
-        }
+        self.move_entries(index + 1, -isize::try_from(size).unwrap());
+        self.move_slots(index + 1, -1);
 
         self.len -= 1;
+        self.entries_size -= u16::try_from(size).unwrap();
     }
 
     pub fn split(&'_ mut self) -> MaterializedData<'_, TKey> {
@@ -210,7 +303,7 @@ impl<TKey: TreeKey> LeafNodeEntries<TKey> {
         let mut entries_to_leave = 0;
         let mut offset = 0;
 
-        while offset <= self.used_size() / 2 {
+        while offset <= self.entries_size() / 2 {
             let entry = self.entry(entries_to_leave).unwrap();
 
             offset += entry.total_size();
@@ -226,28 +319,42 @@ impl<TKey: TreeKey> LeafNodeEntries<TKey> {
 
     fn split_at(&mut self, entries_to_leave: usize) -> &[u8] {
         let move_start_offset = self.entry_offset(entries_to_leave).unwrap();
-        let moved_entries_end = self.used_size();
-
-        let new_node_entries = &self.data[move_start_offset..moved_entries_end];
+        let moved_entries_end = self.entries_size();
 
         self.len = u16::try_from(entries_to_leave).unwrap();
+        self.entries_size = u16::try_from(move_start_offset).unwrap();
 
-        new_node_entries
+        &self.data[move_start_offset..moved_entries_end]
+    }
+
+    pub(super) fn materialize(&self) -> MaterializedData<'_, TKey> {
+        MaterializedData::new(self.len(), &self.data[..self.entries_size()])
     }
 
     pub(crate) fn from_data(entry_count: usize, data: &[u8]) -> Self {
         let mut entries = Self::new();
         entries.data[0..data.len()].copy_from_slice(data);
         entries.len = u16::try_from(entry_count).unwrap();
+        entries.entries_size = u16::try_from(data.len()).unwrap();
+
+        let mut offset = 0;
+
+        for index in 0..entry_count {
+            entries.set_entry_offset(index, offset);
+
+            offset += entries.entry_at(offset).total_size();
+        }
+
+        debug_assert!(offset == data.len());
 
         entries
     }
 
-    pub(super) fn needs_merge(&self) -> bool {
-        self.used_size() * 2 < self.data.len()
+    pub(super) const fn needs_merge(&self) -> bool {
+        self.used_size() * 2 < LEAF_NODE_ENTRIES_DATA_SIZE
     }
 
-    pub(crate) fn can_fit_merge(&self, other: Self) -> bool {
-        self.used_size() + other.used_size() <= self.data.len()
+    pub(crate) const fn can_fit_merge(&self, other: Self) -> bool {
+        self.used_size() + other.used_size() <= LEAF_NODE_ENTRIES_DATA_SIZE
     }
 }
@@ -6,11 +6,13 @@ use std::fmt::Debug;
 use bytemuck::{Pod, Zeroable};
 
 use crate::Size;
+use crate::bplustree::algorithms::bulk_load::FillFactor;
 use crate::bplustree::node::leaf::builder::{
     LeafNodeBuilder, MaterializedData, MaterializedTopology, Topology,
 };
 use crate::bplustree::node::leaf::entries::{LeafNodeEntries, LeafNodeEntry};
 use crate::bplustree::node::{InteriorNodeId, Node, NodeHeader};
+use crate::bplustree::overflow::{LeafValue, StoredValue};
 use crate::bplustree::{LeafNodeId, NodeId, TreeKey};
 use crate::storage::in_memory::version_manager::versioned_page::VERSIONED_PAGE_DATA_SIZE;
 use crate::storage::{SENTINEL_PAGE_ID, SerializedPageId};
@@ -22,6 +24,9 @@ const LEAF_NODE_DATA_SIZE: Size = VERSIONED_PAGE_DATA_SIZE.subtract(
         .add(Size::of::<u64>().multiply(2)),
 );
 
+/// Values larger than this are stored in an overflow chain, and the leaf only keeps a pointer to it.
+pub(in crate::bplustree) const MAX_INLINE_VALUE_SIZE: usize = LEAF_NODE_DATA_SIZE.as_bytes() / 4;
+
 #[derive(Debug, Zeroable, Clone, Copy)]
 #[repr(C, align(8))]
 pub(in crate::bplustree) struct LeafNode<TKey>
@@ -77,11 +82,11 @@ impl<TKey: TreeKey> LeafNode<TKey> {
         }
     }
 
-    pub fn delete(&mut self, key: TKey) -> Option<(Vec<u8>, bool)> {
+    pub fn delete(&mut self, key: TKey::Borrowed<'_>) -> Option<(StoredValue, bool)> {
         let index = self.find(key)?;
 
         let entry = self.entry(index).unwrap();
-        let result = entry.value().to_vec();
+        let result = entry.value().to_stored();
 
         self.data.delete_at(index);
 
@@ -89,44 +94,42 @@ impl<TKey: TreeKey> LeafNode<TKey> {
         Some((result, self.data.needs_merge()))
     }
 
-    pub fn find(&self, key: TKey) -> Option<usize> {
-        for (index, entry) in self.entries().enumerate() {
-            if entry.key() == key {
-                return Some(index);
-            }
-        }
+    pub fn find(&self, key: TKey::Borrowed<'_>) -> Option<usize> {
+        let index = self.lower_bound(key);
 
-        None
+        self.entry(index)
+            .is_some_and(|x| TKey::compare(x.key(), key).is_eq())
+            .then_some(index)
     }
 
-    pub fn insert(&mut self, key: TKey, value: &[u8]) -> Option<Vec<u8>> {
-        let mut insert_index = self.data.len();
-
-        let mut delete_index = None;
-
-        for (index, entry) in self.entries().enumerate() {
-            if key == entry.key() {
-                delete_index = Some(index);
+    /// Returns the index of the first entry with a key that's not less than `key`.
+    pub fn lower_bound(&self, key: TKey::Borrowed<'_>) -> usize {
+        self.data
+            .partition_point(|x| TKey::compare(x.key(), key).is_lt())
+    }
 
-                insert_index = index;
-                break;
-            }
+    /// Returns the index of the first entry with a key greater than `key`.
+    pub fn upper_bound(&self, key: TKey::Borrowed<'_>) -> usize {
+        self.data
+            .partition_point(|x| TKey::compare(x.key(), key).is_le())
+    }
 
-            if key < entry.key() {
-                insert_index = index;
-                break;
-            }
-        }
+    pub fn insert(&mut self, key: TKey::Borrowed<'_>, value: LeafValue<'_>) -> Option<StoredValue> {
+        let insert_index = self.lower_bound(key);
+        let delete_index = self
+            .entry(insert_index)
+            .is_some_and(|x| TKey::compare(x.key(), key).is_eq())
+            .then_some(insert_index);
 
         let deleted_entry = delete_index.and_then(|x| self.data.entry(x));
-        let result = deleted_entry.as_ref().map(|x| x.value().to_vec());
+        let result = deleted_entry.as_ref().map(|x| x.value().to_stored());
 
         let size_increase = value
-            .len()
+            .inline_size()
             .saturating_sub(deleted_entry.map_or(0, |x| x.value_size()));
 
         assert!(
-            self.data.can_fit(size_increase),
+            self.data.can_fit(key, size_increase),
             "not enough capacity for the value, split node before inserting: {:?}",
             tracing::Span::current()
         );
@@ -161,12 +164,26 @@ impl<TKey: TreeKey> LeafNode<TKey> {
         self.leaf_header.next = next.map_or(SENTINEL_PAGE_ID, |x| x.page());
     }
 
-    pub(in crate::bplustree) fn first_key(&self) -> Option<TKey> {
+    pub(in crate::bplustree) fn first_key(&self) -> Option<TKey::Borrowed<'_>> {
         self.entry(0).map(|x| x.key())
     }
 
-    pub(crate) fn can_fit(&self, value_size: usize) -> bool {
-        self.data.can_fit(value_size)
+    pub(crate) fn can_fit(&self, key: TKey::Borrowed<'_>, value_size: usize) -> bool {
+        self.data.can_fit(key, value_size)
+    }
+
+    pub(crate) fn can_fit_filled(
+        &self,
+        key: TKey::Borrowed<'_>,
+        value_size: usize,
+        fill_factor: FillFactor,
+    ) -> bool {
+        self.data.can_fit_filled(key, value_size, fill_factor)
+    }
+
+    /// The entries of this node, for building a new node with `LeafNodeBuilder`.
+    pub(crate) fn materialize(&self) -> MaterializedData<'_, TKey> {
+        self.data.materialize()
     }
 
     pub(crate) const fn len(&self) -> usize {
@@ -181,7 +198,7 @@ impl<TKey: TreeKey> LeafNode<TKey> {
         self.set_next(right.next());
     }
 
-    pub(crate) fn can_fit_merge(&self, right: &Self) -> bool {
+    pub(crate) const fn can_fit_merge(&self, right: &Self) -> bool {
         self.data.can_fit_merge(right.data)
     }
 }
@@ -214,38 +231,93 @@ const _: () = assert!(Size::of::<LeafNodeHeader>().is_equal(Size::of::<u64>().mu
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::bplustree::ByteKey;
 
-    fn collect_entries<TKey: TreeKey>(node: &LeafNode<TKey>) -> Vec<(TKey, Vec<u8>)> {
+    fn collect_entries<TKey: TreeKey>(node: &LeafNode<TKey>) -> Vec<(TKey::Owned, Vec<u8>)> {
         node.entries()
-            .map(|x| (x.key(), x.value().to_vec()))
+            .map(|x| {
+                let LeafValue::Inline(value) = x.value() else {
+                    panic!("unexpected overflow value");
+                };
+
+                (TKey::to_owned(x.key()), value.to_vec())
+            })
             .collect::<Vec<_>>()
     }
 
     #[test]
     fn insert_reverse() {
-        let mut node = LeafNode::new(None);
-        let _ = node.insert(1, &[0]);
-        let _ = node.insert(0, &[0]);
+        let mut node = LeafNode::<i32>::new(None);
+        let _ = node.insert(1, LeafValue::Inline(&[0]));
+        let _ = node.insert(0, LeafValue::Inline(&[0]));
 
         assert_eq!(collect_entries(&node), &[(0, vec![0]), (1, vec![0])]);
     }
 
     #[test]
     fn same_key_overrides() {
-        let mut node = LeafNode::new(None);
-        let _ = node.insert(0, &[0]);
-        let _ = node.insert(0, &[1]);
+        let mut node = LeafNode::<i32>::new(None);
+        let _ = node.insert(0, LeafValue::Inline(&[0]));
+        let _ = node.insert(0, LeafValue::Inline(&[1]));
 
         assert_eq!(collect_entries(&node), &[(0, vec![1])]);
     }
 
     #[test]
     fn same_key_same_overrides_with_intermediate() {
-        let mut node = LeafNode::new(None);
-        let _ = node.insert(1, &[0]);
-        let _ = node.insert(2, &[0]);
-        let _ = node.insert(1, &[0]);
+        let mut node = LeafNode::<i32>::new(None);
+        let _ = node.insert(1, LeafValue::Inline(&[0]));
+        let _ = node.insert(2, LeafValue::Inline(&[0]));
+        let _ = node.insert(1, LeafValue::Inline(&[0]));
 
         assert_eq!(collect_entries(&node), &[(1, vec![0]), (2, vec![0])]);
     }
+
+    #[test]
+    fn binary_search() {
+        let mut node = LeafNode::<i32>::new(None);
+
+        for i in 0..50i32 {
+            let key = (i * 7) % 50 * 2;
+            let _ = node.insert(key, LeafValue::Inline(&key.to_le_bytes()));
+        }
+
+        assert_eq!(
+            collect_entries(&node),
+            (0..50i32)
+                .map(|x| (x * 2, (x * 2).to_le_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(node.find(42), Some(21));
+        assert_eq!(node.find(43), None);
+        assert_eq!(node.lower_bound(42), 21);
+        assert_eq!(node.upper_bound(42), 22);
+        assert_eq!(node.lower_bound(43), 22);
+        assert_eq!(node.upper_bound(43), 22);
+        assert_eq!(node.lower_bound(-1), 0);
+        assert_eq!(node.upper_bound(98), 50);
+    }
+
+    #[test]
+    fn delete_variable_sized_keys() {
+        let mut node = LeafNode::<ByteKey>::new(None);
+
+        for key in [&b"bb"[..], b"a", b"dddd", b"ccc"] {
+            let _ = node.insert(key, LeafValue::Inline(key));
+        }
+
+        let _ = node.delete(b"bb");
+
+        assert_eq!(
+            collect_entries(&node),
+            &[
+                (b"a".to_vec(), b"a".to_vec()),
+                (b"ccc".to_vec(), b"ccc".to_vec()),
+                (b"dddd".to_vec(), b"dddd".to_vec())
+            ]
+        );
+        assert_eq!(node.find(b"dddd"), Some(2));
+        assert_eq!(node.find(b"bb"), None);
+    }
 }
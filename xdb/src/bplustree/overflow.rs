@@ -0,0 +1,192 @@
+use bytemuck::{Pod, Zeroable};
+
+use crate::Size;
+use crate::bplustree::{TreeError, TreeKey, TreeTransaction};
+use crate::storage::in_memory::version_manager::versioned_page::VERSIONED_PAGE_DATA_SIZE;
+use crate::storage::{ReadTransaction, SENTINEL_PAGE_ID, SerializedPageId, Storage};
+
+const OVERFLOW_PAGE_DATA_SIZE: Size =
+    VERSIONED_PAGE_DATA_SIZE.subtract(Size::of::<u64>().multiply(2));
+
+/// A single page of an overflow chain, holding a part of a value that was too large to be stored
+/// in a leaf.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, align(8))]
+pub(super) struct OverflowPage {
+    next: SerializedPageId,
+    length: u64,
+    data: [u8; OVERFLOW_PAGE_DATA_SIZE.as_bytes()],
+}
+
+impl OverflowPage {
+    const _ASSERT_SIZE: () = assert!(Size::of::<Self>().is_equal(VERSIONED_PAGE_DATA_SIZE));
+
+    fn new(next: SerializedPageId, chunk: &[u8]) -> Self {
+        let mut data = [0; _];
+        data[..chunk.len()].copy_from_slice(chunk);
+
+        Self {
+            next,
+            length: chunk.len() as u64,
+            data,
+        }
+    }
+
+    fn next(&self) -> Option<SerializedPageId> {
+        if self.next == SENTINEL_PAGE_ID {
+            None
+        } else {
+            Some(self.next)
+        }
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &self.data[..usize::try_from(self.length).unwrap()]
+    }
+}
+
+/// Points to the first page of an overflow chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct OverflowPointer {
+    first_page: SerializedPageId,
+    length: u64,
+}
+
+impl OverflowPointer {
+    pub const fn new(first_page: SerializedPageId, length: u64) -> Self {
+        Self { first_page, length }
+    }
+
+    pub const fn first_page(self) -> SerializedPageId {
+        self.first_page
+    }
+
+    pub const fn length(self) -> u64 {
+        self.length
+    }
+}
+
+/// The value of a leaf entry, as it is stored in the leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LeafValue<'node> {
+    Inline(&'node [u8]),
+    Overflow(OverflowPointer),
+}
+
+impl LeafValue<'_> {
+    /// The number of bytes the value takes in the leaf itself.
+    pub const fn inline_size(self) -> usize {
+        match self {
+            Self::Inline(value) => value.len(),
+            Self::Overflow(_) => size_of::<SerializedPageId>(),
+        }
+    }
+
+    pub fn to_stored(self) -> StoredValue {
+        match self {
+            Self::Inline(value) => StoredValue::Inline(value.to_vec()),
+            Self::Overflow(pointer) => StoredValue::Overflow(pointer),
+        }
+    }
+}
+
+/// A `LeafValue` that outlives the node it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum StoredValue {
+    Inline(Vec<u8>),
+    Overflow(OverflowPointer),
+}
+
+/// Writes the value into a new overflow chain. The pages are inserted from the end of the chain, so
+/// that each of them can point to the next one.
+pub(super) fn write<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    value: &[u8],
+) -> Result<OverflowPointer, TreeError<TStorage::PageId>> {
+    let mut next = SENTINEL_PAGE_ID;
+
+    for chunk in value.chunks(OVERFLOW_PAGE_DATA_SIZE.as_bytes()).rev() {
+        next = transaction.insert_page(OverflowPage::new(next, chunk))?;
+    }
+
+    Ok(OverflowPointer::new(next, value.len() as u64))
+}
+
+/// Returns the whole value, reading it from the overflow chain if needed.
+pub(super) fn read<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey, TTransaction>,
+    value: StoredValue,
+) -> Result<Vec<u8>, TreeError<TStorage::PageId>>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
+    match value {
+        StoredValue::Inline(value) => Ok(value),
+        StoredValue::Overflow(pointer) => read_chain(transaction, pointer),
+    }
+}
+
+/// Like `read`, but also deletes the overflow chain, if there is one.
+pub(super) fn take<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    value: StoredValue,
+) -> Result<Vec<u8>, TreeError<TStorage::PageId>> {
+    match value {
+        StoredValue::Inline(value) => Ok(value),
+        StoredValue::Overflow(pointer) => {
+            let result = read_chain(transaction, pointer)?;
+
+            free_chain(transaction, pointer)?;
+
+            Ok(result)
+        }
+    }
+}
+
+pub(super) fn free<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    value: &StoredValue,
+) -> Result<(), TreeError<TStorage::PageId>> {
+    match value {
+        StoredValue::Inline(_) => Ok(()),
+        StoredValue::Overflow(pointer) => free_chain(transaction, *pointer),
+    }
+}
+
+fn read_chain<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>(
+    transaction: &mut TreeTransaction<'storage, TStorage, TKey, TTransaction>,
+    pointer: OverflowPointer,
+) -> Result<Vec<u8>, TreeError<TStorage::PageId>>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
+    let mut result = Vec::with_capacity(usize::try_from(pointer.length()).unwrap());
+    let mut next = Some(pointer.first_page());
+
+    while let Some(page) = next {
+        next = transaction.read_page(page, |page: &OverflowPage| {
+            result.extend_from_slice(page.chunk());
+
+            page.next()
+        })?;
+    }
+
+    assert!(result.len() as u64 == pointer.length());
+
+    Ok(result)
+}
+
+fn free_chain<TStorage: Storage, TKey: TreeKey>(
+    transaction: &mut TreeTransaction<TStorage, TKey>,
+    pointer: OverflowPointer,
+) -> Result<(), TreeError<TStorage::PageId>> {
+    let mut next = Some(pointer.first_page());
+
+    while let Some(page) = next {
+        next = transaction.read_page(page, OverflowPage::next)?;
+
+        transaction.delete_page(page)?;
+    }
+
+    Ok(())
+}
@@ -1,32 +1,227 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::mem;
+use std::ops::RangeBounds;
+use std::time::Instant;
 
+use bytemuck::Pod;
+use tracing::debug;
+
+use crate::bplustree::algorithms::delete::delete;
+use crate::bplustree::algorithms::find;
+use crate::bplustree::algorithms::insert::insert;
+use crate::bplustree::iterator::{TreeIterator, TreeIteratorItem};
 use crate::bplustree::{AnyNodeId, Node, NodeId as _, NodeIds, TreeError, TreeHeader, TreeKey};
 use crate::storage::{
-    FIRST_PAGE_ID, Page as _, PageId as _, PageReservation as _, SENTINEL_PAGE_ID, Storage,
-    Transaction as _, TransactionId,
+    Change, IsolationLevel, Page as _, PageId as _, PageReservation as _, ReadTransaction,
+    SENTINEL_PAGE_ID, Savepoint, SerializedPageId, Storage, StorageError, Transaction as _,
+    TransactionId,
 };
 
+/// How many times a single transaction can be started over in a newer snapshot, before its
+/// conflicts are returned to the caller.
+const MAX_REPLAYS: usize = 16;
+
+/// A modification of the tree, recorded so that it can be applied again in a newer snapshot.
 #[derive(Debug)]
-pub struct TreeTransaction<'storage, TStorage: Storage + 'storage, TKey>
-where
+enum Operation<TKey: TreeKey> {
+    Insert(TKey::Owned, Vec<u8>),
+    Delete(TKey::Owned),
+}
+
+impl<TKey: TreeKey> Operation<TKey> {
+    const fn key(&self) -> &TKey::Owned {
+        match self {
+            Self::Insert(key, _) | Self::Delete(key) => key,
+        }
+    }
+}
+
+/// The point at which a transaction is replayed, which decides the keys that must not have changed
+/// since its snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplayPoint {
+    /// One of the operations conflicted. The transaction can still read other keys after the
+    /// replay, in the newer snapshot, so all the keys it read before must be unchanged.
+    Operation,
+    /// The commit conflicted, so with snapshot isolation the keys that were only read are allowed
+    /// to change, just like they would if there was no conflict on the pages.
+    Commit,
+}
+
+/// The keys a transaction touched. Every key that was written is also read first, so that its
+/// value in the snapshot is known.
+#[derive(Debug)]
+struct KeySet<TKey: TreeKey> {
+    // the values in the snapshot, `None` for the keys that did not exist
+    reads: BTreeMap<TKey::Owned, Option<Vec<u8>>>,
+    // the keys that were (or are being) written, which must not change even with snapshot
+    // isolation
+    written: BTreeSet<TKey::Owned>,
+    writes: Vec<Operation<TKey>>,
+    replays: usize,
+    isolation: IsolationLevel,
+}
+
+impl<TKey: TreeKey> KeySet<TKey> {
+    const fn new(isolation: IsolationLevel) -> Self {
+        Self {
+            reads: BTreeMap::new(),
+            written: BTreeSet::new(),
+            writes: vec![],
+            replays: 0,
+            isolation,
+        }
+    }
+}
+
+/// A savepoint of the tree transaction, along with the savepoint of the storage transaction that
+/// currently corresponds to it, as a replay starts a new one.
+#[derive(Debug, Clone, Copy)]
+struct SavepointPosition {
+    handle: Savepoint,
+    storage: Savepoint,
+    // the number of writes recorded before the savepoint
+    writes: usize,
+}
+
+/// A transaction on a single tree. By default it can both read and modify the tree, but it can
+/// also wrap a storage `ReadTransaction`, in which case only the reading operations are available.
+///
+/// The storage detects conflicts at the level of pages, so two transactions modifying different
+/// keys in the same leaf would conflict. To avoid that, a transaction started with
+/// `Tree::replayable_transaction` keeps track of the keys it touched, and on a conflict it starts
+/// over in a newer snapshot and applies its modifications again, as long as none of those keys
+/// were changed since its original snapshot.
+pub struct TreeTransaction<
+    'storage,
+    TStorage: Storage + 'storage,
+    TKey: TreeKey,
+    TTransaction = <TStorage as Storage>::Transaction<'storage>,
+> where
     Self: 'storage,
 {
-    transaction: TStorage::Transaction<'storage>,
-    _key: PhantomData<&'storage TKey>,
+    storage: &'storage TStorage,
+    // this is only `None` while a conflicting commit is being replayed
+    transaction: Option<TTransaction>,
+    header: SerializedPageId,
+    // `None` if the transaction can't be replayed, e.g. because it iterated over a range of keys
+    keys: Option<KeySet<TKey>>,
+    // only set for the transactions that can modify the tree, as only those can conflict
+    #[allow(clippy::type_complexity)]
+    replay: Option<fn(&mut Self, ReplayPoint) -> Result<bool, TreeError<TStorage::PageId>>>,
+    savepoints: Vec<SavepointPosition>,
+    // the handles are not taken from the storage transaction, as a replay starts a new one, which
+    // would give out the handles of the savepoints that are no longer valid again
+    next_savepoint_id: u64,
+    // kept, so that it can be set again on the storage transaction started by a replay
+    deadline: Option<Instant>,
+    // set once a replay failed, as the storage transaction no longer has the earlier
+    // modifications, so every operation after that fails
+    failed: bool,
+    _key: PhantomData<&'storage (TStorage, TKey)>,
 }
 
-impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
-    TreeTransaction<'storage, TStorage, TKey>
+// The key sets can get big, and this gets formatted by `instrument` on every call to some of the
+// algorithms, so only their sizes are included.
+impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction: Debug> Debug
+    for TreeTransaction<'storage, TStorage, TKey, TTransaction>
 {
-    pub(super) const fn new(storage_transaction: TStorage::Transaction<'storage>) -> Self {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeTransaction")
+            .field("transaction", &self.transaction)
+            .field("header", &self.header)
+            .field("read_count", &self.keys.as_ref().map(|x| x.reads.len()))
+            .field("write_count", &self.keys.as_ref().map(|x| x.writes.len()))
+            .finish_non_exhaustive()
+    }
+}
+
+pub type ReadOnlyTreeTransaction<'storage, TStorage, TKey> =
+    TreeTransaction<'storage, TStorage, TKey, <TStorage as Storage>::ReadTransaction<'storage>>;
+
+impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey, TTransaction>
+    TreeTransaction<'storage, TStorage, TKey, TTransaction>
+where
+    TTransaction: ReadTransaction<'storage, Storage = TStorage>,
+{
+    pub(super) const fn new(
+        storage: &'storage TStorage,
+        storage_transaction: TTransaction,
+        header: SerializedPageId,
+    ) -> Self {
         Self {
-            transaction: storage_transaction,
+            storage,
+            transaction: Some(storage_transaction),
+            header,
+            keys: None,
+            replay: None,
+            savepoints: vec![],
+            next_savepoint_id: 0,
+            deadline: None,
+            failed: false,
             _key: PhantomData,
         }
     }
 
+    /// Continues the transaction on another tree in the same storage. The result can't be
+    /// replayed on a conflict.
+    pub(super) fn into_tree<TOtherKey: TreeKey>(
+        self,
+        header: SerializedPageId,
+    ) -> TreeTransaction<'storage, TStorage, TOtherKey, TTransaction> {
+        TreeTransaction::new(self.storage, self.transaction.unwrap(), header)
+    }
+
+    pub(super) const fn header(&self) -> SerializedPageId {
+        self.header
+    }
+
+    /// Gives direct access to the pages of the storage. As those modifications aren't recorded,
+    /// the transaction can no longer be replayed on a conflict.
+    pub(super) fn storage_transaction(&mut self) -> &mut TTransaction {
+        self.stop_replaying();
+
+        self.inner()
+    }
+
+    const fn inner(&mut self) -> &mut TTransaction {
+        self.transaction.as_mut().unwrap()
+    }
+
+    const fn active(&mut self) -> Result<&mut TTransaction, TreeError<TStorage::PageId>> {
+        if self.failed {
+            return Err(TreeError::ReplayFailed);
+        }
+
+        Ok(self.inner())
+    }
+
+    pub(super) fn stop_replaying(&mut self) {
+        self.keys = None;
+    }
+
+    /// Records the value of the key as seen in the snapshot. Only the first read of each key is
+    /// recorded, as the later ones could see the transaction's own writes.
+    pub(super) fn record_read(&mut self, key: TKey::Borrowed<'_>, value: Option<&[u8]>) {
+        if let Some(keys) = &mut self.keys {
+            keys.reads
+                .entry(TKey::to_owned(key))
+                .or_insert_with(|| value.map(<[u8]>::to_vec));
+        }
+    }
+
     pub fn id(&self) -> TransactionId {
-        self.transaction.id()
+        self.transaction.as_ref().unwrap().id()
+    }
+
+    /// Makes the operations that start after `deadline` fail with `StorageError::TimedOut`. The
+    /// deadline stays the same when the transaction is replayed.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+
+        self.inner().set_deadline(deadline);
     }
 
     pub(super) fn get_root(&mut self) -> Result<AnyNodeId, TreeError<TStorage::PageId>> {
@@ -37,11 +232,12 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
         &mut self,
         read: impl FnOnce(&TreeHeader) -> TReturn,
     ) -> Result<TReturn, TreeError<TStorage::PageId>> {
-        let txid = self.transaction.id();
+        let txid = self.id();
+        let header = self.header;
 
         Ok(self
-            .transaction
-            .read(TStorage::PageId::deserialize(FIRST_PAGE_ID), |[page]| {
+            .active()?
+            .read(TStorage::PageId::deserialize(header), |[page]| {
                 let data: &TreeHeader = page.data();
 
                 assert!(
@@ -52,17 +248,6 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
             })?)
     }
 
-    pub(super) fn write_header<TReturn>(
-        &mut self,
-        write: impl FnOnce(&mut TreeHeader) -> TReturn,
-    ) -> Result<TReturn, TreeError<TStorage::PageId>> {
-        Ok(self
-            .transaction
-            .write(TStorage::PageId::deserialize(FIRST_PAGE_ID), |[page]| {
-                write(page.data_mut())
-            })?)
-    }
-
     // TODO we should probably get rid of the callable, and just return a reference that has the
     // same lifetime as the transaction
     pub(super) fn read_nodes<TReturn, TIndices: NodeIds<N>, const N: usize>(
@@ -77,11 +262,335 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
                 .any(|x| x.serialize() == SENTINEL_PAGE_ID)
         );
 
-        Ok(self.transaction.read(page_indices, |pages| {
+        Ok(self.active()?.read(page_indices, |pages| {
             read(TIndices::pages_to_nodes(pages.map(|x| x)))
         })?)
     }
 
+    pub(super) fn read_page<TPage: Pod, TReturn>(
+        &mut self,
+        page_id: SerializedPageId,
+        read: impl FnOnce(&TPage) -> TReturn,
+    ) -> Result<TReturn, TreeError<TStorage::PageId>> {
+        debug_assert!(page_id != SENTINEL_PAGE_ID);
+
+        Ok(self
+            .active()?
+            .read(TStorage::PageId::deserialize(page_id), |[page]| {
+                read(page.data())
+            })?)
+    }
+
+    /// Iterates over all the entries, as seen by this transaction (including its own uncommitted
+    /// changes).
+    #[allow(clippy::iter_not_returning_iterator)]
+    pub fn iter(
+        &mut self,
+    ) -> Result<
+        impl DoubleEndedIterator<Item = TreeIteratorItem<TKey, TStorage::PageId>>,
+        TreeError<TStorage::PageId>,
+    > {
+        self.range(..)
+    }
+
+    /// Iterates over the entries with keys within `bounds`, as seen by this transaction. The keys
+    /// in the range are not tracked, so after this the transaction can't be replayed on a
+    /// conflict.
+    pub fn range<'key>(
+        &mut self,
+        bounds: impl RangeBounds<TKey::Borrowed<'key>>,
+    ) -> Result<
+        impl DoubleEndedIterator<Item = TreeIteratorItem<TKey, TStorage::PageId>>,
+        TreeError<TStorage::PageId>,
+    > {
+        self.stop_replaying();
+
+        TreeIterator::new(self, bounds)
+    }
+
+    /// Commits the transaction, replaying it in a newer snapshot if it conflicts with another one
+    /// on a page, but not on any of the keys.
+    pub fn commit(mut self) -> Result<(), TreeError<TStorage::PageId>> {
+        if self.failed {
+            self.transaction.take().unwrap().rollback()?;
+
+            return Err(TreeError::ReplayFailed);
+        }
+
+        loop {
+            let transaction = self.transaction.take().unwrap();
+
+            match transaction.commit() {
+                Err(
+                    error @ (StorageError::Conflict { .. }
+                    | StorageError::SerializationFailure { .. }),
+                ) => {
+                    let Some(replay) = self.replay else {
+                        return Err(error.into());
+                    };
+
+                    if !replay(&mut self, ReplayPoint::Commit)? {
+                        if let Some(transaction) = self.transaction.take() {
+                            transaction.rollback()?;
+                        }
+
+                        return Err(error.into());
+                    }
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    pub fn rollback(mut self) -> Result<(), TreeError<TStorage::PageId>> {
+        self.transaction.take().unwrap().rollback()?;
+
+        Ok(())
+    }
+}
+
+impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
+    TreeTransaction<'storage, TStorage, TKey>
+{
+    /// Makes the transaction keep track of the keys it touches, so that it can be replayed on a
+    /// conflict. The replayed transaction is started with the same isolation level.
+    pub(super) fn replayable(self, isolation: IsolationLevel) -> Self {
+        Self {
+            keys: Some(KeySet::new(isolation)),
+            replay: Some(Self::replay),
+            ..self
+        }
+    }
+
+    pub(super) fn record_insert(&mut self, key: TKey::Borrowed<'_>, value: &[u8]) {
+        if let Some(keys) = &mut self.keys {
+            keys.writes
+                .push(Operation::Insert(TKey::to_owned(key), value.to_vec()));
+        }
+    }
+
+    pub(super) fn record_delete(&mut self, key: TKey::Borrowed<'_>) {
+        if let Some(keys) = &mut self.keys {
+            keys.writes.push(Operation::Delete(TKey::to_owned(key)));
+        }
+    }
+
+    /// Whether the storage publishes the changes, so the old values are worth reading.
+    pub(super) fn captures_changes(&self) -> bool {
+        self.transaction.as_ref().unwrap().captures_changes()
+    }
+
+    /// Passes the change to the storage, to be published once the transaction commits. Unlike the
+    /// recorded operations, this also happens while replaying, as the replayed transaction is the
+    /// one that commits.
+    pub(super) fn capture_change(
+        &mut self,
+        key: TKey::Borrowed<'_>,
+        old_value: Option<&[u8]>,
+        new_value: Option<&[u8]>,
+    ) {
+        if !self.captures_changes() {
+            return;
+        }
+
+        let mut encoded = vec![0; TKey::encoded_size(key)];
+        TKey::encode(key, &mut encoded);
+
+        let change = Change::new(
+            self.header,
+            encoded,
+            old_value.map(<[u8]>::to_vec),
+            new_value.map(<[u8]>::to_vec),
+        );
+
+        self.inner().record_change(change);
+    }
+
+    /// Marks the current state of the transaction, so that the modifications made after it
+    /// (including any changes to the structure of the tree) can be undone with `rollback_to`.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let storage = self.inner().savepoint();
+        let handle = Savepoint::new(self.savepoints.len(), self.next_savepoint_id);
+        self.next_savepoint_id += 1;
+
+        self.savepoints.push(SavepointPosition {
+            handle,
+            storage,
+            writes: self.keys.as_ref().map_or(0, |keys| keys.writes.len()),
+        });
+
+        handle
+    }
+
+    /// Undoes the modifications made since `savepoint`. The savepoint can be rolled back to again,
+    /// but the ones created after it are no longer valid.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), TreeError<TStorage::PageId>> {
+        let position = self
+            .savepoints
+            .iter()
+            .position(|x| x.handle == savepoint)
+            .ok_or(StorageError::InvalidSavepoint(savepoint))?;
+
+        self.savepoints.truncate(position + 1);
+        let position = self.savepoints[position];
+
+        self.active()?.rollback_to(position.storage)?;
+
+        if let Some(keys) = &mut self.keys {
+            keys.writes.truncate(position.writes);
+            keys.written = keys.writes.iter().map(|x| x.key().clone()).collect();
+        }
+
+        Ok(())
+    }
+
+    /// Runs an operation that writes `key`, and if it fails because of a conflict, replays the
+    /// transaction and runs it again.
+    pub(super) fn retry_on_conflict<TReturn>(
+        &mut self,
+        key: TKey::Borrowed<'_>,
+        mut operation: impl FnMut(&mut Self) -> Result<TReturn, TreeError<TStorage::PageId>>,
+    ) -> Result<TReturn, TreeError<TStorage::PageId>> {
+        // Without knowing the value in the snapshot, the write could overwrite a newer value that
+        // the transaction never saw. This has to happen upfront, as after a conflict the
+        // operation might have already modified the key.
+        if self
+            .keys
+            .as_ref()
+            .is_some_and(|keys| !keys.reads.contains_key(&TKey::to_owned(key)))
+        {
+            find(self, key)?;
+        }
+
+        if let Some(keys) = &mut self.keys {
+            keys.written.insert(TKey::to_owned(key));
+        }
+
+        loop {
+            let result = operation(self);
+
+            if self.keys.is_none()
+                || !matches!(
+                    result,
+                    Err(TreeError::StorageError(StorageError::Conflict { .. }))
+                )
+            {
+                return result;
+            }
+
+            // the storage transaction could have been replaced by an empty one, so committing it
+            // would silently lose the earlier modifications
+            match self.replay(ReplayPoint::Operation) {
+                Ok(true) => {}
+                replayed => {
+                    self.failed = true;
+                    replayed?;
+
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Starts the transaction over in the newest snapshot, and applies all of its operations
+    /// again. Returns `false` if that's not possible, because one of the keys the transaction read
+    /// (or when replaying a commit with snapshot isolation, one of the keys it wrote) was modified
+    /// since its snapshot, in which case the transaction is left empty and must be rolled back.
+    fn replay(&mut self, point: ReplayPoint) -> Result<bool, TreeError<TStorage::PageId>> {
+        // the keys are taken out while replaying, so that the replayed operations are not recorded
+        // again
+        let Some(mut keys) = self.keys.take() else {
+            return Ok(false);
+        };
+
+        if keys.replays == MAX_REPLAYS {
+            return Ok(false);
+        }
+        keys.replays += 1;
+
+        if let Some(transaction) = self.transaction.take() {
+            transaction.rollback()?;
+        }
+        self.transaction = Some(self.storage.transaction_with_isolation(keys.isolation)?);
+
+        if let Some(deadline) = self.deadline {
+            self.inner().set_deadline(deadline);
+        }
+
+        for (key, original) in &keys.reads {
+            if point == ReplayPoint::Commit
+                && keys.isolation == IsolationLevel::Snapshot
+                && !keys.written.contains(key)
+            {
+                continue;
+            }
+
+            if find(self, TKey::borrow(key))? != *original {
+                debug!(
+                    ?key,
+                    "the key was modified since the snapshot, can't replay"
+                );
+
+                return Ok(false);
+            }
+        }
+
+        // the savepoints are created again at the same points, so that they can still be rolled
+        // back to
+        let mut savepoints = mem::take(&mut self.savepoints);
+
+        for (index, operation) in keys.writes.iter().enumerate() {
+            for savepoint in savepoints.iter_mut().filter(|x| x.writes == index) {
+                savepoint.storage = self.inner().savepoint();
+            }
+
+            let result = match operation {
+                Operation::Insert(key, value) => insert(self, TKey::borrow(key), value),
+                Operation::Delete(key) => delete(self, TKey::borrow(key)).map(drop),
+            };
+
+            match result {
+                Err(TreeError::StorageError(StorageError::Conflict { .. })) => {
+                    self.keys = Some(keys);
+                    self.savepoints = savepoints;
+
+                    return self.replay(point);
+                }
+                result => result?,
+            }
+        }
+
+        for savepoint in savepoints
+            .iter_mut()
+            .filter(|x| x.writes == keys.writes.len())
+        {
+            savepoint.storage = self.inner().savepoint();
+        }
+        self.savepoints = savepoints;
+
+        debug!(
+            operation_count = keys.writes.len(),
+            "replayed the transaction"
+        );
+
+        self.keys = Some(keys);
+
+        Ok(true)
+    }
+
+    pub(super) fn write_header<TReturn>(
+        &mut self,
+        write: impl FnOnce(&mut TreeHeader) -> TReturn,
+    ) -> Result<TReturn, TreeError<TStorage::PageId>> {
+        let header = self.header;
+
+        Ok(self
+            .active()?
+            .write(TStorage::PageId::deserialize(header), |[page]| {
+                write(page.data_mut())
+            })?)
+    }
+
     pub(super) fn write_nodes<TReturn, TIndices: NodeIds<N>, const N: usize>(
         &mut self,
         indices: TIndices,
@@ -94,7 +603,7 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
                 .any(|x| x.serialize() == SENTINEL_PAGE_ID)
         );
 
-        Ok(self.transaction.write(page_indices, |pages| {
+        Ok(self.active()?.write(page_indices, |pages| {
             write(TIndices::pages_to_nodes_mut(pages.map(|x| x)))
         })?)
     }
@@ -104,7 +613,7 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
     pub fn reserve_node(
         &mut self,
     ) -> Result<TStorage::PageReservation<'storage>, TreeError<TStorage::PageId>> {
-        Ok(self.transaction.reserve()?)
+        Ok(self.active()?.reserve()?)
     }
 
     #[allow(clippy::large_types_passed_by_value)] // TODO perhaps we should do something to avoid
@@ -116,7 +625,7 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
     ) -> Result<(), TreeError<TStorage::PageId>> {
         debug_assert!(reservation.index() != TStorage::PageId::sentinel());
 
-        self.transaction
+        self.active()?
             .insert_reserved(reservation, TStorage::Page::from_data(page))?;
 
         Ok(())
@@ -126,24 +635,29 @@ impl<'storage, TStorage: Storage + 'storage, TKey: TreeKey>
         &mut self,
         node_id: AnyNodeId,
     ) -> Result<(), TreeError<TStorage::PageId>> {
-        self.transaction
+        self.active()?
             .delete(TStorage::PageId::deserialize(node_id.page()))?;
 
         Ok(())
     }
 
-    pub fn commit(self) -> Result<(), TreeError<TStorage::PageId>> {
-        let Self { transaction, _key } = self;
-
-        transaction.commit()?;
-
-        Ok(())
+    /// Inserts a page that's not a part of the tree structure (e.g. a part of an overflow chain).
+    pub(super) fn insert_page(
+        &mut self,
+        page: impl Pod,
+    ) -> Result<SerializedPageId, TreeError<TStorage::PageId>> {
+        Ok(self
+            .active()?
+            .insert(TStorage::Page::from_data(page))?
+            .serialize())
     }
 
-    pub fn rollback(self) -> Result<(), TreeError<TStorage::PageId>> {
-        let Self { transaction, _key } = self;
-
-        transaction.rollback()?;
+    pub(super) fn delete_page(
+        &mut self,
+        page_id: SerializedPageId,
+    ) -> Result<(), TreeError<TStorage::PageId>> {
+        self.active()?
+            .delete(TStorage::PageId::deserialize(page_id))?;
 
         Ok(())
     }
@@ -5,41 +5,41 @@ use arbitrary::Arbitrary;
 use bytemuck::{Pod, Zeroable, bytes_of, pod_read_unaligned};
 
 use crate::Size;
-use crate::bplustree::TreeKey;
+use crate::bplustree::FixedSizeKey;
 
 #[derive(Clone, Copy, Pod, Zeroable)]
 #[repr(transparent)]
 pub struct BigKey<T, const SIZE: usize>([u8; SIZE], PhantomData<T>);
 
-impl<T: TreeKey, const SIZE: usize> PartialOrd for BigKey<T, SIZE> {
+impl<T: FixedSizeKey, const SIZE: usize> PartialOrd for BigKey<T, SIZE> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<T: TreeKey, const SIZE: usize> Ord for BigKey<T, SIZE> {
+impl<T: FixedSizeKey, const SIZE: usize> Ord for BigKey<T, SIZE> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.value().cmp(&other.value())
     }
 }
 
-impl<T: TreeKey, const SIZE: usize> Eq for BigKey<T, SIZE> {}
+impl<T: FixedSizeKey, const SIZE: usize> Eq for BigKey<T, SIZE> {}
 
-impl<T: TreeKey, const SIZE: usize> PartialEq for BigKey<T, SIZE> {
+impl<T: FixedSizeKey, const SIZE: usize> PartialEq for BigKey<T, SIZE> {
     fn eq(&self, other: &Self) -> bool {
         self.value() == other.value()
     }
 }
 
-impl<T: TreeKey, const SIZE: usize> TreeKey for BigKey<T, SIZE> {}
+impl<T: FixedSizeKey, const SIZE: usize> FixedSizeKey for BigKey<T, SIZE> {}
 
-impl<T: TreeKey, const SIZE: usize> Debug for BigKey<T, SIZE> {
+impl<T: FixedSizeKey, const SIZE: usize> Debug for BigKey<T, SIZE> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "BigKey({:?})", self.value())
     }
 }
 
-impl<'a, T: Arbitrary<'a> + TreeKey, const SIZE: usize> Arbitrary<'a> for BigKey<T, SIZE> {
+impl<'a, T: Arbitrary<'a> + FixedSizeKey, const SIZE: usize> Arbitrary<'a> for BigKey<T, SIZE> {
     fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
         let value = u.arbitrary()?;
 
@@ -47,7 +47,7 @@ impl<'a, T: Arbitrary<'a> + TreeKey, const SIZE: usize> Arbitrary<'a> for BigKey
     }
 }
 
-impl<T: Display + TreeKey, const SIZE: usize> Display for BigKey<T, SIZE> {
+impl<T: Display + FixedSizeKey, const SIZE: usize> Display for BigKey<T, SIZE> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.validate();
 
@@ -55,7 +55,7 @@ impl<T: Display + TreeKey, const SIZE: usize> Display for BigKey<T, SIZE> {
     }
 }
 
-impl<T: TreeKey, const SIZE: usize> BigKey<T, SIZE> {
+impl<T: FixedSizeKey, const SIZE: usize> BigKey<T, SIZE> {
     const VALUE_COUNT: usize = Size::of::<Self>().divide(Size::of::<T>());
 
     pub fn new(value: T) -> Self {
@@ -1,5 +1,4 @@
 // TODO see what we can prove with creusot
-// TODO replace InMemoryStorage with a file-backed one
 // TODO multithreaded fuzzing? not ideal, because it's not deterministic, but maybe could be a bit
 // better at finding bugs?
 // TODO once all the major bugs are gone, make the most expensive assers hidden behind a feature
@@ -43,10 +42,6 @@ impl Size {
         Self::B(size_of::<T>())
     }
 
-    const fn of_val<T: ?Sized>(val: &T) -> Self {
-        Self::B(size_of_val(val))
-    }
-
     const fn as_bytes(self) -> usize {
         match self {
             Self::GiB(x) => x * 1024 * 1024 * 1024,
@@ -5,5 +5,8 @@ pub mod uncommitted;
 
 pub trait Allocation: Debug + Send + Sync {
     fn commit_page(&self, address: NonNull<u8>);
+    /// Gives the memory of a page back to the OS. Its contents are lost, and it has to be
+    /// committed again before it's used.
+    fn decommit_page(&self, address: NonNull<u8>);
     fn base_address(&self) -> NonNull<u8>;
 }
@@ -1,8 +1,8 @@
 use std::ptr::NonNull;
 
 use libc::{
-    _SC_PAGE_SIZE, MAP_ANONYMOUS, MAP_FAILED, MAP_NORESERVE, MAP_PRIVATE, PROT_NONE, PROT_READ,
-    PROT_WRITE, mmap, mprotect, munmap,
+    _SC_PAGE_SIZE, MADV_DONTNEED, MAP_ANONYMOUS, MAP_FAILED, MAP_NORESERVE, MAP_PRIVATE, PROT_NONE,
+    PROT_READ, PROT_WRITE, madvise, mmap, mprotect, munmap,
 };
 
 use crate::Size;
@@ -55,15 +55,19 @@ impl UncommittedAllocation {
             size,
         }
     }
-}
 
-impl Allocation for UncommittedAllocation {
-    fn commit_page(&self, address: NonNull<u8>) {
+    fn assert_page_in_bounds(&self, address: NonNull<u8>) {
         assert!(address.align_offset(PAGE_SIZE.as_bytes()) == 0);
         assert!(
             address >= self.address
                 && address < unsafe { self.address.byte_add(self.size.as_bytes()) }
         );
+    }
+}
+
+impl Allocation for UncommittedAllocation {
+    fn commit_page(&self, address: NonNull<u8>) {
+        self.assert_page_in_bounds(address);
 
         if !cfg!(miri) {
             unsafe {
@@ -80,6 +84,26 @@ impl Allocation for UncommittedAllocation {
         }
     }
 
+    fn decommit_page(&self, address: NonNull<u8>) {
+        self.assert_page_in_bounds(address);
+
+        if !cfg!(miri) {
+            unsafe {
+                // the mapping is private and anonymous, so the page will read as zeroes once it's
+                // committed again
+                if madvise(address.cast().as_ptr(), PAGE_SIZE.as_bytes(), MADV_DONTNEED) != 0 {
+                    panic_on_errno();
+                }
+
+                // any access before committing it again is a bug, make it crash instead of
+                // silently faulting in a new page
+                if mprotect(address.cast().as_ptr(), PAGE_SIZE.as_bytes(), PROT_NONE) != 0 {
+                    panic_on_errno();
+                }
+            }
+        }
+    }
+
     fn base_address(&self) -> NonNull<u8> {
         self.address.cast()
     }
@@ -1,5 +1,5 @@
 use std::pin::Pin;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::sync::atomic::{AtomicU32, Ordering};
 use crate::thread;
@@ -19,12 +19,16 @@ impl Futex {
     }
 
     pub fn wait(self: Pin<&Self>, value: u32, timeout: Option<Duration>) {
+        let start = Instant::now();
+
         thread::yield_now();
 
         loop {
             thread::yield_now();
 
-            if self.value.load(Ordering::SeqCst) == value {
+            if self.value.load(Ordering::SeqCst) == value
+                && timeout.is_none_or(|x| start.elapsed() < x)
+            {
                 continue;
             }
 
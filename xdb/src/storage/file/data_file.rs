@@ -0,0 +1,121 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use bytemuck::must_cast;
+use tracing::{debug, instrument};
+
+use crate::storage::in_memory::InMemoryPageId;
+use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
+use crate::storage::page::{PAGE_SIZE, Page};
+use crate::storage::{PageIndex, StorageError};
+
+/// The on-disk image of the storage.
+///
+/// Each logical page lives at `index * PAGE_SIZE`, and only the latest committed version of a page
+/// is kept here - the version chains only ever exist in memory.
+#[derive(Debug)]
+pub struct DataFile {
+    file: File,
+}
+
+impl DataFile {
+    pub fn open(path: &Path) -> Result<Self, StorageError<InMemoryPageId>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Ok(Self { file })
+    }
+
+    pub fn page_count(&self) -> Result<u64, StorageError<InMemoryPageId>> {
+        let length = self.file.metadata()?.len();
+
+        Ok(length.div_ceil(PAGE_SIZE.as_bytes() as u64))
+    }
+
+    /// Returns `None` for slots that were never written, or that hold a page that was deleted.
+    #[instrument(skip(self))]
+    pub fn read_page(
+        &self,
+        index: PageIndex,
+    ) -> Result<Option<VersionedPage>, StorageError<InMemoryPageId>> {
+        let mut bytes = [0; PAGE_SIZE.as_bytes()];
+
+        // the last page might have been only partially written, the missing tail reads as zeroes
+        // and will fail the checksum check
+        let mut read = 0;
+        while read < bytes.len() {
+            match self
+                .file
+                .read_at(&mut bytes[read..], Self::offset(index) + read as u64)?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        if bytes.iter().all(|x| *x == 0) {
+            return Ok(None);
+        }
+
+        let page = Page::deserialize(bytes)
+            .map_err(|_| StorageError::Corrupted(InMemoryPageId::from_value(index.value())))?;
+
+        if page.is_free() {
+            return Ok(None);
+        }
+
+        Ok(Some(must_cast(page)))
+    }
+
+    /// Writes the page with all the versioning information cleared, as after a restart there are no
+    /// transactions that could see any other version.
+    #[instrument(skip(self, page))]
+    pub fn write_page(
+        &self,
+        index: PageIndex,
+        page: &VersionedPage,
+    ) -> Result<(), StorageError<InMemoryPageId>> {
+        let mut page = *page;
+
+        page.set_visible_from(None);
+        page.set_visible_until(None);
+        page.set_next_version(None);
+        page.set_previous_version(None);
+
+        self.write_raw(index, must_cast(page))
+    }
+
+    #[instrument(skip(self))]
+    pub fn write_free(&self, index: PageIndex) -> Result<(), StorageError<InMemoryPageId>> {
+        let mut page = Page::new();
+        page.mark_free();
+
+        self.write_raw(index, page)
+    }
+
+    pub fn sync(&self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    // TODO can we avoid passing by value?
+    #[allow(clippy::large_types_passed_by_value)]
+    fn write_raw(&self, index: PageIndex, page: Page) -> Result<(), StorageError<InMemoryPageId>> {
+        debug!(?index, "writing page to the data file");
+
+        self.file
+            .write_all_at(&page.serialize(), Self::offset(index))?;
+
+        Ok(())
+    }
+
+    const fn offset(index: PageIndex) -> u64 {
+        index.value() * PAGE_SIZE.as_bytes() as u64
+    }
+}
@@ -0,0 +1,439 @@
+pub(crate) mod data_file;
+pub(crate) mod persistence;
+pub(crate) mod write_ahead_log;
+
+use std::path::Path;
+use std::time::Instant;
+
+use tracing::info;
+
+use crate::storage::file::persistence::Persistence;
+use crate::storage::in_memory::transaction::{InMemoryReadTransaction, InMemoryTransaction};
+use crate::storage::in_memory::version_manager::VersionedBlock;
+use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
+use crate::storage::in_memory::{InMemoryPageId, InMemoryPageReservation, InMemoryStorage};
+use crate::storage::{
+    Change, IsolationLevel, PageIndex, PageReservation, ReadTransaction, Savepoint, Storage,
+    StorageError, Transaction, TransactionId, TransactionalTimestamp, VersionedStorage,
+};
+use crate::sync::Arc;
+
+pub struct FilePageReservation<'storage>(InMemoryPageReservation<'storage>);
+
+impl<'storage> PageReservation<'storage> for FilePageReservation<'storage> {
+    type Storage = FileStorage;
+
+    fn index(&self) -> InMemoryPageId {
+        self.0.index()
+    }
+}
+
+#[derive(Debug)]
+pub struct FileTransaction<'storage>(InMemoryTransaction<'storage>);
+
+impl<'storage> ReadTransaction<'storage> for FileTransaction<'storage> {
+    type Storage = FileStorage;
+
+    fn id(&self) -> TransactionId {
+        self.0.id()
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.0.set_deadline(deadline);
+    }
+
+    fn read<T, const N: usize>(
+        &mut self,
+        indices: impl Into<[InMemoryPageId; N]>,
+        read: impl FnOnce([&VersionedPage; N]) -> T,
+    ) -> Result<T, StorageError<InMemoryPageId>> {
+        self.0.read(indices, read)
+    }
+
+    fn commit(self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.commit()
+    }
+
+    fn rollback(self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.rollback()
+    }
+}
+
+impl<'storage> Transaction<'storage> for FileTransaction<'storage> {
+    fn write<T, const N: usize>(
+        &mut self,
+        indices: impl Into<[InMemoryPageId; N]>,
+        write: impl FnOnce([&mut VersionedPage; N]) -> T,
+    ) -> Result<T, StorageError<InMemoryPageId>> {
+        self.0.write(indices, write)
+    }
+
+    fn reserve(&mut self) -> Result<FilePageReservation<'storage>, StorageError<InMemoryPageId>> {
+        Ok(FilePageReservation(self.0.reserve()?))
+    }
+
+    fn insert_reserved(
+        &mut self,
+        reservation: FilePageReservation<'storage>,
+        page: VersionedPage,
+    ) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.insert_reserved(reservation.0, page)
+    }
+
+    fn insert(
+        &mut self,
+        page: VersionedPage,
+    ) -> Result<InMemoryPageId, StorageError<InMemoryPageId>> {
+        self.0.insert(page)
+    }
+
+    fn delete(&mut self, page: InMemoryPageId) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.delete(page)
+    }
+
+    fn savepoint(&mut self) -> Savepoint {
+        self.0.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.rollback_to(savepoint)
+    }
+
+    fn captures_changes(&self) -> bool {
+        self.0.captures_changes()
+    }
+
+    fn record_change(&mut self, change: Change) {
+        self.0.record_change(change);
+    }
+}
+
+#[derive(Debug)]
+pub struct FileReadTransaction<'storage>(InMemoryReadTransaction<'storage>);
+
+impl<'storage> ReadTransaction<'storage> for FileReadTransaction<'storage> {
+    type Storage = FileStorage;
+
+    fn id(&self) -> TransactionId {
+        self.0.id()
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.0.set_deadline(deadline);
+    }
+
+    fn read<T, const N: usize>(
+        &mut self,
+        indices: impl Into<[InMemoryPageId; N]>,
+        read: impl FnOnce([&VersionedPage; N]) -> T,
+    ) -> Result<T, StorageError<InMemoryPageId>> {
+        self.0.read(indices, read)
+    }
+
+    fn commit(self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.commit()
+    }
+
+    fn rollback(self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.0.rollback()
+    }
+}
+
+/// A storage backed by a single data file.
+///
+/// All the pages are loaded into memory when the file is opened, the in-memory copy is the one
+/// used by transactions. Every commit is first appended to a write-ahead log (kept next to the
+/// data file, with a `.wal` suffix), and only then are the modified pages written to the data
+/// file.
+#[derive(Debug)]
+pub struct FileStorage {
+    inner: InMemoryStorage,
+    persistence: Arc<Persistence>,
+}
+
+impl FileStorage {
+    /// Opens the data file at `path`, creating an empty one if it does not exist yet. If the
+    /// storage wasn't closed cleanly, the commits that made it to the write-ahead log are
+    /// recovered first.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError<InMemoryPageId>> {
+        let persistence = Arc::new(Persistence::open(path.as_ref())?);
+        let data_file = persistence.data_file();
+        let block = Arc::new(VersionedBlock::new());
+
+        let page_count = data_file.page_count()?;
+
+        for index in 0..page_count {
+            let page = data_file.read_page(PageIndex::from_value(index))?;
+            let loaded_index = block.load_page(page)?;
+
+            assert!(loaded_index == PageIndex::from_value(index));
+        }
+
+        info!(?page_count, path = ?path.as_ref(), "loaded the data file");
+
+        Ok(Self {
+            inner: InMemoryStorage::with_durability(block, persistence.clone()),
+            persistence,
+        })
+    }
+
+    /// Flushes the data file to the disk and empties the write-ahead log.
+    pub fn checkpoint(&self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.persistence.checkpoint()
+    }
+
+    /// The in-memory copy of the pages, which has all the settings and the statistics of the
+    /// storage. Only what happened since the file was opened is kept in memory, so e.g. the
+    /// versions or the changes committed before that can't be read.
+    #[must_use]
+    pub const fn in_memory(&self) -> &InMemoryStorage {
+        &self.inner
+    }
+}
+
+impl Storage for FileStorage {
+    type Page = VersionedPage;
+    type PageId = InMemoryPageId;
+    type PageReservation<'a> = FilePageReservation<'a>;
+    type ReadTransaction<'a> = FileReadTransaction<'a>;
+    type Transaction<'a> = FileTransaction<'a>;
+
+    fn transaction_with_isolation(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<Self::Transaction<'_>, StorageError<Self::PageId>> {
+        Ok(FileTransaction(
+            self.inner.transaction_with_isolation(isolation)?,
+        ))
+    }
+
+    fn read_transaction(&self) -> Result<Self::ReadTransaction<'_>, StorageError<Self::PageId>> {
+        Ok(FileReadTransaction(self.inner.read_transaction()?))
+    }
+}
+
+impl VersionedStorage for FileStorage {
+    fn latest_commit(&self) -> Option<TransactionalTimestamp> {
+        self.inner.latest_commit()
+    }
+
+    fn transaction_as_of(
+        &self,
+        timestamp: TransactionalTimestamp,
+    ) -> Result<Self::ReadTransaction<'_>, StorageError<Self::PageId>> {
+        Ok(FileReadTransaction(
+            self.inner.transaction_as_of(timestamp)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::FileExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::bplustree::Tree;
+    use crate::bplustree::algorithms::insert::insert;
+    use crate::storage::in_memory::test_pages::{PageData, page_with};
+    use crate::storage::in_memory::version_manager::versioned_page::VERSIONED_PAGE_DATA_SIZE;
+    use crate::storage::{Page as _, PageId as _};
+
+    #[test]
+    fn pages_survive_reopening() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("data");
+
+        let (first, second, deleted) = {
+            let storage = FileStorage::open(&path).unwrap();
+            let mut transaction = storage.transaction().unwrap();
+
+            let first = transaction.insert(page_with(1)).unwrap();
+            let second = transaction.insert(page_with(2)).unwrap();
+            let deleted = transaction.insert(page_with(3)).unwrap();
+            transaction.commit().unwrap();
+
+            let mut transaction = storage.transaction().unwrap();
+            transaction
+                .write(second, |[page]| page.data_mut::<PageData>()[0] = 42)
+                .unwrap();
+            transaction.delete(deleted).unwrap();
+            transaction.commit().unwrap();
+
+            (first, second, deleted)
+        };
+
+        let storage = FileStorage::open(&path).unwrap();
+        let mut transaction = storage.transaction().unwrap();
+
+        let (first, second) = transaction
+            .read([first, second], |[first, second]| {
+                (*first.data::<PageData>(), *second.data::<PageData>())
+            })
+            .unwrap();
+
+        assert_eq!(first, [1; _]);
+        assert_eq!(second[0], 42);
+        assert_eq!(second[1..], [2; VERSIONED_PAGE_DATA_SIZE.as_bytes() - 1]);
+
+        assert!(
+            storage
+                .persistence
+                .data_file()
+                .read_page(PageIndex::from_value(u64::from_le_bytes(
+                    deleted.serialize().raw()
+                )))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rolled_back_changes_are_not_persisted() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("data");
+
+        let index = {
+            let storage = FileStorage::open(&path).unwrap();
+            let mut transaction = storage.transaction().unwrap();
+            let index = transaction.insert(page_with(1)).unwrap();
+            transaction.commit().unwrap();
+
+            let mut transaction = storage.transaction().unwrap();
+            transaction
+                .write(index, |[page]| page.data_mut::<PageData>()[0] = 42)
+                .unwrap();
+            transaction.rollback().unwrap();
+
+            index
+        };
+
+        let storage = FileStorage::open(&path).unwrap();
+        let mut transaction = storage.transaction().unwrap();
+
+        assert_eq!(
+            transaction
+                .read(index, |[page]| *page.data::<PageData>())
+                .unwrap(),
+            [1; _]
+        );
+    }
+
+    #[test]
+    fn corrupted_page_is_reported() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("data");
+
+        {
+            let storage = FileStorage::open(&path).unwrap();
+            let mut transaction = storage.transaction().unwrap();
+            transaction.insert(page_with(1)).unwrap();
+            transaction.commit().unwrap();
+
+            // otherwise the page would just get restored from the log
+            storage.checkpoint().unwrap();
+        }
+
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .write_all_at(&[0xff; 16], 1024)
+            .unwrap();
+
+        assert_eq!(
+            FileStorage::open(&path).unwrap_err(),
+            StorageError::Corrupted(InMemoryPageId::first())
+        );
+    }
+
+    #[test]
+    fn recovers_the_last_complete_commit() {
+        const COMMIT_COUNT: u8 = 5;
+
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("data");
+
+        let index = {
+            let storage = FileStorage::open(&path).unwrap();
+            let mut transaction = storage.transaction().unwrap();
+            let index = transaction.insert(page_with(1)).unwrap();
+            transaction.commit().unwrap();
+
+            for value in 2..=COMMIT_COUNT {
+                let mut transaction = storage.transaction().unwrap();
+                transaction
+                    .write(index, |[page]| page.data_mut::<PageData>()[0] = value)
+                    .unwrap();
+                transaction.commit().unwrap();
+            }
+
+            index
+        };
+
+        let log = fs::read(directory.path().join("data.wal")).unwrap();
+        let record_size = log.len() / usize::from(COMMIT_COUNT);
+        assert_eq!(log.len(), record_size * usize::from(COMMIT_COUNT));
+
+        // a crash could have happened at any point while the log was being written, the data file
+        // is empty, as if none of the writes to it made it to the disk
+        for length in (0..=log.len())
+            .step_by(509)
+            .chain([record_size - 1, record_size])
+        {
+            let directory = TempDir::new().unwrap();
+            let path = directory.path().join("data");
+            fs::write(directory.path().join("data.wal"), &log[..length]).unwrap();
+
+            let storage = FileStorage::open(&path).unwrap();
+            let complete_commits = length / record_size;
+
+            assert_eq!(
+                storage.persistence.data_file().page_count().unwrap(),
+                u64::from(complete_commits > 0)
+            );
+
+            if complete_commits > 0 {
+                let mut transaction = storage.transaction().unwrap();
+
+                assert_eq!(
+                    transaction
+                        .read(index, |[page]| page.data::<PageData>()[0])
+                        .unwrap(),
+                    u8::try_from(complete_commits).unwrap()
+                );
+            }
+
+            assert_eq!(
+                fs::metadata(directory.path().join("data.wal"))
+                    .unwrap()
+                    .len(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn tree_on_file_storage() {
+        let directory = TempDir::new().unwrap();
+        let storage = FileStorage::open(directory.path().join("data")).unwrap();
+        let tree = Tree::<_, u64>::new(storage).unwrap();
+
+        let mut transaction = tree.transaction().unwrap();
+        for i in 0..1000u64 {
+            insert(&mut transaction, i, &i.to_le_bytes()).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        let result = tree.iter().unwrap().map(|x| x.unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(
+            result,
+            (0..1000u64)
+                .map(|i| (i, i.to_le_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, info, instrument};
+
+use crate::Size;
+use crate::storage::StorageError;
+use crate::storage::file::data_file::DataFile;
+use crate::storage::file::write_ahead_log::WriteAheadLog;
+use crate::storage::in_memory::InMemoryPageId;
+use crate::storage::in_memory::durability::{Durability, PageImage};
+
+/// Once the log grows past this size, the data file gets synced and the log truncated.
+const CHECKPOINT_THRESHOLD: Size = Size::MiB(64);
+
+/// The on-disk state of a `FileStorage` - the data file and the write-ahead log in front of it.
+///
+/// A commit is durable once its record is in the log, the writes to the data file can be lost in a
+/// crash, as they will be redone from the log on the next start.
+#[derive(Debug)]
+pub struct Persistence {
+    data_file: DataFile,
+    log: WriteAheadLog,
+}
+
+impl Persistence {
+    /// Opens the data file and the log next to it, and replays the log into the data file.
+    pub fn open(path: &Path) -> Result<Self, StorageError<InMemoryPageId>> {
+        let persistence = Self {
+            data_file: DataFile::open(path)?,
+            log: WriteAheadLog::open(&Self::log_path(path))?,
+        };
+
+        persistence.recover()?;
+
+        Ok(persistence)
+    }
+
+    pub const fn data_file(&self) -> &DataFile {
+        &self.data_file
+    }
+
+    /// Syncs the data file, after which the records in the log are no longer needed.
+    #[instrument(skip(self))]
+    pub fn checkpoint(&self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.data_file.sync()?;
+        self.log.truncate()?;
+
+        debug!("checkpoint completed");
+
+        Ok(())
+    }
+
+    fn recover(&self) -> Result<(), StorageError<InMemoryPageId>> {
+        if self.log.length() == 0 {
+            return Ok(());
+        }
+
+        let records = self.log.replay()?;
+
+        info!(
+            record_count = records.len(),
+            "recovering from the write-ahead log"
+        );
+
+        for record in &records {
+            self.write(record)?;
+        }
+
+        // this also drops whatever partial record was left at the end of the log
+        self.checkpoint()
+    }
+
+    fn write(&self, pages: &[PageImage]) -> Result<(), StorageError<InMemoryPageId>> {
+        for image in pages {
+            match image.page() {
+                Some(page) => self.data_file.write_page(image.index(), page)?,
+                None => self.data_file.write_free(image.index())?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn log_path(path: &Path) -> PathBuf {
+        let mut log_path = path.as_os_str().to_owned();
+        log_path.push(".wal");
+
+        log_path.into()
+    }
+}
+
+impl Durability for Persistence {
+    /// Appends the commits to the log.
+    fn log(&self, commits: &[Vec<PageImage>]) -> Result<(), StorageError<InMemoryPageId>> {
+        self.log.append(commits)
+    }
+
+    /// Writes the commits, which are already in the log, to the data file.
+    fn apply(&self, commits: &[Vec<PageImage>]) -> Result<(), StorageError<InMemoryPageId>> {
+        for pages in commits {
+            self.write(pages)?;
+        }
+
+        if self.log.length() > CHECKPOINT_THRESHOLD.as_bytes() as u64 {
+            self.checkpoint()?;
+        }
+
+        Ok(())
+    }
+}
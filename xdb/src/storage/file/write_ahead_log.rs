@@ -0,0 +1,206 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use bytemuck::{Pod, Zeroable, bytes_of, bytes_of_mut, pod_read_unaligned};
+use tracing::{debug, error, instrument, warn};
+
+use crate::checksum::Checksum;
+use crate::storage::in_memory::InMemoryPageId;
+use crate::storage::in_memory::durability::PageImage;
+use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
+use crate::storage::{PageIndex, StorageError};
+use crate::sync::Mutex;
+
+bitflags::bitflags! {
+    #[derive(Debug, Pod, Zeroable, Clone, Copy)]
+    #[repr(transparent)]
+    struct LogEntryFlags: u64 {
+        const DELETED = 1 << 0;
+    }
+}
+
+#[derive(Debug, Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+struct RecordHeader {
+    checksum: Checksum,
+    _unused: u32,
+    entry_count: u64,
+}
+
+#[derive(Debug, Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+struct LogEntry {
+    index: PageIndex,
+    flags: LogEntryFlags,
+    page: VersionedPage,
+}
+
+/// An append-only log of committed page images.
+///
+/// Each commit is a single record - a header with the checksum of the whole record, followed by
+/// the images of all the pages modified by the commit. A record that was only partially written
+/// (e.g. because of a crash) fails the checksum check, and everything from there on is ignored.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+    length: Mutex<u64>,
+}
+
+impl WriteAheadLog {
+    pub fn open(path: &Path) -> Result<Self, StorageError<InMemoryPageId>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let length = file.metadata()?.len();
+
+        Ok(Self {
+            file,
+            length: Mutex::new(length),
+        })
+    }
+
+    pub fn length(&self) -> u64 {
+        *self.length.lock().unwrap()
+    }
+
+    /// Appends a record for each of the commits and waits for all of them to reach the disk with a
+    /// single sync. Once this returns, the commits are durable.
+    #[instrument(skip(self, records), fields(record_count = records.len()))]
+    pub fn append(&self, records: &[Vec<PageImage>]) -> Result<(), StorageError<InMemoryPageId>> {
+        let record: Vec<u8> = records.iter().flat_map(|x| Self::record(x)).collect();
+        let mut length = self.length.lock().unwrap();
+
+        let result = self
+            .file
+            .write_all_at(&record, *length)
+            .and_then(|()| self.file.sync_data());
+
+        if let Err(e) = result {
+            // a partial record would hide all the records that come after it on replay
+            if let Err(truncate_error) = self.file.set_len(*length) {
+                error!(?e, ?truncate_error, "failed to remove a partial record");
+                panic!(
+                    "failed to remove a partial record from the write-ahead log: {truncate_error}"
+                );
+            }
+
+            return Err(e.into());
+        }
+
+        *length += record.len() as u64;
+
+        debug!(length = *length, "records appended");
+
+        Ok(())
+    }
+
+    /// Reads all the complete records, in the order they were appended.
+    #[instrument(skip(self))]
+    pub fn replay(&self) -> Result<Vec<Vec<PageImage>>, StorageError<InMemoryPageId>> {
+        let length = self.length();
+        let mut offset = 0;
+        let mut records = vec![];
+
+        while offset + size_of::<RecordHeader>() as u64 <= length {
+            let mut header = RecordHeader::zeroed();
+            self.file.read_exact_at(bytes_of_mut(&mut header), offset)?;
+
+            let Some(record_size) = usize::try_from(header.entry_count)
+                .ok()
+                .and_then(|x| x.checked_mul(size_of::<LogEntry>()))
+                .and_then(|x| x.checked_add(size_of::<RecordHeader>()))
+                .filter(|x| offset + *x as u64 <= length)
+            else {
+                warn!(offset, "incomplete record at the end of the log");
+                break;
+            };
+
+            let mut record = vec![0; record_size];
+            self.file.read_exact_at(&mut record, offset)?;
+
+            if Self::checksum(&mut record) != header.checksum {
+                warn!(
+                    offset,
+                    "record with an invalid checksum at the end of the log"
+                );
+                break;
+            }
+
+            records.push(
+                record[size_of::<RecordHeader>()..]
+                    .chunks_exact(size_of::<LogEntry>())
+                    .map(|x| {
+                        let entry: LogEntry = pod_read_unaligned(x);
+
+                        PageImage::new(
+                            entry.index,
+                            if entry.flags.contains(LogEntryFlags::DELETED) {
+                                None
+                            } else {
+                                Some(&entry.page)
+                            },
+                        )
+                    })
+                    .collect(),
+            );
+
+            offset += record_size as u64;
+        }
+
+        debug!(record_count = records.len(), "replayed the log");
+
+        Ok(records)
+    }
+
+    /// Drops all the records. This must only be called once all of them made it to the data file.
+    pub fn truncate(&self) -> Result<(), StorageError<InMemoryPageId>> {
+        let mut length = self.length.lock().unwrap();
+
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+
+        *length = 0;
+
+        Ok(())
+    }
+
+    fn record(pages: &[PageImage]) -> Vec<u8> {
+        let header = RecordHeader {
+            checksum: Checksum::zeroed(),
+            _unused: 0,
+            entry_count: pages.len() as u64,
+        };
+
+        let mut record = bytes_of(&header).to_vec();
+
+        for image in pages {
+            let entry = LogEntry {
+                index: image.index(),
+                flags: if image.page().is_some() {
+                    LogEntryFlags::empty()
+                } else {
+                    LogEntryFlags::DELETED
+                },
+                page: image.page().copied().unwrap_or_else(VersionedPage::zeroed),
+            };
+
+            record.extend_from_slice(bytes_of(&entry));
+        }
+
+        let checksum = Self::checksum(&mut record);
+        record[..size_of::<Checksum>()].copy_from_slice(bytes_of(&checksum));
+
+        record
+    }
+
+    /// Calculates the checksum of the record, with the checksum field itself zeroed out.
+    fn checksum(record: &mut [u8]) -> Checksum {
+        record[..size_of::<Checksum>()].fill(0);
+
+        Checksum::of(record)
+    }
+}
@@ -346,7 +346,8 @@ impl Debug for Block {
 impl Block {
     const HOUSEKEEPING_BLOCK_SIZE: Size = Size::of::<PageState>().multiply(Self::PAGE_COUNT);
     const PAGE_COUNT: usize = Self::SIZE.divide(PAGE_SIZE);
-    const SIZE: Size = if cfg!(miri) {
+    // the tests need to be able to run out of space
+    const SIZE: Size = if cfg!(any(miri, test)) {
         Size::MiB(128)
     } else {
         Size::GiB(4)
@@ -370,6 +371,10 @@ impl Block {
         self.allocated_page_count.load(Ordering::Acquire)
     }
 
+    pub const fn page_count() -> u64 {
+        Self::PAGE_COUNT as u64
+    }
+
     #[instrument]
     pub fn get(&self, physical_index: PageIndex) -> PageReadGuard<'_> {
         let latest_initialized_page = self.allocated_page_count.load(Ordering::Acquire);
@@ -459,16 +464,41 @@ impl Block {
         Ok(self.get(physical_index).upgrade())
     }
 
-    #[instrument]
-    // TODO this method is almost all copy-paste with get, clean up
-    pub fn try_get(&'_ self, physical_index: PageIndex) -> Option<PageReadGuard<'_>> {
-        let allocated_page_count = self.allocated_page_count.load(Ordering::Acquire);
+    /// Gives the memory of a page that is not initialized back to the OS. It must be recommitted
+    /// before it's initialized again.
+    pub fn decommit(&self, physical_index: PageIndex) {
+        assert!(physical_index.0 < self.allocated_page_count.load(Ordering::Acquire));
+        assert!(!self.housekeeping_for(physical_index).initialized());
 
-        if physical_index.0 >= allocated_page_count {
-            return None;
+        self.data
+            .decommit_page(self.data_page(physical_index).cast());
+    }
+
+    pub fn recommit(&self, physical_index: PageIndex) {
+        assert!(physical_index.0 < self.allocated_page_count.load(Ordering::Acquire));
+
+        self.data.commit_page(self.data_page(physical_index).cast());
+    }
+
+    fn data_page(&self, physical_index: PageIndex) -> NonNull<Page> {
+        unsafe {
+            self.data
+                .base_address()
+                .cast::<Page>()
+                .add(physical_index.0.try_into().unwrap())
         }
+    }
+
+    /// Returns true if the page was allocated and initialized, i.e. it's safe to call `get` for it.
+    pub fn contains(&self, physical_index: PageIndex) -> bool {
+        physical_index.0 < self.allocated_page_count.load(Ordering::Acquire)
+            && self.housekeeping_for(physical_index).initialized()
+    }
 
-        if !self.housekeeping_for(physical_index).initialized() {
+    #[instrument]
+    // TODO this method is almost all copy-paste with get, clean up
+    pub fn try_get(&'_ self, physical_index: PageIndex) -> Option<PageReadGuard<'_>> {
+        if !self.contains(physical_index) {
             return None;
         }
 
@@ -551,13 +581,7 @@ impl Block {
         };
         self.housekeeping.commit_page(houskeeping_page.cast());
 
-        let page = unsafe {
-            self.data
-                .base_address()
-                .cast::<Page>()
-                .add(index.0.try_into().unwrap())
-        };
-        self.data.commit_page(page.cast());
+        self.data.commit_page(self.data_page(index).cast());
 
         let page_state = unsafe {
             self.housekeeping
@@ -0,0 +1,41 @@
+use std::fmt::Debug;
+
+use crate::storage::in_memory::InMemoryPageId;
+use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
+use crate::storage::{PageIndex, StorageError};
+
+/// The state of a single logical page after a commit.
+#[derive(Debug, Clone, Copy)]
+pub struct PageImage {
+    index: PageIndex,
+    // None means that the logical page was deleted
+    page: Option<VersionedPage>,
+}
+
+impl PageImage {
+    pub const fn new(index: PageIndex, page: Option<&VersionedPage>) -> Self {
+        Self {
+            index,
+            page: page.copied(),
+        }
+    }
+
+    pub const fn index(&self) -> PageIndex {
+        self.index
+    }
+
+    pub const fn page(&self) -> Option<&VersionedPage> {
+        self.page.as_ref()
+    }
+}
+
+/// Keeps the commits of an `InMemoryStorage` somewhere they survive a restart. The committer hands
+/// it each batch of commits, as the list of pages each of them modified.
+pub trait Durability: Debug + Send + Sync {
+    /// Makes the commits durable. Called before the commits are visible to other transactions, if
+    /// it fails, none of them are.
+    fn log(&self, commits: &[Vec<PageImage>]) -> Result<(), StorageError<InMemoryPageId>>;
+
+    /// Called with the commits passed to `log`, once they're visible.
+    fn apply(&self, commits: &[Vec<PageImage>]) -> Result<(), StorageError<InMemoryPageId>>;
+}
@@ -1,15 +1,27 @@
 mod bitmap;
 mod block;
-mod transaction;
+pub(crate) mod durability;
+#[cfg(test)]
+pub(crate) mod test_pages;
+pub(crate) mod transaction;
 pub(crate) mod version_manager;
 
+use std::time::Duration;
+
 use bytemuck::Zeroable;
+pub use version_manager::change_feed::{ChangeCapture, ChangeEvent, ChangeSubscription};
+pub use version_manager::transaction_log::{Retention, RunningTransaction, TransactionTimeouts};
+pub use version_manager::{BatchMetrics, CommitMetrics, GroupCommit, VacuumReport, VacuumSchedule};
 
 use crate::storage::in_memory::bitmap::Bitmap;
-use crate::storage::in_memory::transaction::InMemoryTransaction;
+use crate::storage::in_memory::durability::Durability;
+use crate::storage::in_memory::transaction::{InMemoryReadTransaction, InMemoryTransaction};
 use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
 use crate::storage::in_memory::version_manager::{VersionManager, VersionedBlock};
-use crate::storage::{PageId, PageIndex, PageReservation, SerializedPageId, Storage, StorageError};
+use crate::storage::{
+    IsolationLevel, PageId, PageIndex, PageReservation, SerializedPageId, Storage, StorageError,
+    TransactionalTimestamp, VersionedStorage,
+};
 use crate::sync::Arc;
 
 // TODO impl Drop to return the page to free pool if it doesn't get written
@@ -78,18 +90,645 @@ impl InMemoryStorage {
     // multiple storages?
     pub fn new() -> Self {
         Self {
-            version_manager: VersionManager::new(Arc::new(VersionedBlock::new())),
+            version_manager: VersionManager::new(Arc::new(VersionedBlock::new()), None),
+        }
+    }
+
+    /// Creates a storage that serves as the in-memory cache for pages kept elsewhere - the block
+    /// must already contain the pages loaded from there, and every commit is passed to
+    /// `durability`.
+    pub(crate) fn with_durability(
+        block: Arc<VersionedBlock>,
+        durability: Arc<dyn Durability>,
+    ) -> Self {
+        Self {
+            version_manager: VersionManager::new(block, Some(durability)),
         }
     }
+
+    /// Sets which versions vacuum keeps around for `transaction_as_of`, besides the ones the
+    /// running transactions need.
+    pub fn set_retention(&self, retention: Retention) {
+        self.version_manager.set_retention(retention);
+    }
+
+    /// Sets how the queued commits get grouped into batches.
+    pub fn set_group_commit(&self, group_commit: GroupCommit) {
+        self.version_manager.set_group_commit(group_commit);
+    }
+
+    #[must_use]
+    pub fn commit_metrics(&self) -> CommitMetrics {
+        self.version_manager.commit_metrics()
+    }
+
+    /// Sets when the long-running transactions get reported or aborted. The checks happen
+    /// whenever vacuum runs.
+    pub fn set_transaction_timeouts(&self, timeouts: TransactionTimeouts) {
+        self.version_manager.set_transaction_timeouts(timeouts);
+    }
+
+    /// Lists the transactions that are keeping vacuum from removing the old versions.
+    #[must_use]
+    pub fn running_transactions(&self) -> Vec<RunningTransaction> {
+        self.version_manager.running_transactions()
+    }
+
+    /// Sets how long the transactions wait for vacuum to free some pages once the storage runs
+    /// out of space, before failing with `StorageError::OutOfSpace`. By default, they wait
+    /// forever.
+    pub fn set_allocation_timeout(&self, timeout: Option<Duration>) {
+        self.version_manager.set_allocation_timeout(timeout);
+    }
+
+    /// Sets how often vacuum runs, and how soon it runs once the allocations start running out
+    /// of free pages.
+    pub fn set_vacuum_schedule(&self, schedule: VacuumSchedule) {
+        self.version_manager.set_vacuum_schedule(schedule);
+    }
+
+    /// Runs vacuum over all the pages right away, and waits for it to finish. Unlike the scheduled
+    /// runs, it also removes the old versions when there are no running transactions. Only the
+    /// versions the running transactions or the retention still need are kept, along with the
+    /// ones on pages that were locked at the time.
+    pub fn vacuum_now(&self) -> VacuumReport {
+        self.version_manager.vacuum_now()
+    }
+
+    /// Sets how many of the changes made by the trees in the storage are kept for the
+    /// subscribers. Only the transactions started after the capture is turned on are captured, so
+    /// the ones that started before it and modify pages fail to commit with
+    /// `StorageError::UncapturedChanges`, unless they committed before the capture was turned on.
+    pub fn set_change_capture(&self, capture: ChangeCapture) {
+        self.version_manager.set_change_capture(capture);
+    }
+
+    /// Starts receiving the changes committed after `after` (or all the captured changes, if it's
+    /// `None`). Fails with `StorageError::ChangesUnavailable` if some of those changes were
+    /// already dropped from the buffer, or never captured. The changes are published after their
+    /// commits return, so they might not be received right away.
+    pub fn subscribe(
+        &self,
+        after: Option<TransactionalTimestamp>,
+    ) -> Result<ChangeSubscription, StorageError<InMemoryPageId>> {
+        self.version_manager.subscribe(after)
+    }
 }
 
 impl Storage for InMemoryStorage {
     type Page = VersionedPage;
     type PageId = InMemoryPageId;
     type PageReservation<'a> = InMemoryPageReservation<'a>;
+    type ReadTransaction<'a> = InMemoryReadTransaction<'a>;
     type Transaction<'a> = InMemoryTransaction<'a>;
 
-    fn transaction(&self) -> Result<Self::Transaction<'_>, StorageError<Self::PageId>> {
-        Ok(InMemoryTransaction::new(self))
+    fn transaction_with_isolation(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<Self::Transaction<'_>, StorageError<Self::PageId>> {
+        Ok(InMemoryTransaction::new(self, isolation))
+    }
+
+    fn read_transaction(&self) -> Result<Self::ReadTransaction<'_>, StorageError<Self::PageId>> {
+        Ok(InMemoryReadTransaction::new(self))
+    }
+}
+
+impl VersionedStorage for InMemoryStorage {
+    fn latest_commit(&self) -> Option<TransactionalTimestamp> {
+        self.version_manager.latest_commit()
+    }
+
+    fn transaction_as_of(
+        &self,
+        timestamp: TransactionalTimestamp,
+    ) -> Result<Self::ReadTransaction<'_>, StorageError<Self::PageId>> {
+        InMemoryReadTransaction::as_of(self, timestamp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::storage::in_memory::test_pages::{PageData, page_with};
+    use crate::storage::{Change, Page as _, ReadTransaction as _, Transaction as _};
+
+    #[test]
+    fn transactions_started_before_the_capture_cannot_modify_pages() {
+        let storage = InMemoryStorage::new();
+
+        let mut transaction = storage.transaction().unwrap();
+        let index = transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut reading = storage.transaction().unwrap();
+        reading.read(index, |_| ()).unwrap();
+
+        let mut writing = storage.transaction().unwrap();
+        writing
+            .write(index, |[page]| page.data_mut::<PageData>()[0] = 2)
+            .unwrap();
+        let id = writing.id();
+
+        storage.set_change_capture(ChangeCapture::default().with_buffer_size(1));
+
+        reading.commit().unwrap();
+        assert_eq!(writing.commit(), Err(StorageError::UncapturedChanges(id)));
+
+        let mut transaction = storage.transaction().unwrap();
+        assert_eq!(
+            transaction
+                .read(index, |[page]| page.data::<PageData>()[0])
+                .unwrap(),
+            1
+        );
+        transaction
+            .write(index, |[page]| page.data_mut::<PageData>()[0] = 2)
+            .unwrap();
+        transaction.commit().unwrap();
+    }
+
+    #[test]
+    fn commits_run_ahead_of_the_subscribers() {
+        let storage = InMemoryStorage::new();
+        storage.set_change_capture(ChangeCapture::default().with_buffer_size(1));
+        let mut subscription = storage.subscribe(None).unwrap();
+
+        for index in 0..3 {
+            let mut transaction = storage.transaction().unwrap();
+            transaction.record_change(Change::new(
+                SerializedPageId::new([index; 8]),
+                vec![index],
+                None,
+                None,
+            ));
+            transaction.commit().unwrap();
+        }
+
+        assert_eq!(
+            subscription
+                .by_ref()
+                .take(3)
+                .map(|x| x.change().key().to_vec())
+                .collect::<Vec<_>>(),
+            vec![vec![0], vec![1], vec![2]]
+        );
+    }
+
+    #[test]
+    fn concurrent_commits_are_grouped() {
+        const COMMIT_COUNT: u8 = 8;
+
+        let storage = InMemoryStorage::new();
+        storage.set_group_commit(
+            GroupCommit::default()
+                .with_max_batch_size(usize::from(COMMIT_COUNT))
+                .with_max_wait(Duration::from_millis(500)),
+        );
+
+        let indices = thread::scope(|scope| {
+            let handles = (0..COMMIT_COUNT)
+                .map(|value| {
+                    let storage = &storage;
+
+                    scope.spawn(move || {
+                        let mut transaction = storage.transaction().unwrap();
+                        let index = transaction.insert(page_with(value)).unwrap();
+                        transaction.commit().unwrap();
+
+                        (index, value)
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|x| x.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let metrics = storage.commit_metrics();
+        assert_eq!(metrics.committed(), u64::from(COMMIT_COUNT));
+        assert!(metrics.batches() < u64::from(COMMIT_COUNT));
+        assert!(metrics.largest_batch() > 1);
+
+        let mut transaction = storage.transaction().unwrap();
+
+        for (index, value) in indices {
+            assert_eq!(
+                transaction
+                    .read(index, |[page]| *page.data::<PageData>())
+                    .unwrap(),
+                [value; _]
+            );
+        }
+    }
+
+    #[test]
+    fn conflicting_commits_in_a_group() {
+        let storage = InMemoryStorage::new();
+
+        let mut transaction = storage.transaction().unwrap();
+        let index = transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        storage.set_group_commit(GroupCommit::default().with_max_wait(Duration::from_millis(500)));
+
+        let transactions = [2, 3].map(|value| {
+            let mut transaction = storage.transaction().unwrap();
+            transaction
+                .write(index, |[page]| page.data_mut::<PageData>()[0] = value)
+                .unwrap();
+
+            transaction
+        });
+
+        let results = thread::scope(|scope| {
+            transactions
+                .map(|transaction| scope.spawn(move || transaction.commit()))
+                .map(|x| x.join().unwrap())
+        });
+
+        assert_eq!(results.iter().filter(|x| x.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .any(|x| matches!(x, Err(StorageError::Conflict { .. })))
+        );
+
+        let metrics = storage.commit_metrics();
+        assert_eq!(metrics.requests(), 3);
+        assert_eq!(metrics.committed(), 2);
+    }
+
+    fn write_skew(
+        storage: &InMemoryStorage,
+        isolation: IsolationLevel,
+    ) -> Result<(), StorageError<InMemoryPageId>> {
+        let mut transaction = storage.transaction().unwrap();
+        let indices = [
+            transaction.insert(page_with(1)).unwrap(),
+            transaction.insert(page_with(1)).unwrap(),
+        ];
+        transaction.commit().unwrap();
+
+        // each transaction reads both pages, but only writes one of them
+        let [first, second] = [0, 1].map(|target| {
+            let mut transaction = storage.transaction_with_isolation(isolation).unwrap();
+
+            transaction
+                .read(indices, |pages| pages.map(|x| x.data::<PageData>()[0]))
+                .unwrap();
+            transaction
+                .write(indices[target], |[page]| page.data_mut::<PageData>()[0] = 0)
+                .unwrap();
+
+            transaction
+        });
+
+        first.commit().unwrap();
+        second.commit()
+    }
+
+    #[test]
+    fn snapshot_isolation_ignores_reads() {
+        let storage = InMemoryStorage::new();
+
+        write_skew(&storage, IsolationLevel::Snapshot).unwrap();
+    }
+
+    #[test]
+    fn serializable_isolation_validates_reads() {
+        let storage = InMemoryStorage::new();
+
+        assert!(matches!(
+            write_skew(&storage, IsolationLevel::Serializable),
+            Err(StorageError::SerializationFailure { .. })
+        ));
+    }
+
+    #[test]
+    fn writing_a_concurrently_deleted_page_conflicts() {
+        let storage = InMemoryStorage::new();
+
+        let mut transaction = storage.transaction().unwrap();
+        let index = transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut writing = storage.transaction().unwrap();
+        writing
+            .write(index, |[page]| page.data_mut::<PageData>()[0] = 2)
+            .unwrap();
+
+        let mut deleting = storage.transaction().unwrap();
+        deleting.delete(index).unwrap();
+        deleting.commit().unwrap();
+
+        assert!(matches!(
+            writing.commit(),
+            Err(StorageError::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn rollback_to_savepoint() {
+        let storage = InMemoryStorage::new();
+
+        let mut transaction = storage.transaction().unwrap();
+        let updated = transaction.insert(page_with(1)).unwrap();
+        let deleted = transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = storage.transaction().unwrap();
+        let inserted = transaction.insert(page_with(2)).unwrap();
+        transaction
+            .write(updated, |[page]| page.data_mut::<PageData>()[0] = 2)
+            .unwrap();
+
+        let savepoint = transaction.savepoint();
+
+        transaction
+            .write([updated, inserted], |pages| {
+                for page in pages {
+                    page.data_mut::<PageData>()[0] = 3;
+                }
+            })
+            .unwrap();
+        transaction.insert(page_with(3)).unwrap();
+        transaction.delete(deleted).unwrap();
+        transaction.delete(inserted).unwrap();
+
+        transaction.rollback_to(savepoint).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = storage.read_transaction().unwrap();
+        assert_eq!(
+            transaction
+                .read([updated, deleted, inserted], |pages| pages
+                    .map(|x| x.data::<PageData>()[0]))
+                .unwrap(),
+            [2, 1, 2]
+        );
+    }
+
+    #[test]
+    fn transactions_past_their_deadline_time_out() {
+        let storage = InMemoryStorage::new();
+
+        let mut transaction = storage.transaction().unwrap();
+        let index = transaction.insert(page_with(1)).unwrap();
+        transaction.set_deadline(Instant::now());
+
+        assert!(matches!(
+            transaction.read(index, |_| ()),
+            Err(StorageError::TimedOut(_))
+        ));
+        assert!(matches!(
+            transaction.write(index, |_| ()),
+            Err(StorageError::TimedOut(_))
+        ));
+        assert!(matches!(
+            transaction.commit(),
+            Err(StorageError::TimedOut(_))
+        ));
+        assert_eq!(storage.latest_commit(), None);
+    }
+
+    #[test]
+    fn long_running_transactions_are_aborted() {
+        let storage = InMemoryStorage::new();
+
+        let mut transaction = storage.transaction().unwrap();
+        let index = transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut forgotten = storage.read_transaction().unwrap();
+        forgotten.read(index, |_| ()).unwrap();
+
+        assert_eq!(
+            storage
+                .running_transactions()
+                .iter()
+                .map(RunningTransaction::id)
+                .collect::<Vec<_>>(),
+            vec![forgotten.id()]
+        );
+
+        storage.set_transaction_timeouts(
+            TransactionTimeouts::default().with_abort_after(Duration::from_millis(1)),
+        );
+
+        while !storage.running_transactions().is_empty() {
+            thread::yield_now();
+        }
+
+        assert!(matches!(
+            forgotten.read(index, |_| ()),
+            Err(StorageError::TimedOut(id)) if id == forgotten.id()
+        ));
+    }
+
+    #[test]
+    fn resuming_changes_from_a_timestamp() {
+        let storage = InMemoryStorage::new();
+
+        assert_eq!(
+            storage.subscribe(None).unwrap_err(),
+            StorageError::ChangesUnavailable(None)
+        );
+
+        let mut transaction = storage.transaction().unwrap();
+        transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        storage.set_change_capture(ChangeCapture::default().with_buffer_size(2));
+
+        // the commits from before the capture was turned on were not captured
+        assert_eq!(
+            storage.subscribe(None).unwrap_err(),
+            StorageError::ChangesUnavailable(None)
+        );
+
+        let after = storage.latest_commit();
+        let tree = |index: u8| SerializedPageId::new([index; 8]);
+        let mut commits = vec![];
+        // holds back the changes until they're all published, and then lets the first one go
+        let mut everything = storage.subscribe(after).unwrap();
+
+        for index in 0..3 {
+            let mut transaction = storage.transaction().unwrap();
+            transaction.record_change(Change::new(tree(index), vec![index], None, None));
+            transaction.commit().unwrap();
+
+            commits.push(storage.latest_commit());
+        }
+
+        assert_eq!(everything.by_ref().take(3).count(), 3);
+        drop(everything);
+
+        // the first change was dropped to make space for the later ones
+        assert_eq!(
+            storage.subscribe(after).unwrap_err(),
+            StorageError::ChangesUnavailable(after)
+        );
+
+        let mut transaction = storage.transaction().unwrap();
+        transaction.record_change(Change::new(tree(3), vec![3], None, None));
+        transaction.rollback().unwrap();
+
+        let mut subscription = storage.subscribe(commits[0]).unwrap();
+        assert_eq!(
+            subscription.recv().map(|x| x.change().tree()),
+            Some(tree(1))
+        );
+        assert_eq!(
+            subscription.try_recv().map(|x| x.change().tree()),
+            Some(tree(2))
+        );
+        assert_eq!(subscription.try_recv(), None);
+    }
+
+    #[test]
+    fn vacuum_now_removes_the_old_versions() {
+        let storage = InMemoryStorage::new();
+
+        // keep the scheduled runs out of the way
+        storage.set_vacuum_schedule(
+            VacuumSchedule::default()
+                .with_interval(Duration::from_hours(1))
+                .with_idle_interval(Duration::from_hours(1)),
+        );
+        storage.vacuum_now();
+
+        let mut transaction = storage.transaction().unwrap();
+        let updated = transaction.insert(page_with(1)).unwrap();
+        let deleted = transaction.insert(page_with(1)).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = storage.transaction().unwrap();
+        transaction
+            .write(updated, |[page]| page.data_mut::<PageData>()[0] = 2)
+            .unwrap();
+        transaction.delete(deleted).unwrap();
+        transaction.commit().unwrap();
+
+        let blocking = storage.read_transaction().unwrap();
+
+        let mut transaction = storage.transaction().unwrap();
+        transaction
+            .write(updated, |[page]| page.data_mut::<PageData>()[0] = 3)
+            .unwrap();
+        transaction.commit().unwrap();
+
+        // the second update is still visible to the blocking transaction
+        let report = storage.vacuum_now();
+        assert_eq!(report.chains_shortened(), 1);
+        assert_eq!(report.freed(), 2);
+        assert_eq!(
+            report.oldest_blocking().map(|x| x.id()),
+            Some(blocking.id())
+        );
+
+        drop(blocking);
+
+        let report = storage.vacuum_now();
+        assert_eq!(report.chains_shortened(), 1);
+        assert_eq!(report.freed(), 1);
+        assert_eq!(report.oldest_blocking(), None);
+
+        let mut transaction = storage.read_transaction().unwrap();
+        assert_eq!(
+            transaction
+                .read(updated, |[page]| page.data::<PageData>()[0])
+                .unwrap(),
+            3
+        );
+    }
+
+    /// Inserts pages until the storage runs out of space, and commits them.
+    fn fill(storage: &InMemoryStorage) -> Vec<InMemoryPageId> {
+        let mut transaction = storage.transaction().unwrap();
+        let mut indices = vec![];
+
+        loop {
+            match transaction.insert(page_with(1)) {
+                Ok(index) => indices.push(index),
+                Err(StorageError::OutOfSpace) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+
+        transaction.commit().unwrap();
+
+        indices
+    }
+
+    #[test]
+    fn allocations_give_up_after_the_timeout() {
+        let storage = InMemoryStorage::new();
+        storage.set_allocation_timeout(Some(Duration::ZERO));
+        fill(&storage);
+
+        storage.set_allocation_timeout(Some(Duration::from_millis(100)));
+
+        let mut transaction = storage.transaction().unwrap();
+        let started = Instant::now();
+
+        assert_eq!(
+            transaction.insert(page_with(2)).unwrap_err(),
+            StorageError::OutOfSpace
+        );
+
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(100));
+        assert!(waited < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn allocations_wait_for_vacuum_to_free_a_page() {
+        let storage = InMemoryStorage::new();
+        storage.set_allocation_timeout(Some(Duration::ZERO));
+        let indices = fill(&storage);
+
+        // keeps the deleted pages visible, so vacuum can't free them yet
+        let mut blocking = storage.read_transaction().unwrap();
+        blocking.read(indices[0], |_| ()).unwrap();
+
+        let mut transaction = storage.transaction().unwrap();
+        for index in &indices[..10] {
+            transaction.delete(*index).unwrap();
+        }
+        transaction.commit().unwrap();
+
+        storage.set_allocation_timeout(None);
+
+        thread::scope(|scope| {
+            let allocating = scope.spawn(|| {
+                let mut transaction = storage.transaction().unwrap();
+                let index = transaction.insert(page_with(2)).unwrap();
+                transaction.commit().unwrap();
+
+                index
+            });
+
+            thread::sleep(Duration::from_millis(100));
+            assert!(!allocating.is_finished());
+
+            drop(blocking);
+            storage.vacuum_now();
+
+            let index = allocating.join().unwrap();
+
+            let mut transaction = storage.read_transaction().unwrap();
+            assert_eq!(
+                transaction
+                    .read(index, |[page]| page.data::<PageData>()[0])
+                    .unwrap(),
+                2
+            );
+        });
     }
 }
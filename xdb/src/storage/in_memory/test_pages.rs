@@ -0,0 +1,11 @@
+use crate::storage::Page as _;
+use crate::storage::in_memory::version_manager::versioned_page::{
+    VERSIONED_PAGE_DATA_SIZE, VersionedPage,
+};
+
+pub type PageData = [u8; VERSIONED_PAGE_DATA_SIZE.as_bytes()];
+
+/// A page with every byte of the data set to `value`.
+pub fn page_with(value: u8) -> VersionedPage {
+    VersionedPage::from_data::<PageData>([value; _])
+}
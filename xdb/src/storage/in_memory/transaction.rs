@@ -1,7 +1,14 @@
-use crate::storage::in_memory::version_manager::transaction::VersionManagedTransaction;
+use std::time::Instant;
+
+use crate::storage::in_memory::version_manager::transaction::{
+    VersionManagedReadTransaction, VersionManagedTransaction,
+};
 use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
 use crate::storage::in_memory::{InMemoryPageId, InMemoryPageReservation, InMemoryStorage};
-use crate::storage::{StorageError, Transaction};
+use crate::storage::{
+    Change, IsolationLevel, ReadTransaction, Savepoint, StorageError, Transaction,
+    TransactionalTimestamp,
+};
 
 #[derive(Debug)]
 pub struct InMemoryTransaction<'storage> {
@@ -9,20 +16,24 @@ pub struct InMemoryTransaction<'storage> {
 }
 
 impl<'storage> InMemoryTransaction<'storage> {
-    pub fn new(storage: &'storage InMemoryStorage) -> Self {
+    pub fn new(storage: &'storage InMemoryStorage, isolation: IsolationLevel) -> Self {
         Self {
-            version_manager: storage.version_manager.start_transaction(),
+            version_manager: storage.version_manager.start_transaction(isolation),
         }
     }
 }
 
-impl<'storage> Transaction<'storage> for InMemoryTransaction<'storage> {
+impl<'storage> ReadTransaction<'storage> for InMemoryTransaction<'storage> {
     type Storage = InMemoryStorage;
 
     fn id(&self) -> crate::storage::TransactionId {
         self.version_manager.id()
     }
 
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.version_manager.set_deadline(deadline);
+    }
+
     fn read<T, const N: usize>(
         &mut self,
         indices: impl Into<[InMemoryPageId; N]>,
@@ -40,6 +51,20 @@ impl<'storage> Transaction<'storage> for InMemoryTransaction<'storage> {
         Ok(read(guards.each_ref().map(|x| &**x)))
     }
 
+    fn commit(mut self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.version_manager.commit()?;
+
+        Ok(())
+    }
+
+    fn rollback(mut self) -> Result<(), StorageError<InMemoryPageId>> {
+        self.version_manager.rollback();
+
+        Ok(())
+    }
+}
+
+impl<'storage> Transaction<'storage> for InMemoryTransaction<'storage> {
     // TODO do we actually need to differentiate between read() and write()???
     fn write<T, const N: usize>(
         &mut self,
@@ -95,15 +120,79 @@ impl<'storage> Transaction<'storage> for InMemoryTransaction<'storage> {
         Ok(())
     }
 
-    fn commit(mut self) -> Result<(), StorageError<InMemoryPageId>> {
-        self.version_manager.commit()?;
+    fn savepoint(&mut self) -> Savepoint {
+        self.version_manager.savepoint()
+    }
 
-        Ok(())
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError<InMemoryPageId>> {
+        self.version_manager.rollback_to(savepoint)
     }
 
-    fn rollback(mut self) -> Result<(), StorageError<InMemoryPageId>> {
-        self.version_manager.rollback();
+    fn captures_changes(&self) -> bool {
+        self.version_manager.captures_changes()
+    }
+
+    fn record_change(&mut self, change: Change) {
+        self.version_manager.record_change(change);
+    }
+}
+
+#[derive(Debug)]
+pub struct InMemoryReadTransaction<'storage> {
+    version_manager: VersionManagedReadTransaction<'storage>,
+}
+
+impl<'storage> InMemoryReadTransaction<'storage> {
+    pub fn new(storage: &'storage InMemoryStorage) -> Self {
+        Self {
+            version_manager: storage.version_manager.start_read_transaction(),
+        }
+    }
+
+    pub fn as_of(
+        storage: &'storage InMemoryStorage,
+        timestamp: TransactionalTimestamp,
+    ) -> Result<Self, StorageError<InMemoryPageId>> {
+        Ok(Self {
+            version_manager: storage.version_manager.start_transaction_as_of(timestamp)?,
+        })
+    }
+}
+
+impl<'storage> ReadTransaction<'storage> for InMemoryReadTransaction<'storage> {
+    type Storage = InMemoryStorage;
+
+    fn id(&self) -> crate::storage::TransactionId {
+        self.version_manager.id()
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.version_manager.set_deadline(deadline);
+    }
+
+    fn read<T, const N: usize>(
+        &mut self,
+        indices: impl Into<[InMemoryPageId; N]>,
+        read: impl FnOnce([&VersionedPage; N]) -> T,
+    ) -> Result<T, StorageError<InMemoryPageId>> {
+        let indices: [InMemoryPageId; N] = indices.into();
+
+        let guards: [_; N] = indices
+            .map(|x| self.version_manager.read(x.0))
+            .into_iter()
+            .collect::<Result<Vec<_>, StorageError<InMemoryPageId>>>()?
+            .try_into()
+            .unwrap();
+
+        Ok(read(guards.each_ref().map(|x| &**x)))
+    }
+
+    // dropping the transaction is all it takes to finish it
+    fn commit(self) -> Result<(), StorageError<InMemoryPageId>> {
+        Ok(())
+    }
 
+    fn rollback(self) -> Result<(), StorageError<InMemoryPageId>> {
         Ok(())
     }
 }
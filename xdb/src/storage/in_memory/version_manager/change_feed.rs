@@ -0,0 +1,473 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::storage::in_memory::InMemoryPageId;
+use crate::storage::{Change, StorageError, TransactionId, TransactionalTimestamp};
+use crate::sync::mpsc::{self, SyncSender};
+use crate::sync::{Arc, Condvar, Mutex, MutexGuard};
+use crate::thread;
+
+/// Controls whether the changes made by the committed transactions are kept for the subscribers
+/// of the change feed.
+///
+/// The feed keeps up to `buffer_size` of the latest changes, so that a subscriber can resume from
+/// any of them. Once the buffer is full, the newer changes are held back until every subscriber
+/// has received the oldest change. Only the changes of a limited number of commits are held back,
+/// after that the committer waits for the subscribers as well, so a subscriber must not wait for a
+/// commit of its own while it's not receiving.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeCapture {
+    buffer_size: usize,
+}
+
+impl ChangeCapture {
+    /// Turns the capture on, keeping up to `buffer_size` changes. A size of 0 (the default) turns
+    /// it off.
+    #[must_use]
+    pub const fn with_buffer_size(self, buffer_size: usize) -> Self {
+        Self { buffer_size }
+    }
+
+    const fn is_enabled(self) -> bool {
+        self.buffer_size > 0
+    }
+}
+
+/// A change, along with the commit that made it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    timestamp: TransactionalTimestamp,
+    transaction: TransactionId,
+    change: Change,
+}
+
+impl ChangeEvent {
+    pub(crate) const fn new(
+        timestamp: TransactionalTimestamp,
+        transaction: TransactionId,
+        change: Change,
+    ) -> Self {
+        Self {
+            timestamp,
+            transaction,
+            change,
+        }
+    }
+
+    #[must_use]
+    pub const fn timestamp(&self) -> TransactionalTimestamp {
+        self.timestamp
+    }
+
+    #[must_use]
+    pub const fn transaction(&self) -> TransactionId {
+        self.transaction
+    }
+
+    #[must_use]
+    pub const fn change(&self) -> &Change {
+        &self.change
+    }
+}
+
+#[derive(Debug)]
+struct FeedState {
+    capture: ChangeCapture,
+    // ordered by the commit timestamps, as the committer publishes the commits in that order
+    events: VecDeque<ChangeEvent>,
+    // the sequence number of the first event in the buffer
+    first: u64,
+    // the newest commit whose changes are no longer in the buffer (or were never captured)
+    dropped_until: Option<TransactionalTimestamp>,
+    // the sequence number of the next event for each of the subscribers
+    subscribers: HashMap<u64, u64>,
+    next_subscriber: u64,
+    closed: bool,
+}
+
+impl FeedState {
+    /// Removes the oldest event, if all the subscribers have already received it.
+    fn try_drop_oldest(&mut self) -> bool {
+        if self.subscribers.values().any(|next| *next <= self.first) {
+            return false;
+        }
+
+        let Some(dropped) = self.events.pop_front() else {
+            return false;
+        };
+
+        self.first += 1;
+        self.dropped_until = Some(dropped.timestamp);
+
+        true
+    }
+}
+
+/// Passes the changes from the committer to the subscribers.
+#[derive(Debug)]
+pub struct ChangeFeed {
+    state: Mutex<FeedState>,
+    // notified whenever events are published or received, and when the feed is closed
+    updated: Condvar,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(FeedState {
+                capture: ChangeCapture::default(),
+                events: VecDeque::new(),
+                first: 0,
+                dropped_until: None,
+                subscribers: HashMap::new(),
+                next_subscriber: 0,
+                closed: false,
+            }),
+            updated: Condvar::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().capture.is_enabled()
+    }
+
+    /// Whether the changes of the commit at `timestamp` are expected in the feed, i.e. the capture
+    /// was already turned on when the commit got its timestamp.
+    pub fn captures(&self, timestamp: TransactionalTimestamp) -> bool {
+        let state = self.state.lock().unwrap();
+
+        state.capture.is_enabled() && state.dropped_until < Some(timestamp)
+    }
+
+    /// The changes committed up to `latest_commit` were not captured if the capture is only being
+    /// turned on, so those can't be resumed from. It's called with the feed locked, so that the
+    /// commits that get a timestamp after it see the capture turned on.
+    pub fn set_capture(
+        &self,
+        capture: ChangeCapture,
+        latest_commit: impl FnOnce() -> Option<TransactionalTimestamp>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+
+        if capture.is_enabled() && !state.capture.is_enabled() {
+            state.dropped_until = state.dropped_until.max(latest_commit());
+        }
+
+        state.capture = capture;
+
+        drop(state);
+        self.updated.notify_all();
+    }
+
+    /// Adds the changes of a commit to the buffer, waiting for the subscribers to make space if
+    /// it's full. Returns early if the feed gets closed in the meantime.
+    pub fn publish(&self, events: Vec<ChangeEvent>) {
+        let mut state = self.state.lock().unwrap();
+
+        for event in events {
+            // the capture could have been turned off and on again since the commit
+            if !state.capture.is_enabled() || state.dropped_until >= Some(event.timestamp) {
+                state.dropped_until = state.dropped_until.max(Some(event.timestamp));
+
+                continue;
+            }
+
+            while state.events.len() >= state.capture.buffer_size && !state.try_drop_oldest() {
+                if state.closed {
+                    return;
+                }
+
+                debug!("the change feed is full, waiting for the subscribers");
+
+                state = self.updated.wait(state).unwrap();
+            }
+
+            state.events.push_back(event);
+        }
+
+        drop(state);
+        self.updated.notify_all();
+    }
+
+    /// Starts receiving the changes committed after `after`, or all of them if it's `None`.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        after: Option<TransactionalTimestamp>,
+    ) -> Result<ChangeSubscription, StorageError<InMemoryPageId>> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.capture.is_enabled() || state.dropped_until > after {
+            return Err(StorageError::ChangesUnavailable(after));
+        }
+
+        let next =
+            state.first + state.events.partition_point(|x| Some(x.timestamp) <= after) as u64;
+
+        let id = state.next_subscriber;
+        state.next_subscriber += 1;
+        state.subscribers.insert(id, next);
+
+        Ok(ChangeSubscription {
+            feed: self.clone(),
+            id,
+            after,
+        })
+    }
+
+    /// Wakes up all the subscribers, which won't receive anything past the events that are
+    /// already in the buffer.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+
+        self.updated.notify_all();
+    }
+}
+
+// the number of commits whose changes can wait to be published, before the committer has to wait
+// as well
+const QUEUED_COMMITS: usize = 16;
+
+/// Publishes the changes to the feed on a thread of its own, so that the committer only waits for
+/// the subscribers once they fall far behind. The changes are published in the order they're
+/// passed in.
+#[derive(Debug)]
+pub struct ChangePublisher {
+    tx: SyncSender<Vec<ChangeEvent>>,
+}
+
+impl ChangePublisher {
+    pub fn new(feed: Arc<ChangeFeed>) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Vec<ChangeEvent>>(QUEUED_COMMITS);
+
+        thread::Builder::new()
+            .name("change-feed".into())
+            .spawn(move || {
+                while let Ok(events) = rx.recv() {
+                    feed.publish(events);
+                }
+            })
+            .unwrap();
+
+        Self { tx }
+    }
+
+    /// Waits if the changes of `QUEUED_COMMITS` commits are already waiting to be published.
+    pub fn publish(&self, events: Vec<ChangeEvent>) {
+        // the thread only stops once the publisher is dropped
+        self.tx.send(events).unwrap();
+    }
+}
+
+/// Receives the changes, in the order of the commit timestamps. The changes made by a single
+/// transaction are received in the order it made them.
+#[derive(Debug)]
+pub struct ChangeSubscription {
+    feed: Arc<ChangeFeed>,
+    id: u64,
+    // the events up to this commit are skipped, even if they were published after subscribing
+    after: Option<TransactionalTimestamp>,
+}
+
+impl ChangeSubscription {
+    /// Waits for the next change. Returns `None` once the storage is dropped.
+    pub fn recv(&mut self) -> Option<ChangeEvent> {
+        self.recv_until(None)
+    }
+
+    /// Waits up to `timeout` for the next change.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<ChangeEvent> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    /// Returns the next change, if there is one already.
+    pub fn try_recv(&mut self) -> Option<ChangeEvent> {
+        let mut state = self.feed.state.lock().unwrap();
+
+        self.take_next(&mut state)
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Option<ChangeEvent> {
+        let mut state = self.feed.state.lock().unwrap();
+
+        loop {
+            if let Some(event) = self.take_next(&mut state) {
+                return Some(event);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = match deadline {
+                None => self.feed.updated.wait(state).unwrap(),
+                Some(deadline) => {
+                    let remaining = deadline.checked_duration_since(Instant::now())?;
+
+                    self.feed.updated.wait_timeout(state, remaining).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn take_next(&self, state: &mut MutexGuard<'_, FeedState>) -> Option<ChangeEvent> {
+        loop {
+            let next = state.subscribers[&self.id];
+            let event = state
+                .events
+                .get(usize::try_from(next - state.first).unwrap())?
+                .clone();
+
+            state.subscribers.insert(self.id, next + 1);
+            // the publisher could be waiting for this subscriber to make space
+            self.feed.updated.notify_all();
+
+            if Some(event.timestamp) > self.after {
+                return Some(event);
+            }
+        }
+    }
+}
+
+impl Iterator for ChangeSubscription {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+impl Drop for ChangeSubscription {
+    fn drop(&mut self) {
+        self.feed.state.lock().unwrap().subscribers.remove(&self.id);
+
+        self.feed.updated.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::{ReadTransaction as _, SENTINEL_PAGE_ID, Storage as _, Transaction as _};
+    use crate::sync::atomic::{AtomicUsize, Ordering};
+    use crate::thread;
+
+    fn timestamp(value: u64) -> TransactionalTimestamp {
+        (0..value).fold(TransactionalTimestamp::zero(), |x, _| x.next())
+    }
+
+    fn event(value: u64, key: u8) -> ChangeEvent {
+        ChangeEvent::new(
+            timestamp(value),
+            TransactionId::next(),
+            Change::new(SENTINEL_PAGE_ID, vec![key], None, Some(vec![key])),
+        )
+    }
+
+    fn keys(subscription: &mut ChangeSubscription) -> Vec<u8> {
+        std::iter::from_fn(|| subscription.try_recv())
+            .map(|x| x.change().key()[0])
+            .collect()
+    }
+
+    #[test]
+    fn resumes_from_a_timestamp() {
+        let feed = Arc::new(ChangeFeed::new());
+        feed.set_capture(ChangeCapture::default().with_buffer_size(3), || None);
+
+        feed.publish(vec![event(1, 1), event(1, 2)]);
+        feed.publish(vec![event(2, 3)]);
+        feed.publish(vec![event(3, 4)]);
+
+        // the oldest event was dropped to make space
+        assert_eq!(
+            feed.subscribe(None).unwrap_err(),
+            StorageError::ChangesUnavailable(None)
+        );
+
+        let after = Some(timestamp(1));
+        assert_eq!(keys(&mut feed.subscribe(after).unwrap()), vec![3, 4]);
+
+        let mut subscription = feed.subscribe(Some(timestamp(3))).unwrap();
+        assert!(keys(&mut subscription).is_empty());
+
+        feed.publish(vec![event(4, 5)]);
+        assert_eq!(keys(&mut subscription), vec![5]);
+    }
+
+    #[test]
+    fn slow_subscribers_hold_back_publishing() {
+        let feed = Arc::new(ChangeFeed::new());
+        feed.set_capture(ChangeCapture::default().with_buffer_size(1), || None);
+
+        let mut subscription = feed.subscribe(None).unwrap();
+
+        let publisher = {
+            let feed = feed.clone();
+
+            thread::spawn(move || {
+                for timestamp in 1..=10 {
+                    feed.publish(vec![event(timestamp, u8::try_from(timestamp).unwrap())]);
+                }
+            })
+        };
+
+        let received: Vec<_> = subscription
+            .by_ref()
+            .take(10)
+            .map(|x| x.change().key()[0])
+            .collect();
+        assert_eq!(received, (1..=10).collect::<Vec<_>>());
+
+        publisher.join().unwrap();
+
+        feed.close();
+        assert_eq!(subscription.recv(), None);
+    }
+
+    #[test]
+    fn subscribers_that_fall_far_behind_hold_back_the_commits() {
+        const COMMIT_COUNT: usize = QUEUED_COMMITS + 10;
+
+        let storage = InMemoryStorage::new();
+        storage.set_change_capture(ChangeCapture::default().with_buffer_size(1));
+        let mut subscription = storage.subscribe(None).unwrap();
+        let committed = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for index in 0..COMMIT_COUNT {
+                    let mut transaction = storage.transaction().unwrap();
+                    transaction.record_change(Change::new(
+                        SENTINEL_PAGE_ID,
+                        vec![u8::try_from(index).unwrap()],
+                        None,
+                        None,
+                    ));
+                    transaction.commit().unwrap();
+
+                    committed.fetch_add(1, Ordering::Release);
+                }
+            });
+
+            // one commit in the buffer, one waiting for space in it, and the queued ones, while the
+            // next one waits for the queue
+            while committed.load(Ordering::Acquire) < QUEUED_COMMITS + 2 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            assert_eq!(committed.load(Ordering::Acquire), QUEUED_COMMITS + 2);
+
+            assert_eq!(
+                subscription
+                    .by_ref()
+                    .take(COMMIT_COUNT)
+                    .map(|x| usize::from(x.change().key()[0]))
+                    .collect::<Vec<_>>(),
+                (0..COMMIT_COUNT).collect::<Vec<_>>()
+            );
+        });
+    }
+}
@@ -1,23 +1,143 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::pin::Pin;
+use std::time::{Duration, Instant};
 
-use tracing::{debug, info_span, instrument, record_all, trace};
+use tracing::{debug, error, info_span, instrument, trace};
 
 use crate::platform::futex::Futex;
 use crate::storage::in_memory::InMemoryPageId;
+use crate::storage::in_memory::durability::{Durability, PageImage};
+use crate::storage::in_memory::version_manager::change_feed::{
+    ChangeEvent, ChangeFeed, ChangePublisher,
+};
+use crate::storage::in_memory::version_manager::transaction::PageWriteGuard;
 use crate::storage::in_memory::version_manager::transaction_log::{
-    StartedTransaction, TransactionLog,
+    CommitHandle, StartedTransaction, TransactionLog,
 };
 use crate::storage::in_memory::version_manager::{
     TransactionPage, TransactionPageAction, VersionedBlock,
 };
-use crate::storage::{PageIndex, StorageError};
+use crate::storage::{Change, IsolationLevel, PageIndex, StorageError, TransactionalTimestamp};
 use crate::sync::atomic::Ordering;
-use crate::sync::mpsc::{self, Sender};
+use crate::sync::mpsc::{self, Receiver, Sender};
 use crate::sync::{Arc, Mutex};
 use crate::thread::{self, JoinHandle};
 
+/// Controls how many of the queued commit requests the committer handles together. All the
+/// commits in a batch share a single flush of the write-ahead log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    max_batch_size: usize,
+    max_wait: Duration,
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 64,
+            max_wait: Duration::ZERO,
+        }
+    }
+}
+
+impl GroupCommit {
+    /// Limits the number of commits in a single batch. A size of 1 turns the grouping off.
+    #[must_use]
+    pub fn with_max_batch_size(self, max_batch_size: usize) -> Self {
+        Self {
+            max_batch_size: max_batch_size.max(1),
+            ..self
+        }
+    }
+
+    /// Makes the committer wait up to `max_wait` for more requests to arrive before it starts on
+    /// a batch that is not full. By default, only the requests that are already queued are taken.
+    #[must_use]
+    pub const fn with_max_wait(self, max_wait: Duration) -> Self {
+        Self { max_wait, ..self }
+    }
+}
+
+/// What happened to a single batch of commit requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchMetrics {
+    size: usize,
+    committed: usize,
+    log_duration: Duration,
+    duration: Duration,
+}
+
+impl BatchMetrics {
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of requests that were committed, the rest of them failed (e.g. on a conflict).
+    #[must_use]
+    pub const fn committed(&self) -> usize {
+        self.committed
+    }
+
+    /// How long it took to make the batch durable.
+    #[must_use]
+    pub const fn log_duration(&self) -> Duration {
+        self.log_duration
+    }
+
+    /// How long the whole batch took, from taking the first lock to responding to the requests.
+    #[must_use]
+    pub const fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+/// The totals over all the batches handled by the committer, along with the latest batch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitMetrics {
+    batches: u64,
+    requests: u64,
+    committed: u64,
+    largest_batch: usize,
+    latest_batch: Option<BatchMetrics>,
+}
+
+impl CommitMetrics {
+    #[must_use]
+    pub const fn batches(&self) -> u64 {
+        self.batches
+    }
+
+    #[must_use]
+    pub const fn requests(&self) -> u64 {
+        self.requests
+    }
+
+    #[must_use]
+    pub const fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    #[must_use]
+    pub const fn largest_batch(&self) -> usize {
+        self.largest_batch
+    }
+
+    #[must_use]
+    pub const fn latest_batch(&self) -> Option<BatchMetrics> {
+        self.latest_batch
+    }
+
+    fn record(&mut self, batch: BatchMetrics) {
+        self.batches += 1;
+        self.requests += batch.size as u64;
+        self.committed += batch.committed as u64;
+        self.largest_batch = self.largest_batch.max(batch.size);
+        self.latest_batch = Some(batch);
+    }
+}
+
 #[derive(Debug)]
 pub struct CommitRequest {
     is_done: Pin<Arc<Futex>>,
@@ -26,6 +146,9 @@ pub struct CommitRequest {
 
     pages: HashMap<PageIndex, TransactionPage>,
     transaction: StartedTransaction,
+    isolation: IsolationLevel,
+    // `None` if the transaction started before the changes were captured
+    changes: Option<Vec<Change>>,
 }
 
 impl CommitRequest {
@@ -66,13 +189,26 @@ impl Display for CommitRequest {
     }
 }
 
+/// A request from the batch that has its pages locked and a commit timestamp assigned.
+#[derive(Debug)]
+struct LockedCommit<'log, 'storage> {
+    request: CommitRequest,
+    pages: HashMap<PageIndex, TransactionPage>,
+    locks: HashMap<PageIndex, PageWriteGuard<'storage>>,
+    commit_handle: CommitHandle<'log>,
+}
+
 #[derive(Debug)]
 struct CommitterThread<'log, 'storage> {
     log: &'log TransactionLog,
     block: &'storage VersionedBlock,
+    change_feed: &'storage ChangeFeed,
+    publisher: ChangePublisher,
+    durability: Option<&'storage dyn Durability>,
+    metrics: &'storage Mutex<CommitMetrics>,
 }
 
-impl CommitterThread<'_, '_> {
+impl<'log, 'storage> CommitterThread<'log, 'storage> {
     fn rollback(
         &self,
         pages: HashMap<PageIndex, TransactionPage>,
@@ -85,83 +221,318 @@ impl CommitterThread<'_, '_> {
                 TransactionPageAction::Update(cow) => Some(self.block.get(cow).upgrade()),
             };
 
-            if let Some(mut lock) = to_free {
+            if let Some(lock) = to_free {
                 debug!(
                     physical_index = ?lock.physical_index(),
                     logical_index = ?page.logical_index,
                     "clearing page"
                 );
 
-                lock.mark_free();
+                self.block.discard(lock);
             }
         }
 
         self.log.rollback(transaction);
     }
 
-    #[instrument(skip(self, pages), fields(started, timestamp))]
-    fn commit(
+    /// Whether the request modifies pages, but can't publish its changes, as its transaction
+    /// started before they were captured, while its commit is after the capture was turned on.
+    fn misses_capture(
+        &self,
+        request: &CommitRequest,
+        pages: &HashMap<PageIndex, TransactionPage>,
+        timestamp: TransactionalTimestamp,
+    ) -> bool {
+        request.changes.is_none()
+            && pages
+                .values()
+                .any(|x| !matches!(x.action, TransactionPageAction::Read))
+            && self.change_feed.captures(timestamp)
+    }
+
+    /// Takes requests from the queue until the batch is full, there are no more requests within
+    /// `max_wait`, or the next request touches a page that's already in the batch. In the last case,
+    /// the request is returned separately, so that it can start the next batch.
+    fn collect_batch(
+        first: CommitRequest,
+        rx: &Receiver<CommitRequest>,
+        config: GroupCommit,
+    ) -> (Vec<CommitRequest>, Option<CommitRequest>) {
+        let deadline = Instant::now() + config.max_wait;
+        let mut pages: HashSet<PageIndex> = first.pages.keys().copied().collect();
+        let mut batch = vec![first];
+
+        while batch.len() < config.max_batch_size {
+            let next = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => rx.recv_timeout(remaining).ok(),
+                _ => rx.try_recv().ok(),
+            };
+
+            let Some(next) = next else {
+                break;
+            };
+
+            // The pages of all the requests in a batch are locked at the same time, so they must
+            // not overlap. The next batch will see this one committed, just like it would if the
+            // requests were handled one by one.
+            if next.pages.keys().any(|x| pages.contains(x)) {
+                return (batch, Some(next));
+            }
+
+            pages.extend(next.pages.keys().copied());
+            batch.push(next);
+        }
+
+        (batch, None)
+    }
+
+    /// Locks the pages of each of the requests and assigns them commit timestamps. The requests
+    /// that can't be committed are rolled back, and returned along with their errors.
+    #[allow(clippy::type_complexity)]
+    fn lock_batch(
+        &self,
+        requests: Vec<CommitRequest>,
+    ) -> (
+        Vec<LockedCommit<'log, 'storage>>,
+        Vec<(CommitRequest, Result<(), StorageError<InMemoryPageId>>)>,
+    ) {
+        let mut commits = vec![];
+        let mut responses = vec![];
+
+        for mut request in requests {
+            let pages = request.take_pages();
+            let transaction = request.transaction;
+
+            let _ = info_span!("locking pages", ?transaction, %request).entered();
+
+            match self.lock(transaction, request.isolation, &pages) {
+                Ok(locks) => {
+                    // It is very important that we only start the commit in the log after we've
+                    // taken the locks. Otherwise, another commit could change the pages in the
+                    // time between us taking the lock and assigning a commit timestamp, which
+                    // would cause inconsistencies.
+                    let commit_handle = self.log.start_commit(transaction);
+
+                    if self.misses_capture(&request, &pages, commit_handle.timestamp()) {
+                        drop(locks);
+                        self.rollback(pages, commit_handle.abort());
+
+                        responses.push((
+                            request,
+                            Err(StorageError::UncapturedChanges(transaction.id())),
+                        ));
+
+                        continue;
+                    }
+
+                    commits.push(LockedCommit {
+                        request,
+                        pages,
+                        locks,
+                        commit_handle,
+                    });
+                }
+                Err(e) => {
+                    self.rollback(pages, transaction);
+
+                    responses.push((request, Err(e)));
+                }
+            }
+        }
+
+        (commits, responses)
+    }
+
+    #[instrument(skip_all, fields(size = requests.len()))]
+    fn commit_batch(&self, requests: Vec<CommitRequest>) {
+        let started = Instant::now();
+        let size = requests.len();
+        let (mut commits, mut responses) = self.lock_batch(requests);
+
+        trace!(
+            requested = size,
+            locked = commits.len(),
+            "collected locks for the batch"
+        );
+
+        let committed = commits.len();
+        let log_started = Instant::now();
+
+        // The commits must be durable before anyone can see them, so the page images go to the log
+        // before any visibility information is modified.
+        let images = match self.log(&commits) {
+            Ok(images) => images,
+            Err(e) => {
+                for commit in commits {
+                    let LockedCommit {
+                        request,
+                        pages,
+                        locks,
+                        commit_handle,
+                    } = commit;
+
+                    drop(locks);
+                    self.rollback(pages, commit_handle.abort());
+
+                    responses.push((request, Err(e.clone())));
+                }
+
+                self.finish_batch(
+                    BatchMetrics {
+                        size,
+                        committed: 0,
+                        log_duration: log_started.elapsed(),
+                        duration: started.elapsed(),
+                    },
+                    responses,
+                );
+
+                return;
+            }
+        };
+
+        let log_duration = log_started.elapsed();
+
+        for commit in &mut commits {
+            self.update_visibility(commit);
+        }
+
+        let mut changes = vec![];
+
+        for commit in commits {
+            let LockedCommit {
+                mut request,
+                locks,
+                commit_handle,
+                ..
+            } = commit;
+
+            let timestamp = commit_handle.timestamp();
+            commit_handle.commit();
+
+            drop(locks);
+
+            changes.extend(
+                request
+                    .changes
+                    .take()
+                    .into_iter()
+                    .flatten()
+                    .map(|x| ChangeEvent::new(timestamp, request.transaction.id(), x)),
+            );
+
+            responses.push((request, Ok(())));
+        }
+
+        // If the subscribers fell too far behind, the whole batch waits for them here.
+        if !changes.is_empty() {
+            self.publisher.publish(changes);
+        }
+
+        if let (Some(durability), Some(images)) = (self.durability, images) {
+            // The commits are already durable, so there's nothing to roll back here. The log won't
+            // be truncated until the data file is written successfully, so the pages will get
+            // recovered from it on the next start.
+            if let Err(e) = durability.apply(&images) {
+                error!(?e, "failed to write the commits to the data file");
+            }
+        }
+
+        self.finish_batch(
+            BatchMetrics {
+                size,
+                committed,
+                log_duration,
+                duration: started.elapsed(),
+            },
+            responses,
+        );
+    }
+
+    /// Records the metrics before responding, so that they already include the batch once any of
+    /// the waiting transactions returns.
+    fn finish_batch(
+        &self,
+        metrics: BatchMetrics,
+        responses: Vec<(CommitRequest, Result<(), StorageError<InMemoryPageId>>)>,
+    ) {
+        debug!(?metrics, "batch completed");
+
+        self.metrics.lock().unwrap().record(metrics);
+
+        for (request, response) in responses {
+            request.respond(response);
+        }
+    }
+
+    /// Locks the pages the transaction modified, and with serializable isolation also the ones
+    /// it read, failing if any of them has changed since the snapshot.
+    fn lock(
         &self,
         transaction: StartedTransaction,
-        pages: HashMap<PageIndex, TransactionPage>,
-    ) -> Result<(), StorageError<InMemoryPageId>> {
+        isolation: IsolationLevel,
+        pages: &HashMap<PageIndex, TransactionPage>,
+    ) -> Result<HashMap<PageIndex, PageWriteGuard<'storage>>, StorageError<InMemoryPageId>> {
         let mut locks = HashMap::new();
 
-        let mut rollback = None;
+        for (index, page) in pages {
+            let is_read = matches!(page.action, TransactionPageAction::Read);
+
+            if is_read && isolation == IsolationLevel::Snapshot {
+                continue;
+            }
 
-        for (index, page) in &pages {
             let lock = self
                 .block
                 .get_at(page.logical_index, transaction.started())
                 .upgrade();
 
-            if lock.next_version().is_some() {
+            if lock.is_superseded() {
                 debug!(
                     physical_index = ?lock.physical_index(),
                     logical_index = ?page.logical_index,
                     next_version = ?lock.next_version(),
+                    visible_until = ?lock.visible_until(),
+                    is_read,
                     "rolling back, conflict"
                 );
 
-                rollback = Some(*index);
-
-                break;
+                return Err(if is_read {
+                    StorageError::SerializationFailure {
+                        page: InMemoryPageId(*index),
+                        transaction: transaction.id(),
+                        snapshot: transaction.started(),
+                    }
+                } else {
+                    StorageError::Conflict {
+                        page: InMemoryPageId(*index),
+                        transaction: transaction.id(),
+                        snapshot: transaction.started(),
+                    }
+                });
             }
 
             locks.insert(*index, lock);
         }
 
-        if let Some(rollback_index) = rollback {
-            drop(locks);
-
-            self.rollback(pages, transaction);
-
-            // TODO this is not a deadlock, but an optimistic concurrency race
-            return Err(StorageError::Deadlock(InMemoryPageId(rollback_index)));
-        }
-
-        trace!(
-            lock_count = locks.len(),
-            page_count = pages.len(),
-            "collected locks for pages"
-        );
+        Ok(locks)
+    }
 
-        // It is very important that we only start the commit in the log after we've taken the
-        // locks. Otherwise, another commit could change the pages in the time between us taking
-        // the lock and assigning a commit timestamp, which would cause inconsistencies.
-        let commit_handle = self.log.start_commit(transaction);
+    fn update_visibility(&self, commit: &mut LockedCommit<'log, 'storage>) {
+        let timestamp = commit.commit_handle.timestamp();
 
-        record_all!(tracing::Span::current(), started = ?commit_handle.started(), timestamp = ?commit_handle.timestamp());
+        for (index, page) in &commit.pages {
+            let _ =
+                info_span!("committing page", logical_index=?index, ?page, ?timestamp).entered();
 
-        for (index, page) in pages {
-            let _ = info_span!("committing page", logical_index=?index, ?page).entered();
+            assert!(*index == page.logical_index);
 
-            assert!(index == page.logical_index);
+            // the pages that were only read are not locked with snapshot isolation
+            let Some(lock) = commit.locks.get_mut(index) else {
+                debug_assert!(matches!(page.action, TransactionPageAction::Read));
 
-            // It's very tempting to change this `get_mut` to `remove`, but that would be
-            // incorrect, as we'd be unlocking locks while still modifying the stored data.
-            // We can only start unlocking after this loop is done.
-            let lock = locks.get_mut(&index).unwrap();
+                continue;
+            };
 
             match page.action {
                 TransactionPageAction::Read => {
@@ -176,7 +547,8 @@ impl CommitterThread<'_, '_> {
                         "deleted"
                     );
 
-                    lock.set_visible_until(Some(commit_handle.timestamp()));
+                    lock.set_visible_until(Some(timestamp));
+                    self.block.dirty_pages.push(page.logical_index);
                 }
                 TransactionPageAction::Update(cow) => {
                     let cow_page = self.block.get(cow);
@@ -184,19 +556,22 @@ impl CommitterThread<'_, '_> {
                         logical_index = ?page.logical_index,
                         cow.physical_index = ?cow_page.physical_index(),
                         logical_index=?index,
-                        main.visible_until = ?commit_handle.timestamp(),
+                        main.visible_until = ?timestamp,
                         "updated"
                     );
 
                     let mut cow_lock = cow_page.upgrade();
 
                     lock.set_next_version(Some(cow_lock.physical_index()));
-                    lock.set_visible_until(Some(commit_handle.timestamp()));
+                    lock.set_visible_until(Some(timestamp));
 
-                    cow_lock.set_visible_from(Some(commit_handle.timestamp()));
+                    cow_lock.set_visible_from(Some(timestamp));
                     cow_lock.set_visible_until(None);
                     cow_lock.set_previous_version(Some(lock.physical_index()));
                     cow_lock.set_next_version(None);
+
+                    // vacuum can remove the previous version once no one can see it
+                    self.block.dirty_pages.push(page.logical_index);
                 }
                 TransactionPageAction::Insert => {
                     debug!(
@@ -204,19 +579,57 @@ impl CommitterThread<'_, '_> {
                         "inserted"
                     );
 
-                    lock.set_visible_from(Some(commit_handle.timestamp()));
+                    lock.set_visible_from(Some(timestamp));
                     lock.set_visible_until(None);
                 }
             }
         }
+    }
+
+    fn log(
+        &self,
+        commits: &[LockedCommit<'log, 'storage>],
+    ) -> Result<Option<Vec<Vec<PageImage>>>, StorageError<InMemoryPageId>> {
+        let Some(durability) = self.durability else {
+            return Ok(None);
+        };
+
+        if commits.is_empty() {
+            return Ok(None);
+        }
 
-        commit_handle.commit();
+        let images: Vec<_> = commits
+            .iter()
+            .map(|commit| self.page_images(&commit.pages, &commit.locks))
+            .collect();
 
-        drop(locks);
+        if let Err(e) = durability.log(&images) {
+            error!(?e, "failed to write the commits to the write-ahead log");
 
-        debug!("commit completed");
+            return Err(e);
+        }
 
-        Ok(())
+        Ok(Some(images))
+    }
+
+    fn page_images(
+        &self,
+        pages: &HashMap<PageIndex, TransactionPage>,
+        locks: &HashMap<PageIndex, PageWriteGuard<'_>>,
+    ) -> Vec<PageImage> {
+        pages
+            .iter()
+            .filter_map(|(index, page)| match page.action {
+                TransactionPageAction::Read => None,
+                TransactionPageAction::Delete => Some(PageImage::new(*index, None)),
+                TransactionPageAction::Update(cow) => {
+                    Some(PageImage::new(*index, Some(&self.block.get(cow))))
+                }
+                TransactionPageAction::Insert => {
+                    Some(PageImage::new(*index, Some(locks.get(index).unwrap())))
+                }
+            })
+            .collect()
     }
 }
 
@@ -225,27 +638,44 @@ pub struct Committer {
     #[allow(unused)]
     handle: Option<JoinHandle<()>>,
     tx: Sender<CommitRequest>,
+    group_commit: Arc<Mutex<GroupCommit>>,
+    metrics: Arc<Mutex<CommitMetrics>>,
 }
 
 impl Committer {
-    pub(crate) fn new(block: Arc<VersionedBlock>, log: Arc<TransactionLog>) -> Self {
+    pub(crate) fn new(
+        block: Arc<VersionedBlock>,
+        log: Arc<TransactionLog>,
+        change_feed: Arc<ChangeFeed>,
+        durability: Option<Arc<dyn Durability>>,
+    ) -> Self {
         let (tx, rx) = mpsc::channel::<CommitRequest>();
+        let group_commit = Arc::new(Mutex::new(GroupCommit::default()));
+        let metrics = Arc::new(Mutex::new(CommitMetrics::default()));
+
         let handle = {
+            let group_commit = group_commit.clone();
+            let metrics = metrics.clone();
+
             thread::Builder::new()
                 .name("committer".into())
                 .spawn(move || {
                     let thread = CommitterThread {
                         log: &log,
                         block: &block,
+                        change_feed: &change_feed,
+                        publisher: ChangePublisher::new(change_feed.clone()),
+                        durability: durability.as_deref(),
+                        metrics: &metrics,
                     };
-                    while let Ok(mut request) = rx.recv() {
-                        info_span!("transaction commit", transaction = ?request.transaction, %request).in_scope(
-                            || {
-                                let commit_result = thread.commit(request.transaction, request.take_pages());
-
-                                request.respond(commit_result);
-                            },
-                        );
+                    let mut carried_over = None;
+
+                    while let Some(first) = carried_over.take().or_else(|| rx.recv().ok()) {
+                        let config = *group_commit.lock().unwrap();
+                        let (batch, next) = CommitterThread::collect_batch(first, &rx, config);
+
+                        thread.commit_batch(batch);
+                        carried_over = next;
                     }
                 })
                 .unwrap()
@@ -254,13 +684,25 @@ impl Committer {
         Self {
             handle: Some(handle),
             tx,
+            group_commit,
+            metrics,
         }
     }
 
+    pub fn set_group_commit(&self, group_commit: GroupCommit) {
+        *self.group_commit.lock().unwrap() = group_commit;
+    }
+
+    pub fn metrics(&self) -> CommitMetrics {
+        *self.metrics.lock().unwrap()
+    }
+
     pub fn request(
         &self,
         transaction: StartedTransaction,
+        isolation: IsolationLevel,
         pages: HashMap<PageIndex, TransactionPage>,
+        changes: Option<Vec<Change>>,
     ) -> Result<(), StorageError<InMemoryPageId>> {
         let is_done = Arc::pin(Futex::new(0));
         let response = Arc::new(Mutex::new(None));
@@ -269,7 +711,9 @@ impl Committer {
                 is_done: is_done.clone(),
                 response: response.clone(),
                 transaction,
+                isolation,
                 pages,
+                changes,
             })
             .unwrap();
 
@@ -1,19 +1,36 @@
+use std::time::Duration;
+
 use bytemuck::must_cast_ref;
 use tracing::{debug, error};
 
 use crate::storage::in_memory::block::Block;
+use crate::storage::in_memory::durability::Durability;
+use crate::storage::in_memory::version_manager::change_feed::{
+    ChangeCapture, ChangeFeed, ChangeSubscription,
+};
 use crate::storage::in_memory::version_manager::committer::Committer;
-use crate::storage::in_memory::version_manager::recycled_pages::Recycler;
+pub use crate::storage::in_memory::version_manager::committer::{
+    BatchMetrics, CommitMetrics, GroupCommit,
+};
+use crate::storage::in_memory::version_manager::recycled_pages::{FreedPages, Recycler};
 use crate::storage::in_memory::version_manager::transaction::{
-    PageReadGuard, PageWriteGuard, UninitializedPageGuard, VersionManagedTransaction,
+    PageReadGuard, PageWriteGuard, UninitializedPageGuard, VersionManagedReadTransaction,
+    VersionManagedTransaction,
 };
-use crate::storage::in_memory::version_manager::transaction_log::TransactionLog;
-use crate::storage::in_memory::version_manager::vacuum::Vacuum;
+use crate::storage::in_memory::version_manager::transaction_log::{
+    Retention, RunningTransaction, TransactionActivity, TransactionLog, TransactionTimeouts,
+};
+use crate::storage::in_memory::version_manager::vacuum::{DirtyPages, Vacuum};
+pub use crate::storage::in_memory::version_manager::vacuum::{VacuumReport, VacuumSchedule};
 use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
 use crate::storage::in_memory::{Bitmap, InMemoryPageId};
-use crate::storage::{PageIndex, StorageError, TransactionId, TransactionalTimestamp};
-use crate::sync::Arc;
+use crate::storage::{
+    IsolationLevel, PageIndex, StorageError, TransactionId, TransactionalTimestamp,
+};
+use crate::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::{Arc, Mutex};
 
+pub mod change_feed;
 mod committer;
 mod recycled_pages;
 pub mod transaction;
@@ -25,6 +42,12 @@ pub mod versioned_page;
 pub struct VersionedBlock {
     block: Block,
     freemap: Bitmap,
+    freed_pages: FreedPages,
+    dirty_pages: DirtyPages,
+    // the number of pages in the freemap
+    free_page_count: AtomicU64,
+    // the pages taken out of the freemap to give their memory back to the OS
+    decommitted_pages: Mutex<Vec<PageIndex>>,
 }
 
 impl VersionedBlock {
@@ -32,6 +55,10 @@ impl VersionedBlock {
         Self {
             block: Block::new("storage".to_string()),
             freemap: Bitmap::new("freemap".to_string()),
+            freed_pages: FreedPages::new(),
+            dirty_pages: DirtyPages::new(),
+            free_page_count: AtomicU64::new(0),
+            decommitted_pages: Mutex::new(vec![]),
         }
     }
 
@@ -100,6 +127,10 @@ impl VersionedBlock {
         PageReadGuard::new(main_lock)
     }
 
+    fn contains(&self, index: PageIndex) -> bool {
+        self.block.contains(index)
+    }
+
     fn try_get(&'_ self, index: PageIndex) -> Option<PageReadGuard<'_>> {
         self.block.try_get(index).map(PageReadGuard::new)
     }
@@ -116,12 +147,91 @@ impl VersionedBlock {
         self.block.allocated_page_count()
     }
 
+    const fn page_count() -> u64 {
+        Block::page_count()
+    }
+
+    /// Appends a page loaded from persistent storage to the end of the block. Empty slots are
+    /// put straight into the freemap. This must only be used before any transactions have
+    /// started.
+    // TODO avoid passing by value
+    #[allow(clippy::large_types_passed_by_value)]
+    pub(crate) fn load_page(
+        &self,
+        page: Option<VersionedPage>,
+    ) -> Result<PageIndex, StorageError<InMemoryPageId>> {
+        let guard = self.allocate()?;
+        let physical_index = guard.physical_index();
+
+        if let Some(page) = page {
+            drop(guard.initialize(page));
+        } else {
+            drop(guard);
+
+            self.freemap.set(physical_index.0)?;
+            self.free_page_count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(physical_index)
+    }
+
     fn take_free_pages(&self, max_count: usize) -> Vec<PageIndex> {
-        self.freemap
+        let pages: Vec<_> = self
+            .freemap
             .find_and_unset(max_count)
             .into_iter()
             .map(|x| PageIndex(x as u64))
-            .collect()
+            .collect();
+
+        self.free_page_count
+            .fetch_sub(pages.len() as u64, Ordering::Relaxed);
+
+        pages
+    }
+
+    fn free_page_count(&self) -> u64 {
+        self.free_page_count.load(Ordering::Relaxed)
+    }
+
+    /// Takes up to `max_count` pages out of the freemap and gives their memory back to the OS.
+    /// Returns the number of pages decommitted.
+    fn decommit_free_pages(&self, max_count: usize) -> usize {
+        let pages = self.take_free_pages(max_count);
+
+        for index in &pages {
+            self.block.decommit(*index);
+        }
+
+        let count = pages.len();
+        self.decommitted_pages.lock().unwrap().extend(pages);
+
+        count
+    }
+
+    /// Takes up to `max_count` of the decommitted pages, committing them again so that they can be
+    /// initialized.
+    fn take_decommitted_pages(&self, max_count: usize) -> Vec<PageIndex> {
+        let mut decommitted_pages = self.decommitted_pages.lock().unwrap();
+        let remaining = decommitted_pages.len().saturating_sub(max_count);
+        let pages = decommitted_pages.split_off(remaining);
+        drop(decommitted_pages);
+
+        for index in &pages {
+            self.block.recommit(*index);
+        }
+
+        pages
+    }
+
+    /// Marks a page allocated by a transaction that didn't commit as free. The page only gets
+    /// reused once vacuum puts it in the freemap.
+    fn discard(&self, mut page_guard: PageWriteGuard) {
+        let physical_index = page_guard.physical_index();
+
+        page_guard.mark_free();
+        drop(page_guard);
+
+        self.dirty_pages.push(physical_index);
     }
 
     fn free_page(&self, page_guard: PageWriteGuard) {
@@ -139,10 +249,12 @@ impl VersionedBlock {
         drop(page_guard.reset());
 
         self.freemap.set(physical_index.0).unwrap();
+        self.free_page_count.fetch_add(1, Ordering::Relaxed);
+        self.freed_pages.notify();
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum TransactionPageAction {
     Read,
     Delete,
@@ -151,18 +263,31 @@ enum TransactionPageAction {
     Insert,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct TransactionPage {
     logical_index: PageIndex,
     action: TransactionPageAction,
 }
 
+impl TransactionPage {
+    /// The physical index of the page the transaction allocated for this entry (the copy of an
+    /// updated page, or the inserted page itself), if there is one.
+    const fn allocated_page(&self) -> Option<PageIndex> {
+        match self.action {
+            TransactionPageAction::Read | TransactionPageAction::Delete => None,
+            TransactionPageAction::Update(cow) => Some(cow),
+            TransactionPageAction::Insert => Some(self.logical_index),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct VersionManager {
     data: Arc<VersionedBlock>,
     // TODO rename -> freemap
     committer: Committer,
     transaction_log: Arc<TransactionLog>,
+    change_feed: Arc<ChangeFeed>,
     // TODO instead of a mutex, we should probably have per-thread queues or something (a lock-free ring-buffer
     // perhaps?)
     // TODO sending raw pointers kinda sucks, we probably should just do PageIndices?
@@ -173,24 +298,116 @@ unsafe impl Send for VersionManager {}
 unsafe impl Sync for VersionManager {}
 
 impl VersionManager {
-    pub fn new(data: Arc<VersionedBlock>) -> Self {
+    pub fn new(data: Arc<VersionedBlock>, durability: Option<Arc<dyn Durability>>) -> Self {
         let log = Arc::new(TransactionLog::new());
         let vacuum = Vacuum::start(log.clone(), data.clone());
+        let change_feed = Arc::new(ChangeFeed::new());
 
         Self {
-            committer: Committer::new(data.clone(), log.clone()),
+            committer: Committer::new(data.clone(), log.clone(), change_feed.clone(), durability),
             // TODO this should be an argument probably? and we should have some sorta storage
             // loader or something that'll load data from disk (or create new files/memory
             // structures)
             transaction_log: log,
+            change_feed,
             data: data.clone(),
             recycled_pages: Recycler::new(data, vacuum),
         }
     }
 
-    pub fn start_transaction(&self) -> VersionManagedTransaction<'_> {
+    pub fn start_transaction(&self, isolation: IsolationLevel) -> VersionManagedTransaction<'_> {
+        let id = TransactionId::next();
+        let activity = Arc::new(TransactionActivity::default());
+
+        VersionManagedTransaction::new(
+            id,
+            self,
+            self.transaction_log.start_transaction(id, activity.clone()),
+            activity,
+            isolation,
+        )
+    }
+
+    pub fn start_read_transaction(&self) -> VersionManagedReadTransaction<'_> {
+        let id = TransactionId::next();
+        let activity = Arc::new(TransactionActivity::default());
+
+        VersionManagedReadTransaction::new(
+            id,
+            self,
+            self.transaction_log.start_transaction(id, activity.clone()),
+            activity,
+        )
+    }
+
+    pub fn start_transaction_as_of(
+        &self,
+        timestamp: TransactionalTimestamp,
+    ) -> Result<VersionManagedReadTransaction<'_>, StorageError<InMemoryPageId>> {
         let id = TransactionId::next();
+        let activity = Arc::new(TransactionActivity::default());
+
+        let log_entry = self
+            .transaction_log
+            .start_transaction_as_of(id, timestamp, activity.clone())
+            .ok_or(StorageError::SnapshotUnavailable(timestamp))?;
+
+        Ok(VersionManagedReadTransaction::new(
+            id, self, log_entry, activity,
+        ))
+    }
+
+    pub fn latest_commit(&self) -> Option<TransactionalTimestamp> {
+        self.transaction_log.latest_commit()
+    }
+
+    pub fn set_retention(&self, retention: Retention) {
+        self.transaction_log.set_retention(retention);
+    }
+
+    pub fn set_transaction_timeouts(&self, timeouts: TransactionTimeouts) {
+        self.transaction_log.set_timeouts(timeouts);
+    }
+
+    pub fn running_transactions(&self) -> Vec<RunningTransaction> {
+        self.transaction_log.running_transactions()
+    }
+
+    pub fn set_group_commit(&self, group_commit: GroupCommit) {
+        self.committer.set_group_commit(group_commit);
+    }
+
+    pub fn commit_metrics(&self) -> CommitMetrics {
+        self.committer.metrics()
+    }
+
+    pub fn set_allocation_timeout(&self, timeout: Option<Duration>) {
+        self.recycled_pages.set_allocation_timeout(timeout);
+    }
+
+    pub fn set_vacuum_schedule(&self, schedule: VacuumSchedule) {
+        self.recycled_pages.set_vacuum_schedule(schedule);
+    }
+
+    pub fn vacuum_now(&self) -> VacuumReport {
+        self.recycled_pages.vacuum_now()
+    }
+
+    pub fn set_change_capture(&self, capture: ChangeCapture) {
+        self.change_feed
+            .set_capture(capture, || self.transaction_log.latest_started_commit());
+    }
+
+    pub fn subscribe(
+        &self,
+        after: Option<TransactionalTimestamp>,
+    ) -> Result<ChangeSubscription, StorageError<InMemoryPageId>> {
+        self.change_feed.subscribe(after)
+    }
+}
 
-        VersionManagedTransaction::new(id, self, self.transaction_log.start_transaction(id))
+impl Drop for VersionManager {
+    fn drop(&mut self) {
+        self.change_feed.close();
     }
 }
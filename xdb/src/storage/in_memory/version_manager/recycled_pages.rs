@@ -1,30 +1,91 @@
+use std::pin::Pin;
 use std::time::{Duration, Instant};
 
-use tracing::{debug, trace};
+use tracing::{debug, info, trace, warn};
 
+use crate::platform::futex::Futex;
 use crate::storage::PageIndex;
 use crate::storage::in_memory::version_manager::VersionedBlock;
 use crate::storage::in_memory::version_manager::transaction::UninitializedPageGuard;
-use crate::storage::in_memory::version_manager::vacuum::Vacuum;
+use crate::storage::in_memory::version_manager::vacuum::{Vacuum, VacuumReport, VacuumSchedule};
+use crate::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use crate::sync::{Arc, Mutex};
 
+/// How long an allocation that ran out of space waits at most before looking for a free page
+/// again, in case it missed one (e.g. because the queue of recycled pages was locked).
+const MAX_WAIT_FOR_FREE_PAGE: Duration = Duration::from_millis(100);
+
+/// How many of the decommitted pages a scan takes at most, as each of them costs a syscall to
+/// commit again.
+const MAX_RECOMMITTED_PAGES: usize = 1000;
+
+/// Lets the allocations that ran out of space sleep until vacuum frees some pages.
+#[derive(Debug)]
+pub struct FreedPages {
+    // incremented (with wrapping) every time a page is freed
+    generation: Pin<Box<Futex>>,
+    waiters: AtomicU32,
+}
+
+impl FreedPages {
+    pub fn new() -> Self {
+        Self {
+            generation: Box::pin(Futex::new(0)),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation.as_ref().atomic().load(Ordering::SeqCst)
+    }
+
+    pub fn notify(&self) {
+        self.generation
+            .as_ref()
+            .atomic()
+            .fetch_add(1, Ordering::SeqCst);
+
+        // the waiters are only counted so that freeing a page doesn't cost a syscall otherwise
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.generation.as_ref().wake_all();
+        }
+    }
+
+    /// Waits until a page is freed after the `generation` was read, or the `timeout` passes.
+    pub fn wait(&self, generation: u32, timeout: Option<Duration>) {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+
+        self.generation.as_ref().wait(generation, timeout);
+
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 #[derive(Debug)]
 pub struct Recycler {
     pages: Mutex<Vec<PageIndex>>,
     data: Arc<VersionedBlock>,
     last_free_page_scan: Mutex<Option<Instant>>,
-    #[allow(unused)] // TODO who should own vacuum?
+    allocation_timeout: Mutex<Option<Duration>>,
+    // the number of allocated pages past which every allocation requests a vacuum run
+    high_water_mark: AtomicU64,
+    // TODO who should own vacuum?
     vacuum: Vacuum,
 }
 
 unsafe impl Send for Recycler {}
 
 impl Recycler {
-    pub const fn new(data: Arc<VersionedBlock>, vacuum: Vacuum) -> Self {
+    pub fn new(data: Arc<VersionedBlock>, vacuum: Vacuum) -> Self {
+        let high_water_mark =
+            VacuumSchedule::default().high_water_mark(VersionedBlock::page_count());
+
         Self {
             pages: Mutex::new(vec![]),
             data,
             last_free_page_scan: Mutex::new(None),
+            allocation_timeout: Mutex::new(None),
+            high_water_mark: AtomicU64::new(high_water_mark),
             vacuum,
         }
     }
@@ -41,6 +102,12 @@ impl Recycler {
     }
 
     pub fn get_recycled_page(&self) -> Option<UninitializedPageGuard<'_>> {
+        if self.data.allocated_page_count() >= self.high_water_mark.load(Ordering::Relaxed) {
+            trace!("past the high-water mark, requesting vacuum");
+
+            self.vacuum.request_run();
+        }
+
         // don't bother with all this if there aren't many allocated pages (TODO figure out if this
         // number makes sense)
         if self.data.allocated_page_count() < 50000 {
@@ -69,9 +136,96 @@ impl Recycler {
             return None;
         }
 
+        let page = self.scan();
+
+        if page.is_none() {
+            // vacuum didn't free anything since the last scan, so it's probably not running often
+            // enough
+            self.vacuum.request_run();
+        }
+
+        page
+    }
+
+    pub fn set_vacuum_schedule(&self, schedule: VacuumSchedule) {
+        self.high_water_mark.store(
+            schedule.high_water_mark(VersionedBlock::page_count()),
+            Ordering::Relaxed,
+        );
+        self.vacuum.set_schedule(schedule);
+    }
+
+    pub fn vacuum_now(&self) -> VacuumReport {
+        self.vacuum.run_now()
+    }
+
+    /// Sets how long the allocations wait for vacuum to free a page once the storage is out of
+    /// space, before failing with `StorageError::OutOfSpace`. `None` means waiting forever.
+    pub fn set_allocation_timeout(&self, timeout: Option<Duration>) {
+        *self.allocation_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Waits for vacuum to free a page, for the allocations that ran out of space. Returns `None`
+    /// if there's still no free page once the allocation timeout passes.
+    pub fn wait_for_page(&self) -> Option<UninitializedPageGuard<'_>> {
+        let timeout = *self.allocation_timeout.lock().unwrap();
+        let start = Instant::now();
+        let mut warned = false;
+
+        loop {
+            // read before looking for a page, so that if one gets freed in the meantime, the wait
+            // ends right away
+            let generation = self.data.freed_pages.generation();
+
+            // there's no point in limiting how often the scans happen, as nothing else can be
+            // allocated anyway
+            if let Some(page) = self.next().or_else(|| self.scan()) {
+                info!(
+                    physical_index = ?page.physical_index(),
+                    waited = ?start.elapsed(),
+                    "allocated a recycled page",
+                );
+
+                return Some(page);
+            }
+
+            let waited = start.elapsed();
+
+            let remaining = match timeout {
+                Some(timeout) => {
+                    let Some(remaining) = timeout.checked_sub(waited).filter(|x| !x.is_zero())
+                    else {
+                        warn!(?waited, "gave up waiting for a free page");
+
+                        return None;
+                    };
+
+                    remaining.min(MAX_WAIT_FOR_FREE_PAGE)
+                }
+                None => MAX_WAIT_FOR_FREE_PAGE,
+            };
+
+            self.vacuum.request_run();
+
+            if !warned && waited > MAX_WAIT_FOR_FREE_PAGE {
+                warn!(?waited, "waiting for a free page");
+
+                warned = true;
+            }
+
+            self.data.freed_pages.wait(generation, Some(remaining));
+        }
+    }
+
+    fn scan(&self) -> Option<UninitializedPageGuard<'_>> {
         let mut pages = self.pages.try_lock().ok()?;
         pages.append(&mut self.data.take_free_pages(10000));
 
+        // the decommitted pages have been free for a while, so the recently freed ones go first
+        if pages.is_empty() {
+            pages.append(&mut self.data.take_decommitted_pages(MAX_RECOMMITTED_PAGES));
+        }
+
         *self.last_free_page_scan.lock().unwrap() = Some(Instant::now());
 
         debug!(queue_length = ?pages.len(), "recycled page queue filled up");
@@ -79,3 +233,38 @@ impl Recycler {
         pages.pop().map(|page| self.data.get_uninitialized(page))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread;
+
+    #[test]
+    fn freeing_a_page_wakes_up_the_waiters() {
+        let freed_pages = Arc::new(FreedPages::new());
+        let generation = freed_pages.generation();
+
+        let waiter = {
+            let freed_pages = freed_pages.clone();
+
+            thread::spawn(move || {
+                let start = Instant::now();
+                freed_pages.wait(generation, Some(Duration::from_mins(1)));
+
+                start.elapsed()
+            })
+        };
+
+        while freed_pages.waiters.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        freed_pages.notify();
+
+        assert!(waiter.join().unwrap() < Duration::from_mins(1));
+
+        // a page freed after reading the generation ends the wait right away
+        let start = Instant::now();
+        freed_pages.wait(generation, Some(Duration::from_mins(1)));
+        assert!(start.elapsed() < Duration::from_mins(1));
+    }
+}
@@ -1,36 +1,78 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use bytemuck::{must_cast, must_cast_mut, must_cast_ref};
-use tracing::{debug, info, instrument, warn};
+use tracing::{debug, instrument};
 
 use crate::storage::in_memory::InMemoryPageId;
 use crate::storage::in_memory::block::{
     LockError, PageReadGuard as RawPageReadGuard, PageWriteGuard as RawPageWriteGuard,
     UninitializedPageGuard as RawUnitializedPageGuard,
 };
-use crate::storage::in_memory::version_manager::transaction_log::StartedTransaction;
+use crate::storage::in_memory::version_manager::transaction_log::{
+    ActivityGuard, StartedTransaction, TransactionActivity,
+};
 use crate::storage::in_memory::version_manager::{
     TransactionPage, TransactionPageAction, VersionManager, VersionedPage,
 };
-use crate::storage::{PageIndex, StorageError, TransactionId};
+use crate::storage::{Change, IsolationLevel, PageIndex, Savepoint, StorageError, TransactionId};
+use crate::sync::Arc;
+
+/// The state of a transaction at a savepoint.
+#[derive(Debug)]
+struct SavepointState {
+    id: u64,
+    pages: HashMap<PageIndex, TransactionPage>,
+    changes: usize,
+    // the physical indices of the pages allocated by the transaction before the savepoint
+    allocated: HashSet<PageIndex>,
+    // the contents of the allocated pages as of the savepoint, captured before the first write
+    // that follows it
+    images: HashMap<PageIndex, VersionedPage>,
+}
 
 pub struct VersionManagedTransaction<'storage> {
     id: TransactionId,
     pages: HashMap<PageIndex, TransactionPage>,
     version_manager: &'storage VersionManager,
     log_entry: StartedTransaction,
+    isolation: IsolationLevel,
+    savepoints: Vec<SavepointState>,
+    next_savepoint_id: u64,
+    // the allocated pages that are no longer used by the transaction, but can't be freed yet, as
+    // it could still be rolled back to a savepoint that uses them
+    retained: HashSet<PageIndex>,
+    // `None` if the change feed was off when the transaction started
+    changes: Option<Vec<Change>>,
+    activity: Arc<TransactionActivity>,
+    deadline: Option<Instant>,
     committed: bool,
 }
 
+/// Keeps the transaction from being timed out for the duration of an operation, or fails if it
+/// already timed out, either by passing its own deadline, or by getting aborted by the log.
+fn enter(
+    id: TransactionId,
+    activity: &Arc<TransactionActivity>,
+    deadline: Option<Instant>,
+) -> Result<ActivityGuard, StorageError<InMemoryPageId>> {
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(StorageError::TimedOut(id));
+    }
+
+    activity.enter().ok_or(StorageError::TimedOut(id))
+}
+
 impl Debug for VersionManagedTransaction<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("VersionManagedTransaction")
             .field("id", &self.id)
             .field("log_entry", &self.log_entry)
+            .field("isolation", &self.isolation)
+            .field("savepoints", &self.savepoints.len())
+            .field("deadline", &self.deadline)
             .field("committed", &self.committed)
             .finish()
     }
@@ -121,7 +163,7 @@ pub struct UninitializedPageGuard<'storage>(RawUnitializedPageGuard<'storage>);
 impl<'storage> UninitializedPageGuard<'storage> {
     // TODO don't pass the whole thing by value if we can?
     #[allow(clippy::large_types_passed_by_value)]
-    fn initialize(self, page: VersionedPage) -> PageWriteGuard<'storage> {
+    pub(super) fn initialize(self, page: VersionedPage) -> PageWriteGuard<'storage> {
         let raw_page_guard = self.0.initialize(must_cast(page));
 
         PageWriteGuard(raw_page_guard)
@@ -141,12 +183,21 @@ impl<'storage> VersionManagedTransaction<'storage> {
         id: TransactionId,
         version_manager: &'storage VersionManager,
         log_entry: StartedTransaction,
+        activity: Arc<TransactionActivity>,
+        isolation: IsolationLevel,
     ) -> Self {
         Self {
             id,
             pages: HashMap::new(),
             version_manager,
             log_entry,
+            isolation,
+            savepoints: vec![],
+            next_savepoint_id: 0,
+            retained: HashSet::new(),
+            changes: version_manager.change_feed.is_enabled().then(Vec::new),
+            activity,
+            deadline: None,
             committed: false,
         }
     }
@@ -171,29 +222,11 @@ impl<'storage> VersionManagedTransaction<'storage> {
                 );
                 Ok(guard)
             }
-            Err(StorageError::OutOfSpace) => {
-                // TODO we should have some mechanism to use to ask vacuum to wake us up when pages
-                // are available
-                let start = Instant::now();
-                loop {
-                    let waited = start.elapsed();
-
-                    if let Some(page) = self.version_manager.recycled_pages.get_recycled_page() {
-                        info!(
-                            physical_index = ?page.physical_index(),
-                            ?waited,
-                            "allocated a recycled page",
-                        );
-                        return Ok(page);
-                    }
-
-                    thread::yield_now();
-
-                    if waited > Duration::from_millis(100) {
-                        warn!(?waited, "waiting for a free cow page");
-                    }
-                }
-            }
+            Err(StorageError::OutOfSpace) => self
+                .version_manager
+                .recycled_pages
+                .wait_for_page()
+                .ok_or(StorageError::OutOfSpace),
             Err(e) => Err(e),
         }
     }
@@ -203,6 +236,8 @@ impl<'storage> VersionManagedTransaction<'storage> {
         &mut self,
         index: PageIndex,
     ) -> Result<PageReadGuard<'storage>, StorageError<InMemoryPageId>> {
+        let _activity = enter(self.id, &self.activity, self.deadline)?;
+
         if let Some(entry) = self.pages.get(&index) {
             assert!(entry.logical_index == index);
 
@@ -228,6 +263,10 @@ impl<'storage> VersionManagedTransaction<'storage> {
                 }
             }
         } else {
+            if !self.version_manager.data.contains(index) {
+                return Err(StorageError::PageNotFound(InMemoryPageId(index)));
+            }
+
             self.pages.insert(
                 index,
                 TransactionPage {
@@ -249,7 +288,9 @@ impl<'storage> VersionManagedTransaction<'storage> {
         &mut self,
         index: PageIndex,
     ) -> Result<PageWriteGuard<'storage>, StorageError<InMemoryPageId>> {
-        if let Some(entry) = self.pages.get_mut(&index) {
+        let _activity = enter(self.id, &self.activity, self.deadline)?;
+
+        if let Some(entry) = self.pages.get(&index) {
             assert!(entry.logical_index == index);
 
             match entry.action {
@@ -258,30 +299,40 @@ impl<'storage> VersionManagedTransaction<'storage> {
                     return Err(StorageError::PageNotFound(InMemoryPageId(index)));
                 }
                 TransactionPageAction::Update(cow_page_index) => {
-                    let cow_page = self.version_manager.data.get(cow_page_index);
+                    let cow_page = self.version_manager.data.get(cow_page_index).upgrade();
+                    self.capture_image(&cow_page);
 
-                    return Ok(cow_page.upgrade());
+                    return Ok(cow_page);
                 }
                 TransactionPageAction::Insert => {
                     let main = self
                         .version_manager
                         .data
-                        .get_at(entry.logical_index, self.log_entry.started());
+                        .get_at(entry.logical_index, self.log_entry.started())
+                        .upgrade();
+                    self.capture_image(&main);
 
-                    return Ok(main.upgrade());
+                    return Ok(main);
                 }
             }
         }
 
+        if !self.version_manager.data.contains(index) {
+            return Err(StorageError::PageNotFound(InMemoryPageId(index)));
+        }
+
         let main = self
             .version_manager
             .data
             .get_at(index, self.log_entry.started());
         let versioned_page: &VersionedPage = must_cast_ref(&*main);
 
-        if versioned_page.next_version().is_some() {
-            // TODO not a deadlock, but optimistic concurrency failure
-            return Err(StorageError::Deadlock(InMemoryPageId(index)));
+        if versioned_page.is_superseded() {
+            return Err(StorageError::Conflict {
+                page: InMemoryPageId(index),
+                transaction: self.id,
+                snapshot: self.log_entry.started(),
+            });
         }
 
         let cow = self.allocate()?;
@@ -302,6 +353,8 @@ impl<'storage> VersionManagedTransaction<'storage> {
     pub(crate) fn reserve(
         &self,
     ) -> Result<UninitializedPageGuard<'storage>, StorageError<InMemoryPageId>> {
+        let _activity = enter(self.id, &self.activity, self.deadline)?;
+
         self.allocate()
     }
 
@@ -330,6 +383,8 @@ impl<'storage> VersionManagedTransaction<'storage> {
 
     #[instrument(skip(self), fields(logical_index = ?page))]
     pub(crate) fn delete(&mut self, page: PageIndex) -> Result<(), StorageError<InMemoryPageId>> {
+        let _activity = enter(self.id, &self.activity, self.deadline)?;
+
         let inserted = self.pages.insert(
             page,
             TransactionPage {
@@ -341,13 +396,16 @@ impl<'storage> VersionManagedTransaction<'storage> {
         if let Some(previous) = inserted {
             match previous.action {
                 TransactionPageAction::Update(cow) => {
-                    let mut cow_page = self.version_manager.data.get(cow).upgrade();
+                    self.release(cow);
+                }
+                TransactionPageAction::Insert => {
+                    // the page was never visible outside of this transaction, so there's nothing
+                    // to commit and it can be freed right away
+                    self.pages.remove(&page);
 
-                    cow_page.mark_free();
+                    self.release(page);
                 }
-                TransactionPageAction::Read
-                | TransactionPageAction::Delete
-                | TransactionPageAction::Insert => {}
+                TransactionPageAction::Read | TransactionPageAction::Delete => {}
             }
         }
 
@@ -356,26 +414,41 @@ impl<'storage> VersionManagedTransaction<'storage> {
 
     #[instrument(skip(self), fields(id = ?self.id))]
     pub(crate) fn commit(&mut self) -> Result<(), StorageError<InMemoryPageId>> {
+        // held until the commit is done, so that the transaction can't be timed out while the
+        // committer validates its reads
+        let _activity = enter(self.id, &self.activity, self.deadline)?;
+
         self.committed = true;
 
-        // TODO make the commit consistent in event of a crash:
-        //    1. write to a transaction log
-        //    2. fsync the transaction log
-        //    3. fsync the modified pages
-        self.version_manager
-            .committer
-            .request(self.log_entry, self.pages.drain().collect())
+        self.savepoints.clear();
+        self.free_retained();
+
+        self.version_manager.committer.request(
+            self.log_entry,
+            self.isolation,
+            self.pages.drain().collect(),
+            self.changes.take(),
+        )
     }
 
     #[instrument(skip(self), fields(id = ?self.id))]
     pub fn rollback(&mut self) {
         // TODO instead of dealing with this directly here, we should send a request to committer
         debug!("rolling back");
+
+        self.savepoints.clear();
+        self.free_retained();
+
         for (index, page) in self.pages.drain() {
             match page.action {
-                TransactionPageAction::Read
-                | TransactionPageAction::Delete
-                | TransactionPageAction::Insert => {}
+                TransactionPageAction::Read | TransactionPageAction::Delete => {}
+                TransactionPageAction::Insert => {
+                    debug!(logical_index = ?index, "freeing an inserted page");
+
+                    let inserted_page = self.version_manager.data.get(index).upgrade();
+
+                    self.version_manager.data.discard(inserted_page);
+                }
                 TransactionPageAction::Update(cow) => {
                     let cow_page = self.version_manager.data.get(cow);
 
@@ -384,14 +457,241 @@ impl<'storage> VersionManagedTransaction<'storage> {
                         physical_index = ?cow_page.physical_index(),
                         "setting page up to be freed"
                     );
-                    let mut cow_lock = cow_page.upgrade();
-                    cow_lock.mark_free();
+                    self.version_manager.data.discard(cow_page.upgrade());
                 }
             }
         }
+
+        self.version_manager
+            .transaction_log
+            .rollback(self.log_entry);
+    }
+
+    pub const fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    /// Makes all the operations after `deadline` fail with `StorageError::TimedOut`. Once it
+    /// passes, vacuum also stops taking the transaction into account.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+
+        self.version_manager
+            .transaction_log
+            .set_deadline(self.log_entry, deadline);
+    }
+
+    pub(crate) const fn captures_changes(&self) -> bool {
+        self.changes.is_some()
+    }
+
+    pub(crate) fn record_change(&mut self, change: Change) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(change);
+        }
+    }
+
+    #[instrument(skip(self), fields(id = ?self.id))]
+    pub(crate) fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint_id;
+        self.next_savepoint_id += 1;
+
+        self.savepoints.push(SavepointState {
+            id,
+            pages: self.pages.clone(),
+            changes: self.changes.as_ref().map_or(0, Vec::len),
+            allocated: self
+                .pages
+                .values()
+                .filter_map(TransactionPage::allocated_page)
+                .collect(),
+            images: HashMap::new(),
+        });
+
+        Savepoint::new(self.savepoints.len() - 1, id)
+    }
+
+    #[instrument(skip(self), fields(id = ?self.id))]
+    pub(crate) fn rollback_to(
+        &mut self,
+        savepoint: Savepoint,
+    ) -> Result<(), StorageError<InMemoryPageId>> {
+        if self
+            .savepoints
+            .get(savepoint.depth())
+            .is_none_or(|x| x.id != savepoint.id())
+        {
+            return Err(StorageError::InvalidSavepoint(savepoint));
+        }
+
+        let later = self.savepoints.split_off(savepoint.depth() + 1);
+        let target = self.savepoints.last_mut().unwrap();
+
+        // A later savepoint only has an image of a page if it wasn't written between the target
+        // and that savepoint, so the image is also the state as of the target. The images of the
+        // target take precedence, as they're the only ones captured before any such write.
+        let mut images = HashMap::new();
+        for state in later.into_iter().rev() {
+            images.extend(state.images);
+        }
+        images.extend(target.images.drain());
+
+        for (physical_index, image) in images {
+            if target.allocated.contains(&physical_index) {
+                *self.version_manager.data.get(physical_index).upgrade() = image;
+            }
+        }
+
+        let mut pages = target.pages.clone();
+        let mut allocated: HashSet<PageIndex> = self.retained.drain().collect();
+
+        for (index, page) in self.pages.drain() {
+            allocated.extend(page.allocated_page());
+
+            // the pages that were read stay in the transaction, so that they still get validated
+            // with serializable isolation
+            if !matches!(page.action, TransactionPageAction::Insert) {
+                pages.entry(index).or_insert(TransactionPage {
+                    logical_index: index,
+                    action: TransactionPageAction::Read,
+                });
+            }
+        }
+
+        let target_allocated = target.allocated.clone();
+        self.pages = pages;
+
+        if let Some(changes) = &mut self.changes {
+            changes.truncate(target.changes);
+        }
+
+        for physical_index in allocated {
+            if !target_allocated.contains(&physical_index) {
+                self.release(physical_index);
+            }
+        }
+
+        debug!(
+            ?savepoint,
+            page_count = self.pages.len(),
+            "rolled back to a savepoint"
+        );
+
+        Ok(())
+    }
+
+    /// Keeps the contents of a page the transaction allocated before the latest savepoint, so that
+    /// they can be restored if it's rolled back to.
+    fn capture_image(&mut self, page: &PageWriteGuard) {
+        let Some(latest) = self.savepoints.last_mut() else {
+            return;
+        };
+
+        let physical_index = page.physical_index();
+
+        if latest.allocated.contains(&physical_index) {
+            latest.images.entry(physical_index).or_insert(**page);
+        }
+    }
+
+    /// Frees a page allocated by the transaction that's no longer used, unless one of the
+    /// savepoints still needs it.
+    fn release(&mut self, physical_index: PageIndex) {
+        if self
+            .savepoints
+            .iter()
+            .any(|x| x.allocated.contains(&physical_index))
+        {
+            self.retained.insert(physical_index);
+
+            return;
+        }
+
+        debug!(
+            ?physical_index,
+            "freeing a page allocated by the transaction"
+        );
+
+        let page = self.version_manager.data.get(physical_index).upgrade();
+        self.version_manager.data.discard(page);
+    }
+
+    fn free_retained(&mut self) {
+        for physical_index in self.retained.drain() {
+            let page = self.version_manager.data.get(physical_index).upgrade();
+            self.version_manager.data.discard(page);
+        }
+    }
+}
+
+/// A transaction that only reads the pages visible at its snapshot, without keeping track of them,
+/// as there's nothing to validate or commit.
+pub struct VersionManagedReadTransaction<'storage> {
+    id: TransactionId,
+    version_manager: &'storage VersionManager,
+    log_entry: StartedTransaction,
+    activity: Arc<TransactionActivity>,
+    deadline: Option<Instant>,
+}
+
+impl Debug for VersionManagedReadTransaction<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VersionManagedReadTransaction")
+            .field("id", &self.id)
+            .field("log_entry", &self.log_entry)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl Drop for VersionManagedReadTransaction<'_> {
+    fn drop(&mut self) {
+        self.version_manager.transaction_log.finish(self.log_entry);
+    }
+}
+
+impl<'storage> VersionManagedReadTransaction<'storage> {
+    pub const fn new(
+        id: TransactionId,
+        version_manager: &'storage VersionManager,
+        log_entry: StartedTransaction,
+        activity: Arc<TransactionActivity>,
+    ) -> Self {
+        Self {
+            id,
+            version_manager,
+            log_entry,
+            activity,
+            deadline: None,
+        }
+    }
+
+    #[instrument(skip(self, index), fields(logical_index=?index))]
+    pub(crate) fn read(
+        &self,
+        index: PageIndex,
+    ) -> Result<PageReadGuard<'storage>, StorageError<InMemoryPageId>> {
+        let _activity = enter(self.id, &self.activity, self.deadline)?;
+
+        if !self.version_manager.data.contains(index) {
+            return Err(StorageError::PageNotFound(InMemoryPageId(index)));
+        }
+
+        Ok(self
+            .version_manager
+            .data
+            .get_at(index, self.log_entry.started()))
     }
 
     pub const fn id(&self) -> TransactionId {
         self.id
     }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+
+        self.version_manager
+            .transaction_log
+            .set_deadline(self.log_entry, deadline);
+    }
 }
@@ -1,18 +1,183 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::storage::{TransactionId, TransactionalTimestamp};
+use crate::sync::Arc;
 use crate::sync::atomic::{AtomicU64, Ordering};
 
+/// Decides which versions vacuum keeps, on top of the ones visible to the running transactions, so
+/// that they can still be read by transactions started as of a past commit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    window: Option<Duration>,
+    pinned: Option<TransactionalTimestamp>,
+}
+
+impl Retention {
+    /// Keeps the data as of every commit made within the `window` (and as of the last one made
+    /// before it). Only the commits made after the retention was set are taken into account.
+    #[must_use]
+    pub const fn with_window(self, window: Duration) -> Self {
+        Self {
+            window: Some(window),
+            ..self
+        }
+    }
+
+    /// Keeps the data as of the commit at `timestamp` and every commit after it.
+    #[must_use]
+    pub const fn with_pinned(self, timestamp: TransactionalTimestamp) -> Self {
+        Self {
+            pinned: Some(timestamp),
+            ..self
+        }
+    }
+}
+
+/// Decides what happens to the transactions that keep running for too long.
+///
+/// Such transactions keep vacuum from removing the versions they can see. A transaction that is
+/// aborted stops holding vacuum back, and all of its later operations fail with
+/// `StorageError::TimedOut`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransactionTimeouts {
+    warn_after: Option<Duration>,
+    abort_after: Option<Duration>,
+}
+
+impl TransactionTimeouts {
+    /// Logs a warning (once) about every transaction that runs for longer than `age`.
+    #[must_use]
+    pub const fn with_warning_after(self, age: Duration) -> Self {
+        Self {
+            warn_after: Some(age),
+            ..self
+        }
+    }
+
+    /// Aborts every transaction that runs for longer than `age`.
+    #[must_use]
+    pub const fn with_abort_after(self, age: Duration) -> Self {
+        Self {
+            abort_after: Some(age),
+            ..self
+        }
+    }
+}
+
+/// A transaction that is holding back vacuum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunningTransaction {
+    id: TransactionId,
+    snapshot: TransactionalTimestamp,
+    age: Duration,
+    deadline: Option<Instant>,
+}
+
+impl RunningTransaction {
+    #[must_use]
+    pub const fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    #[must_use]
+    pub const fn snapshot(&self) -> TransactionalTimestamp {
+        self.snapshot
+    }
+
+    #[must_use]
+    pub const fn age(&self) -> Duration {
+        self.age
+    }
+
+    #[must_use]
+    pub const fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+const TIMED_OUT: u64 = 1 << 63;
+
+/// Shared between a transaction and the log, so that the log can time the transaction out, but
+/// only in between its operations, as those could be looking at the versions vacuum would remove
+/// once the transaction is gone.
+#[derive(Debug, Default)]
+pub struct TransactionActivity {
+    // the number of operations in progress, with the highest bit set once the transaction timed
+    // out
+    state: AtomicU64,
+}
+
+impl TransactionActivity {
+    /// Keeps the transaction from timing out until the returned guard is dropped. Returns `None`
+    /// if it has already timed out.
+    pub fn enter(self: &Arc<Self>) -> Option<ActivityGuard> {
+        let previous = self.state.fetch_add(1, Ordering::AcqRel);
+
+        if previous & TIMED_OUT != 0 {
+            self.state.fetch_sub(1, Ordering::AcqRel);
+
+            return None;
+        }
+
+        Some(ActivityGuard(self.clone()))
+    }
+
+    fn try_time_out(&self) -> bool {
+        self.state
+            .compare_exchange(0, TIMED_OUT, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct ActivityGuard(Arc<TransactionActivity>);
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        self.0.state.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug)]
+struct TransactionEntry {
+    since: Instant,
+    deadline: Option<Instant>,
+    activity: Arc<TransactionActivity>,
+    warned: bool,
+}
+
+impl TransactionEntry {
+    fn new(activity: Arc<TransactionActivity>) -> Self {
+        Self {
+            since: Instant::now(),
+            deadline: None,
+            activity,
+            warned: false,
+        }
+    }
+}
+
 #[derive(Debug)]
 // TODO the data structures are very wacky here in general, we need to store the log in a Storage +
 // figure out how to best keep an in-memory state
 pub struct TransactionLog {
     next_timestamp: AtomicU64,
-    running_transactions: Mutex<BTreeMap<TransactionalTimestamp, TransactionId>>,
+    running_transactions:
+        Mutex<BTreeMap<(TransactionalTimestamp, TransactionId), TransactionEntry>>,
+    latest_commit: AtomicU64,
+    // the newest timestamp given to a commit, which could still be in progress
+    latest_started_commit: AtomicU64,
+    // the newest horizon vacuum used, the versions that were only visible before it may be gone
+    vacuum_horizon: AtomicU64,
+    retention: Mutex<Retention>,
+    // commit times, only kept while the retention has a window
+    commits: Mutex<VecDeque<(Instant, TransactionalTimestamp)>>,
+    timeouts: Mutex<TransactionTimeouts>,
 }
 
 impl TransactionLog {
@@ -20,17 +185,67 @@ impl TransactionLog {
         Self {
             next_timestamp: AtomicU64::new(1),
             running_transactions: Mutex::new(BTreeMap::new()),
+            latest_commit: AtomicU64::new(0),
+            latest_started_commit: AtomicU64::new(0),
+            vacuum_horizon: AtomicU64::new(0),
+            retention: Mutex::new(Retention {
+                window: None,
+                pinned: None,
+            }),
+            commits: Mutex::new(VecDeque::new()),
+            timeouts: Mutex::new(TransactionTimeouts {
+                warn_after: None,
+                abort_after: None,
+            }),
         }
     }
 
-    pub fn start_transaction(&'_ self, id: TransactionId) -> StartedTransaction {
+    pub fn start_transaction(
+        &'_ self,
+        id: TransactionId,
+        activity: Arc<TransactionActivity>,
+    ) -> StartedTransaction {
+        let mut running_transactions = self.running_transactions.lock().unwrap();
+
+        // the timestamp is taken with the lock held, so that vacuum can't see a state where the
+        // transaction has already started, but isn't registered yet
         let started = self.next_timestamp();
+        running_transactions.insert((started, id), TransactionEntry::new(activity));
 
         StartedTransaction { id, started }
     }
 
+    /// Starts a transaction that sees the data as it was right after the commit at `timestamp`.
+    /// Returns `None` if that commit did not happen yet, or if vacuum could have already removed
+    /// some of the versions visible as of it.
+    pub fn start_transaction_as_of(
+        &'_ self,
+        id: TransactionId,
+        timestamp: TransactionalTimestamp,
+        activity: Arc<TransactionActivity>,
+    ) -> Option<StartedTransaction> {
+        let mut running_transactions = self.running_transactions.lock().unwrap();
+
+        if Some(timestamp) > self.latest_commit() {
+            return None;
+        }
+
+        // the snapshot at a timestamp doesn't include the commit made at that timestamp
+        let started = timestamp.next();
+
+        if started.0 < self.vacuum_horizon.load(Ordering::Acquire) {
+            return None;
+        }
+
+        running_transactions.insert((started, id), TransactionEntry::new(activity));
+
+        Some(StartedTransaction { id, started })
+    }
+
     pub fn start_commit(&'_ self, transaction: StartedTransaction) -> CommitHandle<'_> {
         let timestamp = self.next_timestamp();
+        self.latest_started_commit
+            .fetch_max(timestamp.0, Ordering::AcqRel);
 
         CommitHandle {
             transaction,
@@ -39,11 +254,154 @@ impl TransactionLog {
         }
     }
 
-    pub fn minimum_active_timestamp(&self) -> Option<TransactionalTimestamp> {
-        let running_transactions = self.running_transactions.lock().unwrap();
+    /// Returns the timestamp before which vacuum can remove the versions that are no longer
+    /// visible, taking both the running transactions and the retention into account. The
+    /// transactions that timed out are aborted first, so that they don't hold it back.
+    pub fn vacuum_horizon(&self) -> Option<TransactionalTimestamp> {
+        self.horizon(false)
+    }
+
+    /// Like `vacuum_horizon`, but when there are no running transactions, the horizon is past all
+    /// the commits made so far, instead of there being nothing to do.
+    pub fn full_vacuum_horizon(&self) -> TransactionalTimestamp {
+        self.horizon(true).unwrap()
+    }
+
+    fn horizon(&self, when_idle: bool) -> Option<TransactionalTimestamp> {
+        let mut running_transactions = self.running_transactions.lock().unwrap();
+
+        self.enforce_timeouts(&mut running_transactions);
 
         debug!("running transactions: {}", running_transactions.len());
-        running_transactions.first_key_value().map(|(k, _)| *k)
+        let minimum_active_timestamp = match running_transactions.first_key_value() {
+            Some(((timestamp, _), _)) => *timestamp,
+            // the transactions take their timestamps with the lock held, so none of them can
+            // start with an older snapshot than the next timestamp
+            None if when_idle => {
+                TransactionalTimestamp(self.next_timestamp.load(Ordering::Acquire))
+            }
+            None => return None,
+        };
+
+        let horizon = self
+            .retention_horizon()
+            .map_or(minimum_active_timestamp, |retained| {
+                retained.min(minimum_active_timestamp)
+            });
+
+        self.vacuum_horizon.fetch_max(horizon.0, Ordering::AcqRel);
+
+        Some(horizon)
+    }
+
+    fn enforce_timeouts(
+        &self,
+        running_transactions: &mut BTreeMap<
+            (TransactionalTimestamp, TransactionId),
+            TransactionEntry,
+        >,
+    ) {
+        let timeouts = *self.timeouts.lock().unwrap();
+        let now = Instant::now();
+
+        running_transactions.retain(|(snapshot, id), entry| {
+            let age = now.duration_since(entry.since);
+
+            let timed_out = entry.deadline.is_some_and(|deadline| now >= deadline)
+                || timeouts.abort_after.is_some_and(|limit| age > limit);
+
+            // a transaction in the middle of an operation will be aborted the next time around
+            if timed_out && entry.activity.try_time_out() {
+                warn!(
+                    ?id,
+                    ?snapshot,
+                    ?age,
+                    "aborting a transaction that timed out"
+                );
+
+                return false;
+            }
+
+            if !entry.warned && timeouts.warn_after.is_some_and(|limit| age > limit) {
+                warn!(?id, ?snapshot, ?age, "long-running transaction");
+
+                entry.warned = true;
+            }
+
+            true
+        });
+    }
+
+    /// Lists the transactions that are holding back vacuum, starting with the oldest snapshot.
+    pub fn running_transactions(&self) -> Vec<RunningTransaction> {
+        let running_transactions = self.running_transactions.lock().unwrap();
+        let now = Instant::now();
+
+        running_transactions
+            .iter()
+            .map(|((snapshot, id), entry)| RunningTransaction {
+                id: *id,
+                snapshot: *snapshot,
+                age: now.duration_since(entry.since),
+                deadline: entry.deadline,
+            })
+            .collect()
+    }
+
+    pub fn set_deadline(&self, transaction: StartedTransaction, deadline: Instant) {
+        if let Some(entry) = self
+            .running_transactions
+            .lock()
+            .unwrap()
+            .get_mut(&(transaction.started(), transaction.id()))
+        {
+            entry.deadline = Some(deadline);
+        }
+    }
+
+    pub fn set_timeouts(&self, timeouts: TransactionTimeouts) {
+        *self.timeouts.lock().unwrap() = timeouts;
+    }
+
+    fn retention_horizon(&self) -> Option<TransactionalTimestamp> {
+        let retention = *self.retention.lock().unwrap();
+
+        let pinned = retention.pinned.map(TransactionalTimestamp::next);
+        let window = retention.window.and_then(|window| {
+            let mut commits = self.commits.lock().unwrap();
+
+            while commits.front().is_some_and(|(at, _)| at.elapsed() > window) {
+                commits.pop_front();
+            }
+
+            commits.front().map(|(_, timestamp)| *timestamp)
+        });
+
+        pinned.into_iter().chain(window).min()
+    }
+
+    pub fn set_retention(&self, retention: Retention) {
+        *self.retention.lock().unwrap() = retention;
+
+        if retention.window.is_none() {
+            self.commits.lock().unwrap().clear();
+        }
+    }
+
+    pub fn latest_commit(&self) -> Option<TransactionalTimestamp> {
+        match self.latest_commit.load(Ordering::Acquire) {
+            0 => None,
+            timestamp => Some(TransactionalTimestamp(timestamp)),
+        }
+    }
+
+    /// Like `latest_commit`, but also includes the commits that are still in progress, or that
+    /// were aborted.
+    pub fn latest_started_commit(&self) -> Option<TransactionalTimestamp> {
+        match self.latest_started_commit.load(Ordering::Acquire) {
+            0 => None,
+            timestamp => Some(TransactionalTimestamp(timestamp)),
+        }
     }
 
     fn next_timestamp(&self) -> TransactionalTimestamp {
@@ -51,10 +409,15 @@ impl TransactionLog {
     }
 
     pub fn rollback(&self, transaction: StartedTransaction) {
+        self.finish(transaction);
+    }
+
+    /// Stops the transaction from holding back vacuum.
+    pub fn finish(&self, transaction: StartedTransaction) {
         self.running_transactions
             .lock()
             .unwrap()
-            .remove(&transaction.started());
+            .remove(&(transaction.started(), transaction.id()));
     }
 }
 
@@ -79,22 +442,30 @@ impl CommitHandle<'_> {
         self.timestamp
     }
 
-    pub const fn started(&self) -> TransactionalTimestamp {
-        self.transaction.started()
+    /// Gives up on the commit, the timestamp it got assigned will never become visible.
+    pub const fn abort(self) -> StartedTransaction {
+        self.transaction
     }
 
     pub fn commit(self) {
+        if self.log.retention.lock().unwrap().window.is_some() {
+            self.log
+                .commits
+                .lock()
+                .unwrap()
+                .push_back((Instant::now(), self.timestamp));
+        }
+
         self.log
-            .running_transactions
-            .lock()
-            .unwrap()
-            .remove(&self.transaction.started());
+            .latest_commit
+            .fetch_max(self.timestamp.0, Ordering::AcqRel);
+
+        self.log.finish(self.transaction);
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct StartedTransaction {
-    #[allow(unused)]
     id: TransactionId,
     started: TransactionalTimestamp,
 }
@@ -104,8 +475,161 @@ impl StartedTransaction {
         self.started
     }
 
-    #[allow(unused)]
     pub const fn id(&self) -> TransactionId {
         self.id
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    fn commit(log: &TransactionLog) -> TransactionalTimestamp {
+        let transaction = log.start_transaction(TransactionId::next(), Arc::default());
+        let commit_handle = log.start_commit(transaction);
+        let timestamp = commit_handle.timestamp();
+
+        commit_handle.commit();
+
+        timestamp
+    }
+
+    #[test]
+    fn vacuum_horizon_follows_running_transactions() {
+        let log = TransactionLog::new();
+
+        commit(&log);
+        assert_eq!(log.vacuum_horizon(), None);
+
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+        let latest_commit = commit(&log);
+
+        assert_eq!(log.vacuum_horizon(), Some(running.started()));
+        assert_eq!(log.latest_commit(), Some(latest_commit));
+
+        log.rollback(running);
+        assert_eq!(log.vacuum_horizon(), None);
+    }
+
+    #[test]
+    fn full_vacuum_horizon_is_past_the_commits_when_idle() {
+        let log = TransactionLog::new();
+
+        let latest_commit = commit(&log);
+        assert_eq!(log.vacuum_horizon(), None);
+        assert!(log.full_vacuum_horizon() > latest_commit);
+
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+        commit(&log);
+
+        assert_eq!(log.full_vacuum_horizon(), running.started());
+        log.rollback(running);
+    }
+
+    #[test]
+    fn pinned_retention() {
+        let log = TransactionLog::new();
+
+        let pinned = commit(&log);
+        commit(&log);
+        log.set_retention(Retention::default().with_pinned(pinned));
+
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+        assert_eq!(log.vacuum_horizon(), Some(pinned.next()));
+        log.rollback(running);
+
+        let as_of = log
+            .start_transaction_as_of(TransactionId::next(), pinned, Arc::default())
+            .unwrap();
+        assert_eq!(as_of.started(), pinned.next());
+        log.rollback(as_of);
+
+        log.set_retention(Retention::default());
+
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+        assert_eq!(log.vacuum_horizon(), Some(running.started()));
+
+        // vacuum might have already removed the versions visible as of the pinned commit
+        assert!(
+            log.start_transaction_as_of(TransactionId::next(), pinned, Arc::default())
+                .is_none()
+        );
+        log.rollback(running);
+    }
+
+    #[test]
+    fn window_retention() {
+        let log = TransactionLog::new();
+
+        commit(&log);
+        log.set_retention(Retention::default().with_window(Duration::from_hours(1)));
+
+        let first_in_window = commit(&log);
+        commit(&log);
+
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+        assert_eq!(log.vacuum_horizon(), Some(first_in_window));
+        log.rollback(running);
+
+        log.set_retention(Retention::default().with_window(Duration::ZERO));
+
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+        assert_eq!(log.vacuum_horizon(), Some(running.started()));
+        log.rollback(running);
+    }
+
+    #[test]
+    fn running_transactions_are_listed() {
+        let log = TransactionLog::new();
+
+        let first = log.start_transaction(TransactionId::next(), Arc::default());
+        let second = log.start_transaction(TransactionId::next(), Arc::default());
+
+        let deadline = Instant::now() + Duration::from_hours(1);
+        log.set_deadline(second, deadline);
+
+        let running = log.running_transactions();
+        assert_eq!(
+            running
+                .iter()
+                .map(|x| (x.id(), x.snapshot(), x.deadline()))
+                .collect::<Vec<_>>(),
+            vec![
+                (first.id(), first.started(), None),
+                (second.id(), second.started(), Some(deadline))
+            ]
+        );
+        assert!(running[0].age() >= running[1].age());
+
+        log.rollback(first);
+        log.rollback(second);
+        assert!(log.running_transactions().is_empty());
+    }
+
+    #[test]
+    fn timed_out_transactions_are_aborted() {
+        let log = TransactionLog::new();
+        log.set_timeouts(TransactionTimeouts::default().with_abort_after(Duration::from_hours(1)));
+
+        let activity = Arc::new(TransactionActivity::default());
+        let expired = log.start_transaction(TransactionId::next(), activity.clone());
+        let running = log.start_transaction(TransactionId::next(), Arc::default());
+
+        log.set_deadline(expired, Instant::now());
+
+        // the transaction can't be aborted in the middle of an operation
+        let guard = activity.enter().unwrap();
+        assert_eq!(log.vacuum_horizon(), Some(expired.started()));
+        drop(guard);
+
+        assert_eq!(log.vacuum_horizon(), Some(running.started()));
+        assert!(activity.enter().is_none());
+
+        log.set_timeouts(TransactionTimeouts::default().with_abort_after(Duration::ZERO));
+        thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(log.vacuum_horizon(), None);
+    }
+}
@@ -0,0 +1,41 @@
+use std::collections::HashSet;
+use std::mem;
+
+use crate::storage::PageIndex;
+use crate::sync::Mutex;
+
+/// The pages that might have something for vacuum to remove, so that it doesn't have to look at
+/// every page in the block to find them.
+///
+/// These are the first pages of the version chains that got a new `visible_until`, and the pages
+/// the rolled back transactions left behind.
+#[derive(Debug)]
+pub struct DirtyPages {
+    pages: Mutex<HashSet<PageIndex>>,
+}
+
+impl DirtyPages {
+    pub fn new() -> Self {
+        Self {
+            pages: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn push(&self, index: PageIndex) {
+        self.pages.lock().unwrap().insert(index);
+    }
+
+    pub fn extend(&self, indices: impl IntoIterator<Item = PageIndex>) {
+        self.pages.lock().unwrap().extend(indices);
+    }
+
+    /// Takes all the pages out of the list, ordered by their indices.
+    pub fn take(&self) -> Vec<PageIndex> {
+        let mut pages: Vec<_> = mem::take(&mut *self.pages.lock().unwrap())
+            .into_iter()
+            .collect();
+        pages.sort_unstable_by_key(|x| x.0);
+
+        pages
+    }
+}
@@ -1,18 +1,59 @@
+mod dirty_pages;
 mod scheduler;
 
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
 
 use tracing::{debug, info, info_span, instrument, trace};
 
 use crate::storage::in_memory::version_manager::VersionedBlock;
 use crate::storage::in_memory::version_manager::transaction::PageWriteGuard;
-use crate::storage::in_memory::version_manager::transaction_log::TransactionLog;
-use crate::storage::in_memory::version_manager::vacuum::scheduler::Scheduler;
+use crate::storage::in_memory::version_manager::transaction_log::{
+    RunningTransaction, TransactionLog,
+};
+pub use crate::storage::in_memory::version_manager::vacuum::dirty_pages::DirtyPages;
+pub use crate::storage::in_memory::version_manager::vacuum::scheduler::VacuumSchedule;
+use crate::storage::in_memory::version_manager::vacuum::scheduler::{Scan, Scheduler};
 use crate::storage::{PageIndex, TransactionalTimestamp};
 use crate::sync::Arc;
 use crate::thread::{self, JoinHandle};
 
+/// What a vacuum run requested with `vacuum_now` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumReport {
+    scanned: u64,
+    freed: u64,
+    chains_shortened: u64,
+    oldest_blocking: Option<RunningTransaction>,
+}
+
+impl VacuumReport {
+    /// The number of times a page was looked at, the pages from the dirty page list can be
+    /// counted twice.
+    #[must_use]
+    pub const fn scanned(&self) -> u64 {
+        self.scanned
+    }
+
+    /// The number of pages that were put in the freemap.
+    #[must_use]
+    pub const fn freed(&self) -> u64 {
+        self.freed
+    }
+
+    /// The number of old versions removed from the version chains.
+    #[must_use]
+    pub const fn chains_shortened(&self) -> u64 {
+        self.chains_shortened
+    }
+
+    /// The running transaction with the oldest snapshot once the run finished, which keeps
+    /// vacuum from removing the versions it can see.
+    #[must_use]
+    pub const fn oldest_blocking(&self) -> Option<RunningTransaction> {
+        self.oldest_blocking
+    }
+}
+
 struct VacuumThread {
     // TODO we should prolly just have an Arc<VersionManager> here?
     log: Arc<TransactionLog>,
@@ -21,6 +62,9 @@ struct VacuumThread {
 
     freed_count: AtomicU64,
     checked_count: u64,
+    shortened_count: u64,
+    // the number of pages in the freemap after the previous run
+    free_after_last_run: u64,
 }
 
 impl VacuumThread {
@@ -33,69 +77,170 @@ impl VacuumThread {
                 scheduler::RequestedState::Run => {}
             }
 
-            self.scheduler.start_full_run();
+            let scan = self.scheduler.start_run();
 
-            let Some(min_timestamp) = self.log.minimum_active_timestamp() else {
-                // TODO we need a smarter way of scheduling vacuum (based on usage of the
-                // block and allocation pressure)
-                if cfg!(any(fuzzing, test)) {
-                    thread::yield_now();
-                } else {
-                    thread::sleep(Duration::from_secs(10));
-                }
+            let min_timestamp = if matches!(scan, Scan::Requested) {
+                self.log.full_vacuum_horizon()
+            } else if let Some(min_timestamp) = self.log.vacuum_horizon() {
+                min_timestamp
+            } else {
+                self.scheduler.mark_idle();
                 continue;
             };
 
-            let mut index = PageIndex::from_value(1);
-
-            let mut i = 0u64;
             self.freed_count.store(0, Ordering::Release);
             self.checked_count = 0u64;
+            self.shortened_count = 0u64;
+
+            match scan {
+                Scan::Incremental => {
+                    self.scan_dirty_pages(min_timestamp);
+                }
+                Scan::Full => {
+                    self.scan_all_pages(min_timestamp);
+                }
+                Scan::Requested => {
+                    let scanned =
+                        self.scan_dirty_pages(min_timestamp) + self.scan_all_pages(min_timestamp);
+
+                    self.scheduler.finish_run(VacuumReport {
+                        scanned,
+                        freed: self.freed_count.load(Ordering::Acquire),
+                        chains_shortened: self.shortened_count,
+                        oldest_blocking: self.log.running_transactions().first().copied(),
+                    });
+                }
+            }
+
+            self.decommit_long_free_pages();
+        }
+
+        info!("exiting vacuum thread");
+    }
+
+    /// Gives the memory of the pages that stayed in the freemap since the previous run back to the
+    /// OS, as nothing needed them in the meantime.
+    fn decommit_long_free_pages(&mut self) {
+        let freed_count = self.freed_count.load(Ordering::Acquire);
+        // the freemap hands out the lowest pages first rather than the ones that were freed the
+        // longest ago, so this is only an estimate
+        let long_free = self
+            .data
+            .free_page_count()
+            .saturating_sub(freed_count)
+            .min(self.free_after_last_run);
+
+        if long_free > 0 {
+            let decommitted = self
+                .data
+                .decommit_free_pages(usize::try_from(long_free).unwrap());
+
+            debug!(decommitted, "decommitted free pages");
+        }
+
+        self.free_after_last_run = self.data.free_page_count();
+    }
 
-            let pages_to_check = self.data.allocated_page_count();
+    /// Returns the number of pages it looked at.
+    fn scan_dirty_pages(&mut self, min_timestamp: TransactionalTimestamp) -> u64 {
+        let pages = self.data.dirty_pages.take();
+        let mut revisit = vec![];
+        let mut scanned = 0u64;
 
-            debug!(
-                minimum_active_timestamp = ?min_timestamp,
-                pages_count = ?pages_to_check,
-                "starting scan"
-            );
+        debug!(
+            minimum_active_timestamp = ?min_timestamp,
+            pages_count = pages.len(),
+            "starting incremental scan"
+        );
 
-            while i < pages_to_check {
-                let _ = info_span!("page", physical_index = ?index).entered();
+        for (i, &index) in pages.iter().enumerate() {
+            let _ = info_span!("page", physical_index = ?index).entered();
 
-                if i.is_multiple_of(10000) {
-                    match self.scheduler.requested_state() {
-                        scheduler::RequestedState::Exit => break,
-                        scheduler::RequestedState::Run => {}
-                    }
+            if i.is_multiple_of(10000) {
+                match self.scheduler.requested_state() {
+                    scheduler::RequestedState::Exit => break,
+                    scheduler::RequestedState::Run => {}
                 }
+            }
+
+            scanned += 1;
+
+            if self.vacuum_page(index, min_timestamp) {
+                revisit.push(index);
+            }
+        }
+
+        debug!(
+            freed_count = ?self.freed_count,
+            checked_count = ?self.checked_count,
+            scanned_count = ?scanned,
+            revisit_count = revisit.len(),
+            "incremental vacuum scan finished",
+        );
+
+        self.data.dirty_pages.extend(revisit);
+
+        scanned
+    }
+
+    /// Returns the number of pages it looked at. The pages from the dirty page list stay there,
+    /// as the first pages of the block are skipped.
+    fn scan_all_pages(&mut self, min_timestamp: TransactionalTimestamp) -> u64 {
+        let mut index = PageIndex::from_value(1);
+
+        let mut i = 0u64;
+
+        let pages_to_check = self.data.allocated_page_count();
+
+        debug!(
+            minimum_active_timestamp = ?min_timestamp,
+            pages_count = ?pages_to_check,
+            "starting scan"
+        );
 
-                index = index.next();
-                i += 1;
+        while i < pages_to_check {
+            let _ = info_span!("page", physical_index = ?index).entered();
 
-                self.vacuum_page(index, min_timestamp);
+            if i.is_multiple_of(10000) {
+                match self.scheduler.requested_state() {
+                    scheduler::RequestedState::Exit => break,
+                    scheduler::RequestedState::Run => {}
+                }
             }
 
-            debug!(
-                freed_count = ?self.freed_count,
-                checked_count = ?self.checked_count,
-                scanned_count = ?i,
-                total_count = ?self.data.allocated_page_count(),
-                "vacuum scan finished",
-            );
+            index = index.next();
+            i += 1;
+
+            if self.vacuum_page(index, min_timestamp) {
+                self.data.dirty_pages.push(index);
+            }
         }
 
-        info!("exiting vacuum thread");
+        debug!(
+            freed_count = ?self.freed_count,
+            checked_count = ?self.checked_count,
+            scanned_count = ?i,
+            total_count = ?self.data.allocated_page_count(),
+            "vacuum scan finished",
+        );
+
+        i
     }
 
+    /// Returns true if the page should be looked at again in a later run, because it still has
+    /// versions that can't be removed yet, or it was locked.
     #[instrument(skip(self))]
-    fn vacuum_page(&mut self, index: PageIndex, min_live_timestamp: TransactionalTimestamp) {
+    fn vacuum_page(
+        &mut self,
+        index: PageIndex,
+        min_live_timestamp: TransactionalTimestamp,
+    ) -> bool {
         let Some(page) = self.data.try_get(index) else {
-            return;
+            return false;
         };
 
         let Ok(mut page_guard) = page.try_upgrade() else {
-            return;
+            return true;
         };
 
         self.checked_count += 1;
@@ -103,37 +248,37 @@ impl VacuumThread {
         if page_guard.is_free() {
             self.free_page(page_guard);
 
-            return;
+            return false;
         }
 
         if page_guard.previous_version().is_some() {
             // we will also see the first page in the chain at some point here, and we can look
             // from there (as the versions are in a growing order, it doesn't really matter from
             // correctness standpoint, but simplifies the implementation a lot)
-            return;
+            return false;
         }
 
         let Some(visible_until) = page_guard.visible_until() else {
-            return;
+            return false;
         };
 
         if visible_until >= min_live_timestamp {
-            return;
+            return true;
         }
 
         trace!(physical_index = ?index, "trying to clean up");
 
         let Some(next_version_index) = page_guard.next_version() else {
             self.free_page(page_guard);
-            return;
+            return false;
         };
         assert!(next_version_index != index);
 
         let Some(next_version) = self.data.try_get(next_version_index) else {
-            return;
+            return true;
         };
         let Ok(next_version) = next_version.try_upgrade() else {
-            return;
+            return true;
         };
 
         assert!(
@@ -150,7 +295,7 @@ impl VacuumThread {
 
                 Some(next_next)
             } else {
-                return;
+                return true;
             }
         } else {
             None
@@ -181,9 +326,15 @@ impl VacuumThread {
         assert!(next_version.physical_index() != index);
 
         self.free_page(next_version);
+        self.shortened_count += 1;
+
+        // there might be more versions after this one that are no longer visible
+        let revisit = page_guard.visible_until().is_some();
 
         drop(next_next);
         drop(page_guard);
+
+        revisit
     }
 
     fn free_page(&self, page_guard: PageWriteGuard) {
@@ -213,7 +364,9 @@ impl Vacuum {
                         data,
                         scheduler,
                         checked_count: 0,
+                        shortened_count: 0,
                         freed_count: AtomicU64::new(0),
+                        free_after_last_run: 0,
                     };
                     runner.run();
                 })
@@ -224,6 +377,19 @@ impl Vacuum {
             scheduler,
         }
     }
+
+    /// Asks for a run to start early, because the allocations are running out of free pages.
+    pub fn request_run(&self) {
+        self.scheduler.request_run();
+    }
+
+    pub fn set_schedule(&self, schedule: VacuumSchedule) {
+        self.scheduler.set_schedule(schedule);
+    }
+
+    pub fn run_now(&self) -> VacuumReport {
+        self.scheduler.run_now()
+    }
 }
 
 impl Drop for Vacuum {
@@ -239,3 +405,94 @@ impl Drop for Vacuum {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytemuck::Zeroable as _;
+
+    use super::*;
+    use crate::storage::in_memory::version_manager::recycled_pages::Recycler;
+    use crate::storage::in_memory::version_manager::versioned_page::VersionedPage;
+
+    fn page(data: &VersionedBlock, visible_until: Option<TransactionalTimestamp>) -> PageIndex {
+        let mut page = VersionedPage::zeroed();
+        page.set_next_version(None);
+        page.set_previous_version(None);
+        page.set_visible_until(visible_until);
+
+        data.allocate().unwrap().initialize(page).physical_index()
+    }
+
+    #[test]
+    fn incremental_scans_only_visit_the_dirty_pages() {
+        let data = Arc::new(VersionedBlock::new());
+        let deleted_at = TransactionalTimestamp::zero().next();
+        let horizon = deleted_at.next();
+
+        // the full scans start past these
+        page(&data, None);
+        page(&data, None);
+
+        let dirty = page(&data, Some(deleted_at));
+        let clean = page(&data, Some(deleted_at));
+        let visible = page(&data, Some(horizon));
+        data.dirty_pages.extend([dirty, visible]);
+
+        let mut vacuum = VacuumThread {
+            log: Arc::new(TransactionLog::new()),
+            data: data.clone(),
+            scheduler: Arc::new(Scheduler::new()),
+            freed_count: AtomicU64::new(0),
+            checked_count: 0,
+            shortened_count: 0,
+            free_after_last_run: 0,
+        };
+
+        vacuum.scan_dirty_pages(horizon);
+        assert_eq!(data.take_free_pages(10), vec![dirty]);
+        // still visible at the horizon, so it has to be looked at again later
+        assert_eq!(data.dirty_pages.take(), vec![visible]);
+
+        vacuum.scan_all_pages(horizon);
+        assert_eq!(data.take_free_pages(10), vec![clean]);
+        assert_eq!(data.dirty_pages.take(), vec![visible]);
+    }
+
+    #[test]
+    fn long_free_pages_are_decommitted_until_recycled() {
+        let data = Arc::new(VersionedBlock::new());
+        let log = Arc::new(TransactionLog::new());
+        let deleted_at = TransactionalTimestamp::zero().next();
+
+        let deleted = page(&data, Some(deleted_at));
+        data.dirty_pages.push(deleted);
+
+        let mut vacuum = VacuumThread {
+            log: log.clone(),
+            data: data.clone(),
+            scheduler: Arc::new(Scheduler::new()),
+            freed_count: AtomicU64::new(0),
+            checked_count: 0,
+            shortened_count: 0,
+            free_after_last_run: 0,
+        };
+
+        vacuum.scan_dirty_pages(deleted_at.next());
+        vacuum.decommit_long_free_pages();
+        // freed by this run, so it could still be reused soon
+        assert_eq!(data.free_page_count(), 1);
+
+        vacuum.freed_count.store(0, Ordering::Release);
+        vacuum.decommit_long_free_pages();
+        assert_eq!(data.free_page_count(), 0);
+
+        let recycler = Recycler::new(data.clone(), Vacuum::start(log, data));
+        let reused = recycler.wait_for_page().unwrap();
+        assert_eq!(reused.physical_index(), deleted);
+
+        let mut page = VersionedPage::zeroed();
+        page.set_visible_until(Some(deleted_at));
+        let page = reused.initialize(page);
+        assert_eq!(page.visible_until(), Some(deleted_at));
+    }
+}
@@ -5,8 +5,90 @@ use std::time::{Duration, Instant};
 use tracing::trace;
 use xdb_proc_macros::atomic_state;
 
-use crate::sync::Mutex;
-use crate::sync::atomic::Ordering;
+use crate::storage::in_memory::version_manager::vacuum::VacuumReport;
+use crate::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::{Condvar, Mutex};
+
+/// Controls how often vacuum looks for the versions that can be removed.
+///
+/// Besides the regular runs, vacuum runs as soon as the allocations start running out of free
+/// pages, or once the number of allocated pages passes the high-water mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumSchedule {
+    interval: Duration,
+    idle_interval: Duration,
+    min_interval: Duration,
+    sweep_interval: Duration,
+    high_water_mark: u8,
+}
+
+impl Default for VacuumSchedule {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            idle_interval: Duration::from_secs(10),
+            min_interval: Duration::from_secs(1),
+            sweep_interval: Duration::from_mins(5),
+            high_water_mark: 80,
+        }
+    }
+}
+
+impl VacuumSchedule {
+    /// Sets the pause between the regular runs, counted from the start of the previous one.
+    #[must_use]
+    pub const fn with_interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Sets the pause after a run that found no running transactions, as there is nothing to
+    /// remove until some are started.
+    #[must_use]
+    pub const fn with_idle_interval(self, idle_interval: Duration) -> Self {
+        Self {
+            idle_interval,
+            ..self
+        }
+    }
+
+    /// Sets the shortest pause between the runs, including the ones requested by the
+    /// allocations, so that vacuum doesn't keep scanning the whole block over and over.
+    #[must_use]
+    pub const fn with_min_interval(self, min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            ..self
+        }
+    }
+
+    /// Sets the pause between the runs that look at all the allocated pages. The other runs only
+    /// look at the pages that were changed or abandoned since.
+    #[must_use]
+    pub const fn with_sweep_interval(self, sweep_interval: Duration) -> Self {
+        Self {
+            sweep_interval,
+            ..self
+        }
+    }
+
+    /// Sets the percentage of the block's pages that can be allocated before every allocation
+    /// requests a run.
+    #[must_use]
+    pub fn with_high_water_mark(self, high_water_mark: u8) -> Self {
+        Self {
+            high_water_mark: high_water_mark.min(100),
+            ..self
+        }
+    }
+
+    /// The number of allocated pages (out of `page_count`) past which the runs are requested.
+    pub(in crate::storage::in_memory::version_manager) fn high_water_mark(
+        self,
+        page_count: u64,
+    ) -> u64 {
+        page_count / 100 * u64::from(self.high_water_mark)
+    }
+}
 
 #[must_use]
 pub enum RequestedState {
@@ -14,10 +96,33 @@ pub enum RequestedState {
     Run,
 }
 
+/// Which pages a run looks at.
+#[must_use]
+pub enum Scan {
+    /// Only the pages from the dirty page list.
+    Incremental,
+    /// All the allocated pages, in case anything was missed by the incremental runs.
+    Full,
+    /// The pages from the dirty page list and then all the allocated pages, for someone waiting
+    /// for the run to finish. It runs even if there are no running transactions.
+    Requested,
+}
+
+/// The runs requested with `Scheduler::run_now`, numbered in the order of the requests.
+#[derive(Debug, Default)]
+struct RequestedRuns {
+    requested: u64,
+    // the newest request the current run will satisfy
+    started: u64,
+    finished: u64,
+    report: Option<VacuumReport>,
+}
+
 atomic_state!(
     SchedulerState {
         running: 1,
         exit_requested: 1,
+        run_requested: 1,
     }
 
     pub query exit_requested(Ordering::Acquire);
@@ -30,9 +135,24 @@ atomic_state!(
     }
 
     pub update(Ordering::Release, Ordering::Acquire)
-        set_running(running: bool)
+        request_run()
     {
-        action: { Some(state.with_running(running)) },
+        action: {
+            // there's no need to wake anyone up if the run was already requested
+            if state.run_requested() {
+                return None;
+            }
+
+            Some(state.with_run_requested(true))
+        },
+        ok: { self.wake_all(); },
+        err: {}
+    }
+
+    pub update(Ordering::Release, Ordering::Acquire)
+        start_run()
+    {
+        action: { Some(state.with_running(true).with_run_requested(false)) },
         ok: { self.wake_all(); }
     }
 );
@@ -47,48 +167,90 @@ impl SchedulerState {
 pub(super) struct Scheduler {
     state: Pin<Box<SchedulerState>>,
     last_finished_at: Mutex<Option<Instant>>,
+    last_sweep_at: Mutex<Option<Instant>>,
+    schedule: Mutex<VacuumSchedule>,
+    // set when the last run found no running transactions
+    idle: AtomicBool,
+    requested_runs: Mutex<RequestedRuns>,
+    // notified when a requested run finishes
+    requested_run_finished: Condvar,
 }
 
 impl Scheduler {
-    const PAUSE_LENGTH: Duration = Duration::from_secs(10);
-
     pub fn new() -> Self {
         Self {
             state: Box::pin(SchedulerState::new()),
             last_finished_at: Mutex::new(None),
+            last_sweep_at: Mutex::new(None),
+            schedule: Mutex::new(VacuumSchedule::default()),
+            idle: AtomicBool::new(false),
+            requested_runs: Mutex::new(RequestedRuns::default()),
+            requested_run_finished: Condvar::new(),
         }
     }
 
     pub(super) fn block_if_unscheduled(&self) -> RequestedState {
         loop {
+            let current_state = self.state.as_ref().current();
+
+            if current_state.exit_requested() {
+                return RequestedState::Exit;
+            }
+
+            let requested_runs = self.requested_runs.lock().unwrap();
+
+            if requested_runs.requested > requested_runs.finished {
+                drop(requested_runs);
+
+                self.state.as_ref().start_run();
+                return RequestedState::Run;
+            }
+
+            drop(requested_runs);
+
+            let schedule = *self.schedule.lock().unwrap();
+            let pause = if current_state.run_requested() {
+                schedule.min_interval
+            } else if self.idle.load(Ordering::Acquire) {
+                schedule.idle_interval
+            } else {
+                schedule.interval
+            };
+
             let elapsed_since_last_run = self
                 .last_finished_at
                 .lock()
                 .unwrap()
                 .map_or(Duration::MAX, |x| x.elapsed());
 
-            let current_state = self.state.as_ref().current();
+            if elapsed_since_last_run < pause {
+                trace!(
+                    run_requested = current_state.run_requested(),
+                    "{elapsed_since_last_run:?} since last run, waiting"
+                );
 
-            if current_state.exit_requested() {
-                return RequestedState::Exit;
-            } else if elapsed_since_last_run < Self::PAUSE_LENGTH {
-                trace!("{elapsed_since_last_run:?} since last run, waiting");
-
-                let timeout = self
-                    .last_finished_at
-                    .lock()
-                    .unwrap()
-                    .map_or(Self::PAUSE_LENGTH, |x| {
-                        Self::PAUSE_LENGTH.saturating_sub(x.elapsed())
-                    });
-                self.state.as_ref().wait_timeout(current_state, timeout);
+                self.state
+                    .as_ref()
+                    .wait_timeout(current_state, pause.saturating_sub(elapsed_since_last_run));
             } else {
-                self.set_running(true);
+                self.state.as_ref().start_run();
                 return RequestedState::Run;
             }
         }
     }
 
+    /// Makes the next run start as soon as the minimum interval allows.
+    pub(super) fn request_run(&self) {
+        self.state.as_ref().request_run();
+    }
+
+    pub(super) fn set_schedule(&self, schedule: VacuumSchedule) {
+        *self.schedule.lock().unwrap() = schedule;
+
+        // the waits might be shorter now
+        self.state.as_ref().wake_all();
+    }
+
     pub(super) fn request_exit(&'_ self) {
         self.state.as_ref().request_exit();
     }
@@ -101,11 +263,126 @@ impl Scheduler {
         }
     }
 
-    pub fn start_full_run(&self) {
-        *self.last_finished_at.lock().unwrap() = Some(Instant::now());
+    pub fn start_run(&self) -> Scan {
+        let now = Instant::now();
+        let sweep_interval = self.schedule.lock().unwrap().sweep_interval;
+
+        *self.last_finished_at.lock().unwrap() = Some(now);
+        self.idle.store(false, Ordering::Release);
+
+        let mut last_sweep_at = self.last_sweep_at.lock().unwrap();
+        let mut requested_runs = self.requested_runs.lock().unwrap();
+
+        if requested_runs.requested > requested_runs.finished {
+            requested_runs.started = requested_runs.requested;
+            *last_sweep_at = Some(now);
+
+            Scan::Requested
+        } else if last_sweep_at.is_some_and(|x| now.duration_since(x) < sweep_interval) {
+            Scan::Incremental
+        } else {
+            *last_sweep_at = Some(now);
+
+            Scan::Full
+        }
+    }
+
+    /// Hands the report over to whoever is waiting for the requested run that just finished.
+    pub fn finish_run(&self, report: VacuumReport) {
+        let mut requested_runs = self.requested_runs.lock().unwrap();
+
+        if requested_runs.started > requested_runs.finished {
+            requested_runs.finished = requested_runs.started;
+            requested_runs.report = Some(report);
+
+            drop(requested_runs);
+            self.requested_run_finished.notify_all();
+        }
+    }
+
+    /// Starts a run that looks at all the pages, without waiting for the schedule, and waits for
+    /// it to finish.
+    pub(super) fn run_now(&self) -> VacuumReport {
+        let mut requested_runs = self.requested_runs.lock().unwrap();
+        requested_runs.requested += 1;
+        let request = requested_runs.requested;
+        drop(requested_runs);
+
+        self.state.as_ref().request_run();
+
+        let mut requested_runs = self.requested_runs.lock().unwrap();
+
+        while requested_runs.finished < request {
+            requested_runs = self.requested_run_finished.wait(requested_runs).unwrap();
+        }
+
+        requested_runs.report.unwrap()
     }
 
-    fn set_running(&self, value: bool) {
-        self.state.as_ref().set_running(value);
+    /// Makes the scheduler wait for the idle interval before the next run.
+    pub fn mark_idle(&self) {
+        self.idle.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sync::Arc;
+    use crate::thread;
+
+    #[test]
+    fn requested_runs_start_before_the_interval() {
+        let scheduler = Arc::new(Scheduler::new());
+        scheduler.set_schedule(
+            VacuumSchedule::default()
+                .with_interval(Duration::from_mins(10))
+                .with_min_interval(Duration::ZERO),
+        );
+
+        assert!(matches!(
+            scheduler.block_if_unscheduled(),
+            RequestedState::Run
+        ));
+        let _ = scheduler.start_run();
+
+        let waiter = {
+            let scheduler = scheduler.clone();
+
+            thread::spawn(move || {
+                let start = Instant::now();
+                let requested = scheduler.block_if_unscheduled();
+
+                (matches!(requested, RequestedState::Run), start.elapsed())
+            })
+        };
+
+        scheduler.request_run();
+
+        let (started, elapsed) = waiter.join().unwrap();
+        assert!(started);
+        assert!(elapsed < Duration::from_mins(10));
+    }
+
+    #[test]
+    fn high_water_mark_is_a_percentage_of_the_block() {
+        let schedule = VacuumSchedule::default().with_high_water_mark(150);
+
+        assert_eq!(schedule.high_water_mark(1000), 1000);
+        assert_eq!(schedule.with_high_water_mark(50).high_water_mark(1000), 500);
+    }
+
+    #[test]
+    fn full_sweeps_are_periodic() {
+        let scheduler = Scheduler::new();
+        scheduler
+            .set_schedule(VacuumSchedule::default().with_sweep_interval(Duration::from_mins(10)));
+
+        assert!(matches!(scheduler.start_run(), Scan::Full));
+        assert!(matches!(scheduler.start_run(), Scan::Incremental));
+
+        scheduler.set_schedule(VacuumSchedule::default().with_sweep_interval(Duration::ZERO));
+
+        assert!(matches!(scheduler.start_run(), Scan::Full));
     }
 }
@@ -86,6 +86,12 @@ impl VersionedPage {
         }
     }
 
+    /// Whether a newer version of the page was committed or the page was deleted. For the version
+    /// visible in a snapshot, this means it was modified after the snapshot was taken.
+    pub fn is_superseded(&self) -> bool {
+        self.next_version().is_some() || self.visible_until().is_some()
+    }
+
     pub fn previous_version(&self) -> Option<PageIndex> {
         if self.header.previous_version == PageIndex::max() {
             None
@@ -1,7 +1,8 @@
 use std::marker::PhantomData;
+use std::time::Instant;
 
-use super::{StorageError, Transaction};
-use crate::storage::{PageReservation, Storage};
+use super::{ReadTransaction, StorageError, Transaction};
+use crate::storage::{Change, IsolationLevel, PageReservation, Savepoint, Storage};
 use crate::sync::Arc;
 use crate::sync::atomic::{AtomicUsize, Ordering};
 
@@ -22,7 +23,7 @@ pub struct InstrumentedTransaction<'a, TStorage: Storage>(
     PhantomData<&'a TStorage>,
 );
 
-impl<'a, TStorage: Storage> Transaction<'a> for InstrumentedTransaction<'a, TStorage> {
+impl<'a, TStorage: Storage> ReadTransaction<'a> for InstrumentedTransaction<'a, TStorage> {
     type Storage = InstrumentedStorage<TStorage>;
 
     fn read<TReturn, const N: usize>(
@@ -33,6 +34,24 @@ impl<'a, TStorage: Storage> Transaction<'a> for InstrumentedTransaction<'a, TSto
         self.0.read(indices, read)
     }
 
+    fn commit(self) -> Result<(), StorageError<TStorage::PageId>> {
+        self.0.commit()
+    }
+
+    fn rollback(self) -> Result<(), StorageError<TStorage::PageId>> {
+        self.0.rollback()
+    }
+
+    fn id(&self) -> super::TransactionId {
+        self.0.id()
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.0.set_deadline(deadline);
+    }
+}
+
+impl<'a, TStorage: Storage> Transaction<'a> for InstrumentedTransaction<'a, TStorage> {
     fn write<TReturn, const N: usize>(
         &mut self,
         indices: impl Into<[TStorage::PageId; N]>,
@@ -70,6 +89,40 @@ impl<'a, TStorage: Storage> Transaction<'a> for InstrumentedTransaction<'a, TSto
         self.0.delete(page)
     }
 
+    fn savepoint(&mut self) -> Savepoint {
+        self.0.savepoint()
+    }
+
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), StorageError<TStorage::PageId>> {
+        self.0.rollback_to(savepoint)
+    }
+
+    fn captures_changes(&self) -> bool {
+        self.0.captures_changes()
+    }
+
+    fn record_change(&mut self, change: Change) {
+        self.0.record_change(change);
+    }
+}
+
+#[derive(Debug)]
+pub struct InstrumentedReadTransaction<'a, TStorage: Storage>(
+    TStorage::ReadTransaction<'a>,
+    PhantomData<&'a TStorage>,
+);
+
+impl<'a, TStorage: Storage> ReadTransaction<'a> for InstrumentedReadTransaction<'a, TStorage> {
+    type Storage = InstrumentedStorage<TStorage>;
+
+    fn read<TReturn, const N: usize>(
+        &mut self,
+        indices: impl Into<[TStorage::PageId; N]>,
+        read: impl FnOnce([&TStorage::Page; N]) -> TReturn,
+    ) -> Result<TReturn, StorageError<TStorage::PageId>> {
+        self.0.read(indices, read)
+    }
+
     fn commit(self) -> Result<(), StorageError<TStorage::PageId>> {
         self.0.commit()
     }
@@ -81,6 +134,10 @@ impl<'a, TStorage: Storage> Transaction<'a> for InstrumentedTransaction<'a, TSto
     fn id(&self) -> super::TransactionId {
         self.0.id()
     }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.0.set_deadline(deadline);
+    }
 }
 
 // TODO generalize this so more metrics can be extracted (transactions per second, total
@@ -104,16 +161,30 @@ impl<T: Storage> Storage for InstrumentedStorage<T> {
         = InstrumentedPageReservation<'a, T>
     where
         T: 'a;
+    type ReadTransaction<'a>
+        = InstrumentedReadTransaction<'a, T>
+    where
+        T: 'a;
     type Transaction<'a>
         = InstrumentedTransaction<'a, T>
     where
         T: 'a;
 
-    fn transaction(&self) -> Result<Self::Transaction<'_>, StorageError<T::PageId>> {
+    fn transaction_with_isolation(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<Self::Transaction<'_>, StorageError<T::PageId>> {
         Ok(InstrumentedTransaction(
-            self.inner.transaction()?,
+            self.inner.transaction_with_isolation(isolation)?,
             self.page_count.clone(),
             PhantomData,
         ))
     }
+
+    fn read_transaction(&self) -> Result<Self::ReadTransaction<'_>, StorageError<T::PageId>> {
+        Ok(InstrumentedReadTransaction(
+            self.inner.read_transaction()?,
+            PhantomData,
+        ))
+    }
 }
@@ -1,10 +1,13 @@
+pub mod file;
 pub mod in_memory;
 pub mod instrumented;
 pub(super) mod page;
 
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::io;
 use std::num::NonZeroU64;
+use std::time::Instant;
 
 use bytemuck::{AnyBitPattern, NoUninit, Pod, PodInOption, Zeroable, ZeroableInOption};
 use thiserror::Error;
@@ -15,12 +18,52 @@ use crate::sync::atomic::{AtomicU64, Ordering};
 pub enum StorageError<T: PageId> {
     #[error("The page at index {0:?} does not exist")]
     PageNotFound(T),
-    #[error("Would deadlock when locking {0:?}")]
-    // TODO this should also have a transaction ID
-    Deadlock(T),
+    #[error(
+        "Transaction {transaction:?} (snapshot at {snapshot:?}) conflicts with a newer version of {page:?}"
+    )]
+    Conflict {
+        page: T,
+        transaction: TransactionId,
+        snapshot: TransactionalTimestamp,
+    },
 
     #[error("out of space")]
     OutOfSpace,
+
+    #[error("I/O error: {0}")]
+    Io(io::ErrorKind),
+    #[error("The page at index {0:?} is corrupted")]
+    Corrupted(T),
+
+    #[error("The data as of {0:?} is not available")]
+    SnapshotUnavailable(TransactionalTimestamp),
+
+    #[error("Transaction {0:?} timed out")]
+    TimedOut(TransactionId),
+
+    #[error("The changes committed after {0:?} are not available")]
+    ChangesUnavailable(Option<TransactionalTimestamp>),
+
+    #[error(
+        "Transaction {transaction:?} (snapshot at {snapshot:?}) read {page:?}, which was modified before it committed"
+    )]
+    SerializationFailure {
+        page: T,
+        transaction: TransactionId,
+        snapshot: TransactionalTimestamp,
+    },
+
+    #[error("{0:?} is not valid in this transaction")]
+    InvalidSavepoint(Savepoint),
+
+    #[error("Transaction {0:?} started before the changes were captured, so it can't modify pages")]
+    UncapturedChanges(TransactionId),
+}
+
+impl<T: PageId> From<io::Error> for StorageError<T> {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.kind())
+    }
 }
 
 #[derive(Debug, Clone, Copy, Pod, Zeroable, PartialEq, Eq, Hash)]
@@ -74,6 +117,90 @@ impl TransactionId {
     }
 }
 
+/// Marks a point in a transaction that it can be rolled back to, without rolling back the whole
+/// transaction. It's only valid in the transaction that created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    depth: usize,
+    id: u64,
+}
+
+impl Savepoint {
+    pub(crate) const fn new(depth: usize, id: u64) -> Self {
+        Self { depth, id }
+    }
+
+    pub(crate) const fn depth(self) -> usize {
+        self.depth
+    }
+
+    pub(crate) const fn id(self) -> u64 {
+        self.id
+    }
+}
+
+/// A logical modification of a single key in a tree, as opposed to the modifications of the pages
+/// the storage itself keeps track of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    tree: SerializedPageId,
+    key: Vec<u8>,
+    old_value: Option<Vec<u8>>,
+    new_value: Option<Vec<u8>>,
+}
+
+impl Change {
+    pub(crate) const fn new(
+        tree: SerializedPageId,
+        key: Vec<u8>,
+        old_value: Option<Vec<u8>>,
+        new_value: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            tree,
+            key,
+            old_value,
+            new_value,
+        }
+    }
+
+    /// The page holding the header of the tree, see `Tree::id`.
+    pub const fn tree(&self) -> SerializedPageId {
+        self.tree
+    }
+
+    /// The key, encoded with `TreeKey::encode`.
+    #[must_use]
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The value before the change, `None` if the key did not exist.
+    #[must_use]
+    pub fn old_value(&self) -> Option<&[u8]> {
+        self.old_value.as_deref()
+    }
+
+    /// The value after the change, `None` if the key was deleted.
+    #[must_use]
+    pub fn new_value(&self) -> Option<&[u8]> {
+        self.new_value.as_deref()
+    }
+}
+
+/// Decides which of the concurrent modifications a transaction must not overlap with in order to
+/// commit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Only the pages the transaction modified are checked for newer versions, so two
+    /// transactions can each read what the other one modifies and both commit (write skew).
+    #[default]
+    Snapshot,
+    /// The pages the transaction read are also checked, and if any of them was modified since
+    /// the snapshot, the commit fails with `StorageError::SerializationFailure`.
+    Serializable,
+}
+
 #[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq, PartialOrd, Eq, Ord)]
 #[repr(transparent)]
 pub struct TransactionalTimestamp(u64);
@@ -83,6 +210,11 @@ impl TransactionalTimestamp {
     pub(crate) const fn zero() -> Self {
         Self(0)
     }
+
+    #[must_use]
+    pub(crate) const fn next(self) -> Self {
+        Self(self.0.strict_add(1))
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable, Hash)]
@@ -128,10 +260,17 @@ pub trait Page: Debug {
     fn data_mut<T: AnyBitPattern + NoUninit>(&mut self) -> &mut T;
 }
 
-pub trait Transaction<'storage>: Send + Debug {
+/// A transaction that can only read pages, so it never conflicts with others. Finishing it in any
+/// way only stops it from holding back the cleanup of the versions it can see.
+pub trait ReadTransaction<'storage>: Send + Debug {
     type Storage: Storage + 'storage;
 
     fn id(&self) -> TransactionId;
+
+    /// Makes the operations that start after `deadline` fail with `StorageError::TimedOut`, so
+    /// that a forgotten transaction doesn't hold back vacuum forever.
+    fn set_deadline(&mut self, deadline: Instant);
+
     // TODO unify get and write, don't take callbacks, return a ref with the same lifetime as &self
     fn read<T, const N: usize>(
         &mut self,
@@ -139,6 +278,11 @@ pub trait Transaction<'storage>: Send + Debug {
         read: impl FnOnce([&<Self::Storage as Storage>::Page; N]) -> T,
     ) -> Result<T, ErrorOf<Self::Storage>>;
 
+    fn commit(self) -> Result<(), ErrorOf<Self::Storage>>;
+    fn rollback(self) -> Result<(), ErrorOf<Self::Storage>>;
+}
+
+pub trait Transaction<'storage>: ReadTransaction<'storage> {
     fn write<T, const N: usize>(
         &mut self,
         indices: impl Into<[PageIdOf<Self::Storage>; N]>,
@@ -162,8 +306,22 @@ pub trait Transaction<'storage>: Send + Debug {
 
     fn delete(&mut self, page: PageIdOf<Self::Storage>) -> Result<(), ErrorOf<Self::Storage>>;
 
-    fn commit(self) -> Result<(), ErrorOf<Self::Storage>>;
-    fn rollback(self) -> Result<(), ErrorOf<Self::Storage>>;
+    /// Marks the current state of the transaction, so that the modifications made after it can be
+    /// undone with `rollback_to`.
+    fn savepoint(&mut self) -> Savepoint;
+
+    /// Undoes all the modifications made since `savepoint`, and frees the pages allocated for
+    /// them. The savepoint can be rolled back to again, but the ones created after it are no
+    /// longer valid, and rolling back to them fails with `InvalidSavepoint`.
+    fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), ErrorOf<Self::Storage>>;
+
+    /// Whether the changes passed to `record_change` get published anywhere, so that the callers
+    /// can skip preparing them otherwise.
+    fn captures_changes(&self) -> bool;
+
+    /// Attaches a logical change to the transaction, to be published once it commits. Rolling
+    /// back to a savepoint also drops the changes recorded after it.
+    fn record_change(&mut self, change: Change);
 }
 
 pub trait Storage: Send + Sync + Debug {
@@ -175,10 +333,45 @@ pub trait Storage: Send + Sync + Debug {
     where
         Self: 'storage;
 
+    type ReadTransaction<'storage>: ReadTransaction<'storage, Storage = Self>
+    where
+        Self: 'storage;
+
     type PageId: PageId;
     type Page: Page;
 
     fn transaction(&self) -> Result<Self::Transaction<'_>, ErrorOf<Self>>
+    where
+        Self: Sized,
+    {
+        self.transaction_with_isolation(IsolationLevel::default())
+    }
+
+    fn transaction_with_isolation(
+        &self,
+        isolation: IsolationLevel,
+    ) -> Result<Self::Transaction<'_>, ErrorOf<Self>>
+    where
+        Self: Sized;
+
+    fn read_transaction(&self) -> Result<Self::ReadTransaction<'_>, ErrorOf<Self>>
+    where
+        Self: Sized;
+}
+
+/// A storage that keeps the past versions of its pages, so that the data can be read as of an
+/// earlier commit.
+pub trait VersionedStorage: Storage {
+    /// Returns the timestamp of the newest commit, if there was any.
+    fn latest_commit(&self) -> Option<TransactionalTimestamp>;
+
+    /// Starts a read-only transaction that sees the data as it was right after the commit at
+    /// `timestamp`. Fails with `StorageError::SnapshotUnavailable` if the versions from back then
+    /// were not retained.
+    fn transaction_as_of(
+        &self,
+        timestamp: TransactionalTimestamp,
+    ) -> Result<Self::ReadTransaction<'_>, ErrorOf<Self>>
     where
         Self: Sized;
 }
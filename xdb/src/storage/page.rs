@@ -72,8 +72,6 @@ impl Page {
         }
     }
 
-    #[allow(unused)] // TODO we don't need the whole method, but we need a checksum check on read,
-    // and an update on write
     pub fn serialize(mut self) -> [u8; PAGE_SIZE.as_bytes()] {
         self.header.checksum.clear();
 
@@ -87,8 +85,6 @@ impl Page {
         bytes
     }
 
-    #[allow(unused)] // TODO we don't need most of this, but we need to do a checksum update on
-    // write
     pub fn deserialize(mut bytes: [u8; PAGE_SIZE.as_bytes()]) -> Result<Self, PageError> {
         let expected_checksum =
             Checksum::from_bytes(bytes[0..size_of::<Checksum>()].try_into().unwrap());